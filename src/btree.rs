@@ -1,110 +1,355 @@
 use std::cast;
-use std::mem::size_of;
+use std::cmp::min;
+use std::ptr;
 use std::raw::Slice;
-use std::num::Zero;
-use std::io::TempDir;
-use std::rc::Rc;
-use sync::Mutex;
+use std::str;
+use std::io::{BufReader, BufWriter};
+use sync::Arc;
 
 use buffer;
+use freespace;
+use replacement;
+use storage;
 use schema;
+use faulty;
+use recovery;
+#[cfg(test)]
+use test::BenchHarness;
 
 static LEAF_MARKER: u8 = 0b11111111;
 static BRANCH_MARKER: u8 = 0b0;
 
+/*
+ * Both kinds of nodes are slotted pages. The header has the marker, the
+ * number of entries, where the data of the entries starts and how many
+ * bytes the entries and their slots take up, leaves also
+ * have the page ids of their left and right siblings, 0 if there is none.
+ * The slots come after the header, one per entry with the offset and the
+ * length of its key. The data grows from the end of the page towards them,
+ * every entry is the key followed by the TID or the page id of the child.
+ * Erasing leaves holes in the data, they get compacted once the space is
+ * needed.
+ */
+static NODE_COUNT: uint = 4;
+static LEAF_PREV: uint = 8;
+static LEAF_NEXT: uint = 16;
+static NODE_DATA: uint = 24;
+static NODE_USED: uint = 28;
+static NODE_HEADER: uint = 32;
+static SLOT_SIZE: uint = 8;
+static VALUE_SIZE: uint = 8;
+
+/* page 0 is the free space map, the tree's metadata comes right after it */
+static META_PAGE: u64 = 1;
+/* "NCBT" */
+static META_MAGIC: u32 = 0x4E434254;
+
 /* simple type alias to simplify signatures */
-type ConcurrentManager = Rc<Mutex<buffer::BufferManager>>;
+type ConcurrentManager = Arc<buffer::BufferManager>;
+
+/*
+ * what keys have to offer: a tag for the metadata and an encoding to put
+ * them on the pages. The encodings have to sort bytewise the way the keys
+ * do, that is the order the tree keeps unless it gets another comparator.
+ */
+trait Keyish: Clone {
+	/* the argument only picks the implementation */
+	fn tag(_: Option<Self>) -> u8;
+	fn encode(&self) -> Vec<u8>;
+	fn decode(bytes: &[u8]) -> Self;
+}
+
+/* numbers are stored big endian, signed ones with the sign bit flipped */
+fn big_endian(value: u64, width: uint) -> Vec<u8> {
+	range(0, width).rev().map(|i| (value >> (i * 8)) as u8).collect()
+}
+
+fn from_big_endian(bytes: &[u8]) -> u64 {
+	bytes.iter().fold(0, |value, &byte| value << 8 | byte as u64)
+}
+
+impl Keyish for int {
+	fn tag(_: Option<int>) -> u8 { 1 }
+	fn encode(&self) -> Vec<u8> { (*self as i64).encode() }
+	fn decode(bytes: &[u8]) -> int {
+		let value: i64 = Keyish::decode(bytes);
+		value as int
+	}
+}
+
+impl Keyish for i32 {
+	fn tag(_: Option<i32>) -> u8 { 2 }
+	fn encode(&self) -> Vec<u8> { big_endian((*self as u32 ^ 0x80000000) as u64, 4) }
+	fn decode(bytes: &[u8]) -> i32 { (from_big_endian(bytes) as u32 ^ 0x80000000) as i32 }
+}
+
+impl Keyish for i64 {
+	fn tag(_: Option<i64>) -> u8 { 3 }
+	fn encode(&self) -> Vec<u8> { big_endian(*self as u64 ^ 1 << 63, 8) }
+	fn decode(bytes: &[u8]) -> i64 { (from_big_endian(bytes) ^ 1 << 63) as i64 }
+}
+
+impl Keyish for uint {
+	fn tag(_: Option<uint>) -> u8 { 4 }
+	fn encode(&self) -> Vec<u8> { big_endian(*self as u64, 8) }
+	fn decode(bytes: &[u8]) -> uint { from_big_endian(bytes) as uint }
+}
+
+impl Keyish for u32 {
+	fn tag(_: Option<u32>) -> u8 { 5 }
+	fn encode(&self) -> Vec<u8> { big_endian(*self as u64, 4) }
+	fn decode(bytes: &[u8]) -> u32 { from_big_endian(bytes) as u32 }
+}
+
+impl Keyish for u64 {
+	fn tag(_: Option<u64>) -> u8 { 6 }
+	fn encode(&self) -> Vec<u8> { big_endian(*self, 8) }
+	fn decode(bytes: &[u8]) -> u64 { from_big_endian(bytes) }
+}
+
+/* byte strings, e.g. the values of Varchar columns */
+impl Keyish for Vec<u8> {
+	fn tag(_: Option<Vec<u8>>) -> u8 { 7 }
+	fn encode(&self) -> Vec<u8> { self.clone() }
+	fn decode(bytes: &[u8]) -> Vec<u8> { Vec::from_slice(bytes) }
+}
+
+impl Keyish for ~str {
+	fn tag(_: Option<~str>) -> u8 { 8 }
+	fn encode(&self) -> Vec<u8> { Vec::from_slice(self.as_bytes()) }
+	fn decode(bytes: &[u8]) -> ~str {
+		match str::from_utf8(bytes) {
+			Some(s) => s.to_owned(),
+			None => fail!("Key is not valid UTF-8"),
+		}
+	}
+}
+
+fn key_tag<K: Keyish>() -> u8 {
+	Keyish::tag(None::<K>)
+}
+
+/* orders encoded keys, the entries of a tree are sorted by one */
+type Comparator = fn(&[u8], &[u8]) -> Ordering;
 
-/* a new trait which specifies which traits our keys should implement */
-trait Keyish: TotalOrd + Zero + Clone {}
-impl<T: TotalOrd + Zero + Clone> Keyish for T {}
+/*
+ * the metadata has a tag for the comparator next to the key tag, so a tree
+ * is not opened with another order than it was built in. This is bytewise's,
+ * other comparators bring their own.
+ */
+static BYTEWISE_TAG: u8 = 0;
+
+/* the order of the encodings, which is the order of the keys themselves */
+fn bytewise(a: &[u8], b: &[u8]) -> Ordering {
+	a.cmp(&b)
+}
 
 struct BTree<'a, K> {
 	segment: u64,
 	manager: ConcurrentManager,
 	root: LazyNode,
-	next_free_page: u64,
+	/* levels of branch nodes, the root included */
+	height: u64,
+	/*
+	 * one past the highest page the tree ever got. The free space map
+	 * decides which pages are free, this is how far the tree has grown.
+	 */
+	next_free: u64,
+	free_space: freespace::FreeSpaceMap,
+	compare: Comparator,
+	compare_tag: u8,
 }
 
 impl<'a, K: Keyish> BTree<'a, K> {
+	/* creates a tree in a segment that doesn't hold one yet */
 	fn new<'b>(segment_id: u64, manager: ConcurrentManager) -> BTree<'b, K> {
-		let tree_base = buffer::join_segment(segment_id, 1);
-		// TODO read tree and next free page from page 0
+		BTree::with_comparator(segment_id, manager, BYTEWISE_TAG, bytewise)
+	}
+
+	/*
+	 * like new, but the keys are kept in the order of `compare`, which
+	 * `compare_tag` stands for in the metadata
+	 */
+	fn with_comparator<'b>(segment_id: u64, manager: ConcurrentManager,
+			compare_tag: u8, compare: Comparator) -> BTree<'b, K> {
+		let free_space = freespace::FreeSpaceMap::new(segment_id, manager.clone());
+		manager.begin();
+		match free_space.is_allocated(META_PAGE) {
+			Ok(false) => (),
+			Ok(true) => fail!("Segment {} already holds a tree", segment_id),
+			Err(e) => fail!("Reading the free space map failed: {}", e),
+		}
+		match free_space.allocate() {
+			Ok(META_PAGE) => (),
+			Ok(page) => fail!("Got page {} for the tree metadata", page),
+			Err(e) => fail!("Allocating the metadata page failed: {}", e),
+		}
+		let mut tree = BTree {
+			segment: segment_id,
+			manager: manager.clone(),
+			root: LazyNode::new(0),
+			height: 1,
+			next_free: META_PAGE + 1,
+			free_space: free_space,
+			compare: compare,
+			compare_tag: compare_tag,
+		};
+		tree.root = tree.create_branch_node();
+		tree.write_meta();
+		commit(&manager);
+		tree
+	}
+
+	/* the tree that `new` created in the segment earlier */
+	fn open<'b>(segment_id: u64, manager: ConcurrentManager) -> BTree<'b, K> {
+		BTree::open_with_comparator(segment_id, manager, BYTEWISE_TAG, bytewise)
+	}
+
+	/* fails unless `compare_tag` is the one the tree was created with */
+	fn open_with_comparator<'b>(segment_id: u64, manager: ConcurrentManager,
+			compare_tag: u8, compare: Comparator) -> BTree<'b, K> {
+		let pagelock = manager.fix_page(manager.join_segment(segment_id, META_PAGE)).unwrap();
+		let (root, next_free, height, tag, stored_compare_tag) = {
+			let page = pagelock.read();
+			let mut reader = BufReader::new(page.get_data());
+			if reader.read_le_u32().unwrap() != META_MAGIC {
+				fail!("Segment {} holds no tree", segment_id);
+			}
+			(reader.read_le_u64().unwrap(), reader.read_le_u64().unwrap(),
+				reader.read_le_u64().unwrap(), reader.read_u8().unwrap(),
+				reader.read_u8().unwrap())
+		};
+		if tag != key_tag::<K>() {
+			fail!("The tree in segment {} has keys of type {}, not {}",
+				segment_id, tag, key_tag::<K>());
+		}
+		if stored_compare_tag != compare_tag {
+			fail!("The tree in segment {} is ordered by comparator {}, not {}",
+				segment_id, stored_compare_tag, compare_tag);
+		}
+		info!("Opened tree in segment {}, root {} and height {}", segment_id, root, height);
 		BTree {
 			segment: segment_id,
-			manager: manager,
-			root: LazyNode::new(tree_base),
-			next_free_page: 2,
+			manager: manager.clone(),
+			root: LazyNode::new(manager.join_segment(segment_id, root)),
+			height: height,
+			next_free: next_free,
+			free_space: freespace::FreeSpaceMap::new(segment_id, manager),
+			compare: compare,
+			compare_tag: compare_tag,
+		}
+	}
+
+	/* part of the transaction that changed the tree */
+	fn write_meta(&self) {
+		let (_, root) = self.manager.split_segment(self.root.page_id);
+		let pagelock = self.manager.fix_page(
+			self.manager.join_segment(self.segment, META_PAGE)).unwrap();
+		let mut page = pagelock.write();
+		let mut writer = BufWriter::new(page.get_mut_data());
+		match writer.write_le_u32(META_MAGIC).and_then(|_|
+			writer.write_le_u64(root)).and_then(|_|
+			writer.write_le_u64(self.next_free)).and_then(|_|
+			writer.write_le_u64(self.height)).and_then(|_|
+			writer.write_u8(key_tag::<K>())).and_then(|_|
+			writer.write_u8(self.compare_tag)) {
+			Ok(()) => (),
+			Err(e) => fail!("Writing the tree metadata failed: {}", e),
 		}
 	}
 
+	/*
+	 * the longest key that goes into the tree, so a split node always has
+	 * room for one more entry
+	 */
+	fn max_key(&self) -> uint {
+		(self.manager.page_size() - NODE_HEADER) / 4 - VALUE_SIZE - SLOT_SIZE
+	}
+
+	/* inserts and erases are transactions of their own */
 	fn insert(&mut self, key: K, value: schema::TID) {
-		// it is not allowed for the key to be the Zero value, that is used
-		// as marker for invalid data
-		assert!(!key.is_zero());
+		let key = key.encode();
+		if key.len() > self.max_key() {
+			fail!("Key of {} bytes is longer than the {} bytes allowed", key.len(), self.max_key());
+		}
+		self.manager.begin();
+		let next_free = self.next_free;
 		let node = self.root.load(self.manager.clone());
 		// try insertion and see if the root was split
 		let candidate = match node {
-			Branch(mut n) => n.insert_value(self, key, value),
-			Leaf(mut n) => n.insert_value(self, key, value),
+			Branch(mut n) => n.insert_value(self, key.as_slice(), value),
+			Leaf(mut n) => n.insert_value(self, key.as_slice(), value),
 		};
 		// set new tree root if it was split
 		match candidate {
 			Some(Overflowed(new_k, new_page_id)) => {
 				let old_page_id = self.root.page_id;
-				// determine old_k
-				let old_node: BranchNode<K> = match self.root.load(self.manager.clone()) {
-					Branch(b) => b,
-					Leaf(_) => fail!("Got leaf where branch was expected"),
+				// the old root kept the upper half, old_k is its largest key
+				let old_k = {
+					let old_node = load_branch(&self.manager, old_page_id);
+					Vec::from_slice(old_node.node.key(old_node.len() - 1))
 				};
-				let mut old_k: K = Zero::zero();
-				for i in range(0, old_node.entries.len()) {
-					if old_node.entries[i].page_id == 0 {
-						continue;
-					}
-					if old_node.entries[i].key > old_k {
-						old_k = old_node.entries[i].key.clone();
-					}
-				}
-				debug!("new_k {:?}, old_k {:?}", new_k, old_k);
+				debug!("new_k {}, old_k {}", new_k, old_k);
 				let new_lazy_root = self.create_branch_node();
-				let mut new_lazy_root_node = match new_lazy_root.load(self.manager.clone()) {
-					Branch(b) => b,
-					Leaf(_) => fail!("Got lead where branch was expected"),
-				};
+				let mut new_lazy_root_node = load_branch(&self.manager, new_lazy_root.page_id);
 
-				new_lazy_root_node.insert_branch(self, new_k, new_page_id);
-				new_lazy_root_node.insert_branch(self, old_k, old_page_id);
+				new_lazy_root_node.insert_branch(self, new_k.as_slice(), new_page_id);
+				new_lazy_root_node.insert_branch(self, old_k.as_slice(), old_page_id);
 				self.root = new_lazy_root;
+				self.height += 1;
+				self.write_meta();
+			},
+			None => if self.next_free != next_free {
+				self.write_meta();
 			},
-			None => (),
 		}
+		commit(&self.manager);
 	}
 
 	fn next_page(&mut self) -> u64 {
-		let n = self.next_free_page;
-		self.next_free_page += 1;
-		n
+		let page = match self.free_space.allocate() {
+			Ok(page) => page,
+			Err(e) => fail!("Allocating a page failed: {}", e),
+		};
+		if page >= self.next_free {
+			self.next_free = page + 1;
+		}
+		page
+	}
+
+	/* gives a page that no node uses anymore back to the free space map */
+	fn free_page(&mut self, page_id: u64) {
+		let (_, page) = self.manager.split_segment(page_id);
+		match self.free_space.free(page) {
+			Ok(()) => (),
+			Err(e) => fail!("Freeing page {} failed: {}", page, e),
+		}
 	}
 
 	fn create_branch_node(&mut self) -> LazyNode {
 		let next = self.next_page();
-		let page_path = buffer::join_segment(self.segment, next);
-		let mut manager = self.manager.lock();
-		let pagelock = manager.fix_page(page_path).unwrap();
+		let page_path = self.manager.join_segment(self.segment, next);
+		let pagelock = self.manager.fix_page(page_path).unwrap();
 		let mut page = pagelock.write();
 		let data = page.get_mut_data();
+		// the page might have been used before
+		for b in data.mut_iter() {
+			*b = 0;
+		}
 		data[0] = BRANCH_MARKER;
 		LazyNode::new(page_path)
 	}
 
 	fn create_leaf_node(&mut self) -> LazyNode {
 		let next = self.next_page();
-		let page_path = buffer::join_segment(self.segment, next);
-		let mut manager = self.manager.lock();
-		let pagelock = manager.fix_page(page_path).unwrap();
+		let page_path = self.manager.join_segment(self.segment, next);
+		let pagelock = self.manager.fix_page(page_path).unwrap();
 		let mut page = pagelock.write();
 		let data = page.get_mut_data();
+		// the page might have been used before
+		for b in data.mut_iter() {
+			*b = 0;
+		}
 		// marker to be a leaf page
 		data[0] = LEAF_MARKER;
 		LazyNode::new(page_path)
@@ -112,22 +357,252 @@ impl<'a, K: Keyish> BTree<'a, K> {
 	}
 
 	fn erase(&mut self, key: &K) {
-		let node = self.root.load(self.manager.clone());
-		match node {
-			Branch(mut n) => n.erase(self.manager.clone(), key),
-			Leaf(mut n) => n.erase(key),
+		let key = key.encode();
+		self.manager.begin();
+		{
+			let node = self.root.load(self.manager.clone());
+			match node {
+				Branch(mut n) => n.erase(self, key.as_slice()),
+				Leaf(mut n) => n.erase(key.as_slice(), self.compare),
+			};
+		}
+		self.shrink();
+		commit(&self.manager);
+	}
+
+	/* a root with just one branch below it is a level too many */
+	fn shrink(&mut self) {
+		loop {
+			let child = match self.root.load(self.manager.clone()) {
+				Branch(ref n) if n.len() == 1 => n.node.value(0),
+				_ => return,
+			};
+			match LazyNode::new(child).load(self.manager.clone()) {
+				Branch(_) => (),
+				// the root stays a branch
+				Leaf(_) => return,
+			}
+			let old_root = self.root.page_id;
+			self.root = LazyNode::new(child);
+			self.height -= 1;
+			self.free_page(old_root);
+			self.write_meta();
 		}
 	}
 
 	fn lookup(&self, key: &K) -> Option<schema::TID> {
+		let key = key.encode();
 		let node = self.root.load(self.manager.clone());
 		match node {
-			Branch(n) => n.lookup(self.manager.clone(), key),
-			Leaf(n) => n.lookup(key),
+			Branch(n) => n.lookup(self.manager.clone(), key.as_slice(), self.compare),
+			Leaf(n) => n.lookup(key.as_slice(), self.compare),
+		}
+	}
+
+	/*
+	 * the entries between the bounds in key order, use rev() for the
+	 * reverse. The cursor borrows the tree, so it can't change meanwhile.
+	 */
+	fn range<'t>(&'t self, lower: Bound<K>, upper: Bound<K>) -> Cursor<'t, K> {
+		let (lower, upper) = (lower.encode(), upper.encode());
+		let front = self.find_leaf(lower.key(), false).map(|page| {
+			let leaf = load_leaf(&self.manager, page);
+			(page, match lower {
+				Included(ref k) => leaf.rank(k.as_slice(), false, self.compare),
+				Excluded(ref k) => leaf.rank(k.as_slice(), true, self.compare),
+				Unbounded => 0,
+			})
+		});
+		let back = self.find_leaf(upper.key(), true).map(|page| {
+			let leaf = load_leaf(&self.manager, page);
+			(page, match upper {
+				Included(ref k) => leaf.rank(k.as_slice(), true, self.compare),
+				Excluded(ref k) => leaf.rank(k.as_slice(), false, self.compare),
+				Unbounded => leaf.len(),
+			})
+		});
+		Cursor {
+			tree: self,
+			lower: lower,
+			upper: upper,
+			front: front,
+			back: back,
+		}
+	}
+
+	/*
+	 * descends to the first leaf that can have the key, or the last one
+	 * with `last`. Without a key, the first or last leaf of all.
+	 */
+	fn find_leaf(&self, key: Option<&[u8]>, last: bool) -> Option<u64> {
+		let mut page_id = self.root.page_id;
+		loop {
+			match LazyNode::new(page_id).load(self.manager.clone()) {
+				Leaf(_) => return Some(page_id),
+				Branch(n) => match n.child(key, last, self.compare) {
+					Some(child) => page_id = child,
+					// an empty tree
+					None => return None,
+				},
+			}
+		}
+	}
+}
+
+/*
+ * an index over a column, from the values to the TIDs of their records. The
+ * keys are the values in their Keyish encoding, so Varchar values are their
+ * bytes and an Integer `value` is looked up as `value.encode()`.
+ */
+fn index_column<'a>(segment_id: u64, manager: ConcurrentManager, seg: &schema::SPSegment,
+		column: &schema::Column) -> BTree<'a, Vec<u8>> {
+	let mut bt = BTree::new(segment_id, manager);
+	for tid in column.tids().iter() {
+		let record = seg.lookup(*tid);
+		let key = match column.datatype() {
+			schema::Varchar(_) => Vec::from_slice(record.get_data()),
+			schema::Integer => record.to_int().encode(),
+		};
+		bt.insert(key, *tid);
+	}
+	bt
+}
+
+fn load_leaf<'a>(manager: &ConcurrentManager, page_id: u64) -> LeafNode<'a> {
+	match LazyNode::new(page_id).load(manager.clone()) {
+		Leaf(n) => n,
+		Branch(_) => fail!("Got branch node where leaf was expected"),
+	}
+}
+
+fn load_branch<'a>(manager: &ConcurrentManager, page_id: u64) -> BranchNode<'a> {
+	match LazyNode::new(page_id).load(manager.clone()) {
+		Branch(n) => n,
+		Leaf(_) => fail!("Got leaf node where branch was expected"),
+	}
+}
+
+/* where a range starts or ends */
+enum Bound<K> {
+	Included(K),
+	Excluded(K),
+	Unbounded,
+}
+
+impl<K: Keyish> Bound<K> {
+	fn encode(&self) -> Bound<Vec<u8>> {
+		match *self {
+			Included(ref k) => Included(k.encode()),
+			Excluded(ref k) => Excluded(k.encode()),
+			Unbounded => Unbounded,
+		}
+	}
+}
+
+impl Bound<Vec<u8>> {
+	fn key<'a>(&'a self) -> Option<&'a [u8]> {
+		match *self {
+			Included(ref k) | Excluded(ref k) => Some(k.as_slice()),
+			Unbounded => None,
+		}
+	}
+
+	/* whether the key is on the inner side of the bound */
+	fn admits(&self, key: &[u8], lower: bool, compare: Comparator) -> bool {
+		match *self {
+			Included(ref k) => compare(key, k.as_slice()) != if lower {Less} else {Greater},
+			Excluded(ref k) => compare(key, k.as_slice()) == if lower {Greater} else {Less},
+			Unbounded => true,
+		}
+	}
+}
+
+/*
+ * Walks the leaves along their sibling links. The ends are kept as a page
+ * and an index into it, the front one points at the next entry to return,
+ * the back one just past it. They stop where they meet.
+ */
+struct Cursor<'t, K> {
+	tree: &'t BTree<'t, K>,
+	lower: Bound<Vec<u8>>,
+	upper: Bound<Vec<u8>>,
+	front: Option<(u64, uint)>,
+	back: Option<(u64, uint)>,
+}
+
+impl<'t, K: Keyish> Iterator<(K, schema::TID)> for Cursor<'t, K> {
+	fn next(&mut self) -> Option<(K, schema::TID)> {
+		loop {
+			let (page_id, index) = match self.front {
+				Some(position) => position,
+				None => return None,
+			};
+			let met = match self.back {
+				Some((back_page, back_index)) => page_id == back_page && index >= back_index,
+				None => true,
+			};
+			if met {
+				self.front = None;
+				return None;
+			}
+			let leaf = load_leaf(&self.tree.manager, page_id);
+			if index >= leaf.len() {
+				let next = leaf.next();
+				self.front = if next == 0 {None} else {Some((next, 0))};
+				continue;
+			}
+			if !self.upper.admits(leaf.node.key(index), false, self.tree.compare) {
+				self.front = None;
+				return None;
+			}
+			self.front = Some((page_id, index + 1));
+			return Some((Keyish::decode(leaf.node.key(index)), leaf.tid(index)));
+		}
+	}
+}
+
+impl<'t, K: Keyish> DoubleEndedIterator<(K, schema::TID)> for Cursor<'t, K> {
+	fn next_back(&mut self) -> Option<(K, schema::TID)> {
+		loop {
+			let (page_id, index) = match self.back {
+				Some(position) => position,
+				None => return None,
+			};
+			let met = match self.front {
+				Some((front_page, front_index)) => page_id == front_page && index <= front_index,
+				None => true,
+			};
+			if met {
+				self.back = None;
+				return None;
+			}
+			let leaf = load_leaf(&self.tree.manager, page_id);
+			if index == 0 {
+				let prev = leaf.prev();
+				self.back = if prev == 0 {
+					None
+				} else {
+					Some((prev, load_leaf(&self.tree.manager, prev).len()))
+				};
+				continue;
+			}
+			if !self.lower.admits(leaf.node.key(index - 1), true, self.tree.compare) {
+				self.back = None;
+				return None;
+			}
+			self.back = Some((page_id, index - 1));
+			return Some((Keyish::decode(leaf.node.key(index - 1)), leaf.tid(index - 1)));
 		}
 	}
 }
 
+fn commit(manager: &ConcurrentManager) {
+	match manager.commit() {
+		Ok(()) => (),
+		Err(e) => fail!("Committing to the log failed: {}", e),
+	}
+}
+
 /* a placeholder for the actual page */
 struct LazyNode {
 	page_id: u64,
@@ -142,11 +617,8 @@ impl LazyNode {
 	 * As this is just a placeholder, return the actual node that his is
 	 * representing
 	 */
-	fn load<'a, K: Keyish>(&self, manager: ConcurrentManager) -> Node<'a, K> {
-		let pagelock = {
-			let mut managerlock = manager.lock();
-			managerlock.fix_page(self.page_id).unwrap()
-		};
+	fn load<'a>(&self, manager: ConcurrentManager) -> Node<'a> {
+		let pagelock = manager.fix_page(self.page_id).unwrap();
 
 		let mut is_leaf = false;
 		{
@@ -160,481 +632,631 @@ impl LazyNode {
 				fail!("Invalid page type");
 			}
 		}
+		let node = SlottedNode::new(pagelock);
 		if is_leaf {
-			Leaf(LeafNode::new(manager, pagelock))
+			Leaf(LeafNode {node: node, manager: manager, page_id: self.page_id})
 		} else {
-			Branch(BranchNode::new(manager, pagelock))
+			Branch(BranchNode {node: node, manager: manager})
 		}
 	}
 
 }
 
 /* a node might either be an inner node (branch node) or a leaf node (LeafNode) */
-enum Node<'a, K> {
-	Branch(BranchNode<'a, K>),
-	Leaf(LeafNode<'a, K>),
-}
-
-/* leaf pages consist mainly of leaf entries which are (K, TID) pairs */
-struct LeafEntry<K> {
-	key: K,
-	tid: schema::TID,
-}
-
-/* branch pages consist mainly of branch entries which are (K, page_id) pairs */
-struct BranchEntry<K> {
-	key: K,
-	page_id: u64,
+enum Node<'a> {
+	Branch(BranchNode<'a>),
+	Leaf(LeafNode<'a>),
 }
 
-struct Overflowed<K>(K, u64);
-
-struct LeafNode<'a, K> {
-	capacity: uint,
-	entries: &'a mut [LeafEntry<K>],
-	manager: ConcurrentManager,
-	frame: buffer::ConcurrentFrame,
+/* the separator of the lower half and the page it went to */
+struct Overflowed(Vec<u8>, u64);
+
+/* the page of a node, see the top of the file for the layout */
+struct SlottedNode<'a> {
+	/* how many entries are used */
+	count: uint,
+	/* what the slots and the entries take up, holes not counted */
+	used: uint,
+	data: &'a mut [u8],
+	/* keeps the page fixed for as long as the node lives */
+	frame: buffer::PageGuard,
 }
 
-impl<'a, K: Keyish> LeafNode<'a, K> {
-	fn new(manager: ConcurrentManager, frame: buffer::ConcurrentFrame) -> LeafNode<'a, K> {
-		let mut r = {
+impl<'a> SlottedNode<'a> {
+	fn new(frame: buffer::PageGuard) -> SlottedNode<'a> {
+		let data: &mut [u8] = {
 			let framelock = frame.read();
 			let page = framelock.get_data();
-			// first byte is a Leaf/Branch marker
-			let entry_size = size_of::<LeafEntry<K>>();
-			let entry_num = (page.len() - size_of::<u8>()) / entry_size;
-			let start_from = entry_size;
-
-			let mut entries: &mut[LeafEntry<K>] = unsafe {
-				cast::transmute(
-					Slice::<LeafEntry<K>> {
-						data: page.slice_from(start_from).as_ptr() as *() as *LeafEntry<K>,
-						len: entry_num,
-					}
-				)
-			};
-			entries
+			unsafe {
+				cast::transmute(Slice::<u8> {data: page.as_ptr(), len: page.len()})
+			}
 		};
+		let count = read_u32(data, NODE_COUNT) as uint;
+		let used = read_u32(data, NODE_USED) as uint;
+		SlottedNode {count: count, used: used, data: data, frame: frame}
+	}
 
-		/* find slots that are used */
-		let mut capacity = r.len();
-		for i in range(0, r.len()) {
-			//println!("Entry {:?}", r[i]);
-			if !r[i].key.is_zero() && !r[i].tid.is_invalid() {
-				capacity -= 1;
-			}
-		}
+	fn len(&self) -> uint {
+		self.count
+	}
 
-		info!("Instantiating leaf node with capacity {}", capacity);
-		LeafNode {
-			entries: r,
-			capacity: capacity,
-			manager: manager,
-			frame: frame,
-		}
+	fn set_len(&mut self, count: uint) {
+		self.count = count;
+		write_u32(self.data_mut(), NODE_COUNT, count as u32);
 	}
 
-	fn insert_value(&mut self, tree: &mut BTree<K>, key: K, tid: schema::TID) -> Option<Overflowed<K>> {
-		info!("Leaf insertion, remaining capacity {}", self.capacity);
-		if self.capacity == 0 {
-			let lazy_node = tree.create_leaf_node();
-			let new_node: Node<K> = lazy_node.load(self.manager.clone());
-			let mut new_leaf = match new_node {
-				Leaf(n) => n,
-				Branch(_) => fail!("Got branch node where leaf was expected"),
-			};
-			let num_elements_to_move = self.entries.len()/2;
-			let maximum = self.entries[num_elements_to_move].key.clone();
-
-			// copy them over first
-			for i in range(0, num_elements_to_move) {
-				new_leaf.insert_value(tree,
-					self.entries[i].key.clone(),
-					self.entries[i].tid);
-			}
-			// erase them second
-			for i in range(0, num_elements_to_move).rev() {
-				let k = self.entries[i].key.clone();
-				self.erase(&k);
-			}
+	fn set_used(&mut self, used: uint) {
+		self.used = used;
+		write_u32(self.data_mut(), NODE_USED, used as u32);
+	}
 
-			// now let's actually insert that value
-			if key <= maximum {
-				// insert into new
-				new_leaf.insert_value(tree, key, tid);
-			} else {
-				// insert into this
-				self.insert_value(tree, key, tid);
-			}
-			// no worries, those can't overflow
+	/* the page for modification, marks it as dirty */
+	fn data_mut<'b>(&'b mut self) -> &'b mut [u8] {
+		self.frame.mark_dirty();
+		self.data.mut_slice_from(0)
+	}
 
-			let overflow = Overflowed(maximum, lazy_node.page_id);
-			return Some(overflow);
+	/* offset and length of the key of an entry */
+	fn slot(&self, index: uint) -> (uint, uint) {
+		let at = NODE_HEADER + index * SLOT_SIZE;
+		(read_u32(self.data, at) as uint, read_u32(self.data, at + 4) as uint)
+	}
+
+	fn key<'b>(&'b self, index: uint) -> &'b [u8] {
+		let (offset, len) = self.slot(index);
+		self.data.slice(offset, offset + len)
+	}
+
+	fn value(&self, index: uint) -> u64 {
+		let (offset, len) = self.slot(index);
+		read_u64(self.data, offset + len)
+	}
+
+	/* where the data of the entries starts, a blank page has none */
+	fn data_start(&self) -> uint {
+		match read_u32(self.data, NODE_DATA) as uint {
+			0 => self.data.len(),
+			start => start,
 		}
+	}
 
-		// find place to insert
-		let location = self.find_slot(&key);
-		info!("Location found: {}", location);
-		// free that spot
-		self.shift_from(location);
-		// and put it in
-		self.entries[location].key = key;
-		self.entries[location].tid = tid;
-		self.capacity -= 1;
+	/* what there is room for besides the header */
+	fn capacity(&self) -> uint {
+		self.data.len() - NODE_HEADER
+	}
 
-		// insertion went fine, done
-		None
+	fn used(&self) -> uint {
+		self.used
 	}
 
-	fn erase(&mut self, key: &K) {
-		for i in range(0, self.entries.len()) {
-			if &self.entries[i].key == key {
-				self.entries[i].key = Zero::zero();
-				self.entries[i].tid = schema::TID::new(0, 0);
-				self.capacity += 1;
-				self.shift_to(i);
-				break;
+	fn fits(&self, key_len: uint) -> bool {
+		self.used() + entry_size(key_len) <= self.capacity()
+	}
+
+	/* whether the key of an entry can be replaced with one of `key_len` bytes */
+	fn fits_instead(&self, index: uint, key_len: uint) -> bool {
+		let (_, len) = self.slot(index);
+		self.used() - len + key_len <= self.capacity()
+	}
+
+	/* binary search, the first entry with a key not less than `key`, or greater if `past_equal` */
+	fn bisect(&self, key: &[u8], past_equal: bool, compare: Comparator) -> uint {
+		let mut low = 0;
+		let mut high = self.count;
+		while low < high {
+			let middle = low + (high - low) / 2;
+			match compare(self.key(middle), key) {
+				Less => low = middle + 1,
+				Equal if past_equal => low = middle + 1,
+				_ => high = middle,
 			}
 		}
+		low
 	}
 
-	/* finds the location at which a key should be inserted */
-	fn find_slot(&self, key: &K) -> uint {
-		let mut found = None;
-		for i in range(0, self.entries.len()) {
-			//println!("Checking {:?} against {:?}", key, self.entries[i].key);
-			if self.entries[i].key.is_zero() {
-				found = Some(i);
-				break;
-			}
-			// there is no prev element to compare
-			if i == 0 {
-				if key < &self.entries[i].key {
-					found = Some(i);
-					break;
-				}
-				continue;
-			}
+	/* puts an entry at `index`, the caller checked that it fits */
+	fn insert(&mut self, index: uint, key: &[u8], value: u64) {
+		let (count, used) = (self.count, self.used);
+		let size = key.len() + VALUE_SIZE;
+		if self.data_start() < NODE_HEADER + (count + 1) * SLOT_SIZE + size {
+			self.compact();
+		}
+		let start = self.data_start() - size;
+		assert!(start >= NODE_HEADER + (count + 1) * SLOT_SIZE);
+		{
+			let data = self.data_mut();
+			data.mut_slice(start, start + key.len()).copy_from(key);
+			write_u64(data, start + key.len(), value);
+			// make room among the slots
+			let at = NODE_HEADER + index * SLOT_SIZE;
+			move_bytes(data, at + SLOT_SIZE, at, (count - index) * SLOT_SIZE);
+			write_u32(data, at, start as u32);
+			write_u32(data, at + 4, key.len() as u32);
+			write_u32(data, NODE_DATA, start as u32);
+		}
+		self.set_len(count + 1);
+		self.set_used(used + entry_size(key.len()));
+	}
 
-			if &self.entries[i - 1].key < key && key < &self.entries[i].key {
-				found = Some(i);
-				break;
+	/* the data of the entry stays behind as a hole */
+	fn remove(&mut self, index: uint) {
+		self.remove_range(index, 1);
+	}
+
+	/* removes `count` entries from `first` on, with one move of the slots */
+	fn remove_range(&mut self, first: uint, count: uint) {
+		let (total, used) = (self.count, self.used);
+		let size = range(first, first + count).fold(0, |size, i| {
+			let (_, len) = self.slot(i);
+			size + entry_size(len)
+		});
+		{
+			let data = self.data_mut();
+			let at = NODE_HEADER + first * SLOT_SIZE;
+			move_bytes(data, at, at + count * SLOT_SIZE, (total - first - count) * SLOT_SIZE);
+		}
+		self.set_len(total - count);
+		self.set_used(used - size);
+	}
+
+	/*
+	 * puts `count` entries of `from`, starting at `first`, in at `index`.
+	 * The slots move once and the data gets compacted at most once, the
+	 * caller checked that they fit.
+	 */
+	fn insert_from(&mut self, index: uint, from: &SlottedNode, first: uint, count: uint) {
+		let (total, used) = (self.count + count, self.used);
+		let size = range(first, first + count).fold(0, |size, i| {
+			let (_, len) = from.slot(i);
+			size + len + VALUE_SIZE
+		});
+		if self.data_start() < NODE_HEADER + total * SLOT_SIZE + size {
+			self.compact();
+		}
+		let mut start = self.data_start();
+		assert!(start >= NODE_HEADER + total * SLOT_SIZE + size);
+		let moved = self.count - index;
+		{
+			let data = self.data_mut();
+			let at = NODE_HEADER + index * SLOT_SIZE;
+			move_bytes(data, at + count * SLOT_SIZE, at, moved * SLOT_SIZE);
+			for i in range(0, count) {
+				let (offset, len) = from.slot(first + i);
+				start -= len + VALUE_SIZE;
+				data.mut_slice(start, start + len + VALUE_SIZE)
+					.copy_from(from.data.slice(offset, offset + len + VALUE_SIZE));
+				write_u32(data, at + i * SLOT_SIZE, start as u32);
+				write_u32(data, at + i * SLOT_SIZE + 4, len as u32);
 			}
+			write_u32(data, NODE_DATA, start as u32);
 		}
+		self.set_len(total);
+		self.set_used(used + size + count * SLOT_SIZE);
+	}
 
-		found.unwrap()
+	/* gives an entry another key, the caller checked that it fits */
+	fn set_key(&mut self, index: uint, key: &[u8]) {
+		let value = self.value(index);
+		self.remove(index);
+		self.insert(index, key, value);
 	}
 
-	/* moves all items from `index` one number back */
-	fn shift_from(&mut self, index: uint) {
-		// actually, this rotates right by 1 starting from index
-		let last_elem = self.entries.len() - 1;
-		for i in range(index, last_elem) {
-			self.entries.swap(i, last_elem);
+	/* moves the data of the entries to the end of the page, without holes */
+	fn compact(&mut self) {
+		let entries: Vec<(Vec<u8>, u64)> = range(0, self.count)
+			.map(|i| (Vec::from_slice(self.key(i)), self.value(i))).collect();
+		let data = self.data_mut();
+		let mut start = data.len();
+		for (i, &(ref key, value)) in entries.iter().enumerate() {
+			start -= key.len() + VALUE_SIZE;
+			data.mut_slice(start, start + key.len()).copy_from(key.as_slice());
+			write_u64(data, start + key.len(), value);
+			write_u32(data, NODE_HEADER + i * SLOT_SIZE, start as u32);
 		}
+		write_u32(data, NODE_DATA, start as u32);
 	}
 
-	fn shift_to(&mut self, index: uint) {
-		let last_elem = self.entries.len() - 1;
-		for i in range(index, last_elem) {
-			self.entries.swap(i, i+1);
-		}
+	/* moves the first `count` entries behind the ones of `to` */
+	fn move_to_end(&mut self, to: &mut SlottedNode, count: uint) {
+		let at = to.len();
+		to.insert_from(at, self, 0, count);
+		self.remove_range(0, count);
 	}
 
-	fn lookup(self, key: &K) -> Option<schema::TID> {
-		for i in range(0, self.entries.len()) {
-			//println!("Checking {:?} for {:?}", self.entries[i], key);
-			if &self.entries[i].key == key {
-				return Some(self.entries[i].tid)
-			}
+	/* moves the last `count` entries in front of the ones of `to` */
+	fn move_to_front(&mut self, to: &mut SlottedNode, count: uint) {
+		let first = self.count - count;
+		to.insert_from(0, self, first, count);
+		self.remove_range(first, count);
+	}
+
+	/*
+	 * moves the lower half of the entries, by size, into the empty `to`.
+	 * Returns the largest key that moved.
+	 */
+	fn split_into(&mut self, to: &mut SlottedNode) -> Vec<u8> {
+		let half = self.used() / 2;
+		let mut moved = 0;
+		let mut count = 0;
+		while moved < half && count + 1 < self.count {
+			let (_, len) = self.slot(count);
+			moved += entry_size(len);
+			count += 1;
 		}
-		None
+		self.move_to_end(to, count);
+		Vec::from_slice(to.key(count - 1))
 	}
-}
 
-#[unsafe_destructor]
-impl<'a, K> Drop for LeafNode<'a, K> {
-	fn drop(&mut self) {
-		let mut manager = self.manager.lock();
-		// TODO: figure out if page was modified since loading
-		manager.unfix_page(self.frame.clone(), true);
+	/* less than half of it is used */
+	fn underfull(&self) -> bool {
+		self.used() < self.capacity() / 2
 	}
 }
 
-struct BranchNode<'a, K> {
-	capacity: uint,
-	entries: &'a mut [BranchEntry<K>],
-	manager: ConcurrentManager,
-	frame: buffer::ConcurrentFrame,
+fn entry_size(key_len: uint) -> uint {
+	key_len + VALUE_SIZE + SLOT_SIZE
 }
 
-impl<'a, K: Keyish> BranchNode<'a, K> {
-	fn new(manager: ConcurrentManager, frame: buffer::ConcurrentFrame) -> BranchNode<'a, K> {
-		let mut r = {
-			let framelock = frame.read();
-			let page = framelock.get_data();
+fn read_u32(data: &[u8], offset: uint) -> u32 {
+	let mut reader = BufReader::new(data.slice(offset, offset + 4));
+	reader.read_le_u32().unwrap()
+}
 
-			let entry_size = size_of::<BranchEntry<K>>();
-			let entry_num = (page.len() - size_of::<u8>()) / entry_size;
-			let start_from = entry_size;
-			let mut r: &mut [BranchEntry<K>] = unsafe {
-				cast::transmute(
-					Slice::<BranchEntry<K>> {
-						data: page.slice_from(start_from).as_ptr() as *() as *BranchEntry<K>,
-						len: entry_num,
-					}
-				)
-			};
-			r
-		};
+fn read_u64(data: &[u8], offset: uint) -> u64 {
+	let mut reader = BufReader::new(data.slice(offset, offset + 8));
+	reader.read_le_u64().unwrap()
+}
+
+fn write_u32(data: &mut [u8], offset: uint, value: u32) {
+	let mut writer = BufWriter::new(data.mut_slice(offset, offset + 4));
+	match writer.write_le_u32(value) {
+		Ok(()) => (),
+		Err(e) => fail!("Writing to a node failed: {}", e),
+	}
+}
+
+fn write_u64(data: &mut [u8], offset: uint, value: u64) {
+	let mut writer = BufWriter::new(data.mut_slice(offset, offset + 8));
+	match writer.write_le_u64(value) {
+		Ok(()) => (),
+		Err(e) => fail!("Writing to a node failed: {}", e),
+	}
+}
+
+/* memmove within the page */
+fn move_bytes(data: &mut [u8], to: uint, from: uint, len: uint) {
+	assert!(to + len <= data.len() && from + len <= data.len());
+	unsafe {
+		let base = data.as_mut_ptr();
+		ptr::copy_memory(base.offset(to as int), base.offset(from as int) as *u8, len);
+	}
+}
 
-		/* find slots that are used */
-		let mut capacity = r.len();
-		for i in range(0, r.len()) {
-			if r[i].page_id != 0 {
-				capacity -= 1;
+/*
+ * how many entries to move between two neighbours so they are about the
+ * same size. Positive numbers move from the right to the left one.
+ */
+fn plan_balance(left: &SlottedNode, right: &SlottedNode) -> int {
+	let (mut left_used, mut right_used) = (left.used(), right.used());
+	let mut moves = 0;
+	if left_used < right_used {
+		while (moves as uint) + 1 < right.len() {
+			let (_, len) = right.slot(moves as uint);
+			let size = entry_size(len);
+			if right_used <= left_used + size {
+				break;
+			}
+			left_used += size;
+			right_used -= size;
+			moves += 1;
+		}
+	} else {
+		while (-moves as uint) + 1 < left.len() {
+			let (_, len) = left.slot(left.len() - 1 - (-moves as uint));
+			let size = entry_size(len);
+			if left_used <= right_used + size {
+				break;
 			}
+			left_used -= size;
+			right_used += size;
+			moves -= 1;
 		}
+	}
+	moves
+}
 
-		info!("BranchNode computed capacity: {}", capacity);
+struct LeafNode<'a> {
+	node: SlottedNode<'a>,
+	manager: ConcurrentManager,
+	page_id: u64,
+}
 
-		BranchNode {
-			entries: r,
-			capacity: capacity,
-			manager: manager,
-			frame: frame,
-		}
+impl<'a> LeafNode<'a> {
+	fn len(&self) -> uint {
+		self.node.len()
 	}
 
-	fn insert_branch(&mut self, tree: &mut BTree<K>, key: K, value: u64) -> Option<Overflowed<K>> {
-		if self.capacity == 0 {
-			let lazy_node = tree.create_branch_node();
-			let new_node = lazy_node.load(self.manager.clone());
-			let mut new_branch = match new_node {
-				Branch(b) => b,
-				Leaf(_) => fail!("Got leaf node where branch was expected"),
-			};
-			let num_elements_to_move = self.entries.len()/2;
-			let maximum = self.entries[num_elements_to_move].key.clone();
-			// copy them over first
-			for i in range(0, num_elements_to_move) {
-				new_branch.insert_branch(tree,
-					self.entries[i].key.clone(),
-					self.entries[i].page_id);
-			}
-			// erase them second
-			for i in range(0, num_elements_to_move).rev() {
-				let k = self.entries[i].key.clone();
-				self.erase_branch(&k);
+	fn tid(&self, index: uint) -> schema::TID {
+		schema::TID::new_from_u64(self.node.value(index))
+	}
+
+	fn link(&self, offset: uint) -> u64 {
+		read_u64(self.node.data, offset)
+	}
+
+	fn set_link(&mut self, offset: uint, page_id: u64) {
+		write_u64(self.node.data_mut(), offset, page_id);
+	}
+
+	fn prev(&self) -> u64 {
+		self.link(LEAF_PREV)
+	}
+
+	fn next(&self) -> u64 {
+		self.link(LEAF_NEXT)
+	}
+
+	/* how many entries come before `key`, or before and including it */
+	fn rank(&self, key: &[u8], including: bool, compare: Comparator) -> uint {
+		self.node.bisect(key, including, compare)
+	}
+
+	fn insert_value<K: Keyish>(&mut self, tree: &mut BTree<K>, key: &[u8], tid: schema::TID) -> Option<Overflowed> {
+		info!("Leaf insertion, {} of {} bytes used", self.node.used(), self.node.capacity());
+		if !self.node.fits(key.len()) {
+			let lazy_node = tree.create_leaf_node();
+			let mut new_leaf = load_leaf(&self.manager, lazy_node.page_id);
+			let maximum = self.node.split_into(&mut new_leaf.node);
+
+			// the lower half moved, so the new leaf goes in before this one
+			let prev = self.prev();
+			new_leaf.set_link(LEAF_PREV, prev);
+			new_leaf.set_link(LEAF_NEXT, self.page_id);
+			self.set_link(LEAF_PREV, lazy_node.page_id);
+			if prev != 0 {
+				load_leaf(&self.manager, prev).set_link(LEAF_NEXT, lazy_node.page_id);
 			}
 
 			// now let's actually insert that value
-			if key <= maximum {
+			if (tree.compare)(key, maximum.as_slice()) != Greater {
 				// insert into new
-				new_branch.insert_branch(tree, key, value);
+				new_leaf.insert_value(tree, key, tid);
 			} else {
 				// insert into this
-				self.insert_branch(tree, key, value);
+				self.insert_value(tree, key, tid);
 			}
 			// no worries, those can't overflow
 
 			let overflow = Overflowed(maximum, lazy_node.page_id);
-			debug!("Overflow {:?}", overflow);
 			return Some(overflow);
 		}
-		let index = self.find_slot(&key);
-		debug!("Adding new page reference at {}", index);
-		self.shift_from(index);
 
-		self.entries[index].page_id = value;
-		self.entries[index].key = key;
-		self.capacity -= 1;
+		let location = self.rank(key, false, tree.compare);
+		info!("Location found: {}", location);
+		self.node.insert(location, key, tid.as_u64());
+
+		// insertion went fine, done
 		None
 	}
 
-	fn erase_branch(&mut self, key: &K) {
-		for i in range(0, self.entries.len()) {
-			if &self.entries[i].key == key {
-				self.entries[i].key = Zero::zero();
-				self.entries[i].page_id = 0;
-				self.capacity += 1;
-				self.shift_to(i);
-				break;
-			}
+	/* whether the key was there */
+	fn erase(&mut self, key: &[u8], compare: Comparator) -> bool {
+		let index = self.rank(key, false, compare);
+		if index < self.len() && compare(self.node.key(index), key) == Equal {
+			self.node.remove(index);
+			true
+		} else {
+			false
 		}
 	}
 
-	fn erase(&mut self, manager: ConcurrentManager, key: &K) {
-		let mut found = None;
-		for i in range(0, self.entries.len()) {
-			if self.entries[i].key.is_zero() {
-				continue;
-			}
-			if key <= &self.entries[i].key {
-				found = Some(i);
-				break;
-			}
+	fn lookup(self, key: &[u8], compare: Comparator) -> Option<schema::TID> {
+		let index = self.rank(key, false, compare);
+		if index < self.len() && compare(self.node.key(index), key) == Equal {
+			Some(self.tid(index))
+		} else {
+			None
 		}
+	}
+}
 
-		match found {
-			None => (),
-			Some(index) => {
-				let lazy_node = LazyNode::new(self.entries[index].page_id);
-				match lazy_node.load(manager.clone()) {
-					Branch(mut n) => n.erase(manager.clone(), key),
-					Leaf(mut n) => n.erase(key),
-				};
-			},
-		}
+struct BranchNode<'a> {
+	node: SlottedNode<'a>,
+	manager: ConcurrentManager,
+}
+
+impl<'a> BranchNode<'a> {
+	fn len(&self) -> uint {
+		self.node.len()
+	}
+
+	/*
+	 * the child a key belongs in. A child has the keys up to its
+	 * separator, the last one also all that are larger.
+	 */
+	fn find_child(&self, key: &[u8], compare: Comparator) -> uint {
+		min(self.node.bisect(key, false, compare), self.len() - 1)
 	}
 
-	// duplicated from LeafNode
-	fn shift_to(&mut self, index: uint) {
-		let last_elem = self.entries.len() - 1;
-		for i in range(index, last_elem) {
-			self.entries.swap(i, i+1);
+	/*
+	 * the first child that can have the key, or the last one with `last`.
+	 * Without a key, the first or the last child.
+	 */
+	fn child(&self, key: Option<&[u8]>, last: bool, compare: Comparator) -> Option<u64> {
+		if self.len() == 0 {
+			return None;
 		}
+		let index = match key {
+			None if !last => 0,
+			None => self.len() - 1,
+			Some(key) => min(self.node.bisect(key, last, compare), self.len() - 1),
+		};
+		Some(self.node.value(index))
 	}
 
-	// duplicated from LeafNode, Rust doesn't do inheritance
-	fn find_slot(&self, key: &K) -> uint {
-		let mut found = None;
-		for i in range(0, self.entries.len()) {
-			//println!("Checking {:?} against {:?}", key, self.entries[i].key);
-			if self.entries[i].key.is_zero() {
-				found = Some(i);
-				break;
-			}
-			// there is no prev element to compare
-			if i == 0 {
-				if key < &self.entries[i].key {
-					found = Some(i);
-					break;
-				}
-				continue;
-			}
+	fn insert_branch<K: Keyish>(&mut self, tree: &mut BTree<K>, key: &[u8], value: u64) -> Option<Overflowed> {
+		if !self.node.fits(key.len()) {
+			let lazy_node = tree.create_branch_node();
+			let mut new_branch = load_branch(&self.manager, lazy_node.page_id);
+			let maximum = self.node.split_into(&mut new_branch.node);
 
-			if &self.entries[i - 1].key < key && key < &self.entries[i].key {
-				found = Some(i);
-				break;
+			// now let's actually insert that value
+			if (tree.compare)(key, maximum.as_slice()) != Greater {
+				// insert into new
+				new_branch.insert_branch(tree, key, value);
+			} else {
+				// insert into this
+				self.insert_branch(tree, key, value);
 			}
-		}
+			// no worries, those can't overflow
 
-		found.unwrap()
+			let overflow = Overflowed(maximum, lazy_node.page_id);
+			debug!("Overflow into page {}", lazy_node.page_id);
+			return Some(overflow);
+		}
+		let index = self.node.bisect(key, false, tree.compare);
+		debug!("Adding new page reference at {}", index);
+		self.node.insert(index, key, value);
+		None
 	}
 
-	// duplicated from LeafNode
-	fn shift_from(&mut self, index: uint) {
-		// actually, this rotates right by 1 starting from index
-		let last_elem = self.entries.len() - 1;
-		for i in range(index, last_elem) {
-			self.entries.swap(i, last_elem);
+	/* whether the key was there. Children that get underfull are rebalanced. */
+	fn erase<K: Keyish>(&mut self, tree: &mut BTree<K>, key: &[u8]) -> bool {
+		if self.len() == 0 {
+			return false;
+		}
+		let index = self.find_child(key, tree.compare);
+		let lazy_node = LazyNode::new(self.node.value(index));
+		let (erased, underfull) = match lazy_node.load(tree.manager.clone()) {
+			Branch(mut n) => (n.erase(tree, key), n.node.underfull()),
+			Leaf(mut n) => (n.erase(key, tree.compare), n.node.underfull()),
+		};
+		if underfull {
+			self.rebalance(tree, index);
 		}
+		erased
 	}
 
-	/* might return a new branch node if this one was split */
-	fn insert_value(&mut self, tree: &mut BTree<K>, key: K, value: schema::TID) -> Option<Overflowed<K>> {
-		// locate the place where to insert
-		let mut place = None;
-		for i in range(0, self.entries.len()) {
-			if self.entries[i].page_id == 0 {
-				continue
-			}
-			if key <= self.entries[i].key {
-				debug!("Found candidate, {:?} <= {:?}", key, self.entries[i].key);
-				place = Some(i);
-				break;
-			}
+	/*
+	 * evens out the child at `index` with a neighbour, or merges the two if
+	 * they fit into one node. The left one of them stays, the separators
+	 * get adjusted to what the children hold now. If the separator doesn't
+	 * fit, the children stay as they are.
+	 */
+	fn rebalance<K: Keyish>(&mut self, tree: &mut BTree<K>, index: uint) {
+		if self.len() < 2 {
+			return;
 		}
-
-		match place {
-			/* no already existing place to insert exists */
-			None => {
-				// there is no such page, but it should be created
-				let lazy_node = tree.create_leaf_node();
-				let new_node = lazy_node.load(tree.manager.clone());
-				match new_node {
-					Leaf(mut n) => n.insert_value(tree, key.clone(), value),
-					Branch(_) => fail!("Did not create a leaf page"),
-				};
-				self.insert_branch(tree, key, lazy_node.page_id)
-			},
-			/* a place to insert it was found */
-			Some(index) => {
-				let lazy_node = LazyNode::new(self.entries[index].page_id);
-				let new_node = lazy_node.load(tree.manager.clone());
-				let overflowed = match new_node {
-					Leaf(mut n) => n.insert_value(tree, key, value),
-					Branch(mut n) => n.insert_value(tree, key, value),
-				};
-				match overflowed {
-					None => None,
-					Some(Overflowed(max, page)) => self.insert_branch(tree, max, page),
+		let left_index = if index + 1 < self.len() {index} else {index - 1};
+		let left_page = self.node.value(left_index);
+		let right_page = self.node.value(left_index + 1);
+
+		let merged = match LazyNode::new(left_page).load(tree.manager.clone()) {
+			Leaf(mut left) => {
+				let mut right = load_leaf(&tree.manager, right_page);
+				let merged = self.balance(left_index, &mut left.node, &mut right.node);
+				if merged {
+					// the right leaf goes away, so does it from the chain
+					let next = right.next();
+					left.set_link(LEAF_NEXT, next);
+					if next != 0 {
+						load_leaf(&tree.manager, next).set_link(LEAF_PREV, left_page);
+					}
 				}
-			}
+				merged
+			},
+			Branch(mut left) => {
+				let mut right = load_branch(&tree.manager, right_page);
+				self.balance(left_index, &mut left.node, &mut right.node)
+			},
+		};
+		if merged {
+			debug!("Merged page {} into {}", right_page, left_page);
+			tree.free_page(right_page);
 		}
 	}
 
-	fn lookup(self, manager: ConcurrentManager, key: &K) -> Option<schema::TID> {
-		// find the page to descend to
-		let mut next_page = None;
-		for i in range(0, self.entries.len()) {
-			// skip all empty fields
-			if self.entries[i].key.is_zero() && self.entries[i].page_id == 0 {
-				continue
-			}
-			debug!("Entry {}: {:?}", i, self.entries[i]);
-			if i == 0 {
-				if key <= &self.entries[i].key {
-					next_page = Some(self.entries[i].page_id);
-				}
-				continue;
-			}
-			if &self.entries[i-1].key < key && key <= &self.entries[i].key {
-				// found the branch into which to descend
-				next_page = Some(self.entries[i].page_id);
-				break;
-			}
+	/* does the work of rebalance for the two children, true if they were merged */
+	fn balance(&mut self, left_index: uint, left: &mut SlottedNode, right: &mut SlottedNode) -> bool {
+		if left.used() + right.used() <= left.capacity() {
+			let count = right.len();
+			right.move_to_end(left, count);
+			// the right separator covers both now
+			let separator = Vec::from_slice(self.node.key(left_index + 1));
+			self.node.remove(left_index + 1);
+			self.node.set_key(left_index, separator.as_slice());
+			return true;
 		}
-		info!("Going for {:?}", next_page);
+		let moves = plan_balance(left, right);
+		if moves == 0 {
+			return false;
+		}
+		// the largest key the left one is going to have
+		let separator = if moves > 0 {
+			Vec::from_slice(right.key(moves as uint - 1))
+		} else {
+			Vec::from_slice(left.key(left.len() - 1 - (-moves as uint)))
+		};
+		if !self.node.fits_instead(left_index, separator.len()) {
+			return false;
+		}
+		if moves > 0 {
+			right.move_to_end(left, moves as uint);
+		} else {
+			left.move_to_front(right, -moves as uint);
+		}
+		self.node.set_key(left_index, separator.as_slice());
+		false
+	}
 
-		match next_page {
-			// if there is no page to descend to, it can't be found
-			None => None,
-			Some(page_id) => {
-				let ln = LazyNode::new(page_id);
-				let node = ln.load(manager);
-				match node {
-					Branch(n) => n.lookup(self.manager.clone(), key),
-					Leaf(n) => n.lookup(key),
-				}
-			}
+	fn lookup(self, manager: ConcurrentManager, key: &[u8], compare: Comparator) -> Option<schema::TID> {
+		// an empty root has nothing to descend to
+		if self.len() == 0 {
+			return None;
+		}
+		// find the page to descend to
+		let index = self.find_child(key, compare);
+		info!("Going for entry {} of {}", index, self.len());
+
+		let ln = LazyNode::new(self.node.value(index));
+		let node = ln.load(manager);
+		match node {
+			Branch(n) => n.lookup(self.manager.clone(), key, compare),
+			Leaf(n) => n.lookup(key, compare),
 		}
 	}
-}
 
-#[unsafe_destructor]
-impl<'a, K> Drop for BranchNode<'a, K> {
-	fn drop(&mut self) {
-		let mut manager = self.manager.lock();
-		// TODO: figure out if page was modified since loading
-		manager.unfix_page(self.frame.clone(), true);
+	/* might return a new branch node if this one was split */
+	fn insert_value<K: Keyish>(&mut self, tree: &mut BTree<K>, key: &[u8], value: schema::TID) -> Option<Overflowed> {
+		if self.len() == 0 {
+			// the node is empty, there is nothing to descend into, so
+			// the page has to be created
+			let lazy_node = tree.create_leaf_node();
+			let mut leaf = load_leaf(&tree.manager, lazy_node.page_id);
+			leaf.insert_value(tree, key, value);
+			return self.insert_branch(tree, key, lazy_node.page_id);
+		}
+
+		// locate the place where to insert
+		let index = self.find_child(key, tree.compare);
+		let lazy_node = LazyNode::new(self.node.value(index));
+		let new_node = lazy_node.load(tree.manager.clone());
+		let overflowed = match new_node {
+			Leaf(mut n) => n.insert_value(tree, key, value),
+			Branch(mut n) => n.insert_value(tree, key, value),
+		};
+		match overflowed {
+			None => None,
+			Some(Overflowed(max, page)) => self.insert_branch(tree, max.as_slice(), page),
+		}
 	}
 }
 
 #[test]
 fn simple_insert() {
-	let dir = match TempDir::new("btree") {
-		Some(temp_dir) => temp_dir,
-		None => fail!("creation of temporary directory"),
-	};
-
-	let p = dir.path();
-	//let p = Path::new(".");
-
-	let manager = buffer::BufferManager::new(1024, p.clone());
-	let mut bt = BTree::new(23, Rc::new(Mutex::new(manager)));
+	let manager = buffer::BufferManager::with_storage(1024,
+		~storage::MemoryStorage::new() as ~storage::Storage:Send, replacement::LRU).unwrap();
+	let mut bt = BTree::new(23, Arc::new(manager));
 	let some_tid = schema::TID::new(0, 0);
 	bt.insert(42, some_tid);
 	let res = bt.lookup(&42).unwrap();
@@ -647,16 +1269,9 @@ fn split_leaf_insert() {
 }
 
 fn split_insert(leaf: bool) {
-	let dir = match TempDir::new("btree") {
-		Some(temp_dir) => temp_dir,
-		None => fail!("creation of temporary directory"),
-	};
-
-	let p = dir.path();
-	//let p = Path::new(".");
-
-	let manager = buffer::BufferManager::new(1024, p.clone());
-	let mut bt = BTree::new(23, Rc::new(Mutex::new(manager)));
+	let manager = buffer::BufferManager::with_storage(1024,
+		~storage::MemoryStorage::new() as ~storage::Storage:Send, replacement::LRU).unwrap();
+	let mut bt = BTree::new(23, Arc::new(manager));
 	let some_tid = schema::TID::new(23, 42);
 
 	// this causes it to fill the leaf first
@@ -674,6 +1289,152 @@ fn split_insert(leaf: bool) {
 	}
 }
 
+#[test]
+fn small_pages() {
+	let geometry = buffer::Geometry::new(512, buffer::DEFAULT_PAGE_BITS).unwrap();
+	let manager = buffer::BufferManager::create(1024,
+		~storage::MemoryStorage::new() as ~storage::Storage:Send, replacement::LRU,
+		geometry).unwrap();
+	let mut bt = BTree::new(23, Arc::new(manager));
+	// a leaf only takes 20 entries, so this needs a bunch of them
+	for i in range(1, 200) {
+		bt.insert(i, schema::TID::new(i as u64, 0));
+	}
+	for i in range(1, 200) {
+		assert_eq!(bt.lookup(&i), Some(schema::TID::new(i as u64, 0)));
+	}
+}
+
+#[cfg(test)]
+fn range_keys(bt: &BTree<u64>, lower: Bound<u64>, upper: Bound<u64>) -> Vec<u64> {
+	bt.range(lower, upper).map(|(k, _)| k).collect()
+}
+
+#[test]
+fn range_scans() {
+	let geometry = buffer::Geometry::new(512, buffer::DEFAULT_PAGE_BITS).unwrap();
+	let manager = buffer::BufferManager::create(1024,
+		~storage::MemoryStorage::new() as ~storage::Storage:Send, replacement::LRU,
+		geometry).unwrap();
+	let mut bt = BTree::new(23, Arc::new(manager));
+	assert_eq!(bt.range(Unbounded, Unbounded).count(), 0);
+	// the even keys from 2 to 1000, all over a bunch of leaves
+	for i in range(1_u64, 501) {
+		bt.insert((i * 263) % 500 * 2 + 2, schema::TID::new(i, 0));
+	}
+	assert_eq!(range_keys(&bt, Unbounded, Unbounded), range(1_u64, 501).map(|i| i * 2).collect());
+	assert_eq!(range_keys(&bt, Included(100), Included(110)), vec!(100, 102, 104, 106, 108, 110));
+	assert_eq!(range_keys(&bt, Excluded(100), Excluded(110)), vec!(102, 104, 106, 108));
+	assert_eq!(range_keys(&bt, Included(101), Excluded(107)), vec!(102, 104, 106));
+	assert_eq!(range_keys(&bt, Excluded(996), Unbounded), vec!(998, 1000));
+	assert_eq!(range_keys(&bt, Excluded(1000), Unbounded), vec!());
+	assert_eq!(range_keys(&bt, Included(50), Excluded(50)), vec!());
+
+	let reversed: Vec<u64> = bt.range(Unbounded, Included(9)).rev().map(|(k, _)| k).collect();
+	assert_eq!(reversed, vec!(8, 6, 4, 2));
+	// the ends meet in the middle
+	let mut cursor = bt.range(Included(2), Included(10));
+	assert_eq!(cursor.next().map(|(k, _)| k), Some(2));
+	assert_eq!(cursor.next_back().map(|(k, _)| k), Some(10));
+	assert_eq!(cursor.map(|(k, _)| k).collect::<Vec<u64>>(), vec!(4, 6, 8));
+	// the values come along
+	let (key, tid) = bt.range(Included(2), Unbounded).next().unwrap();
+	assert_eq!(bt.lookup(&key), Some(tid));
+}
+
+#[test]
+fn slotted_nodes() {
+	let manager = buffer::BufferManager::with_storage(16,
+		~storage::MemoryStorage::new() as ~storage::Storage:Send, replacement::LRU).unwrap();
+	let mut leaf = full_leaf(Arc::new(manager), 1);
+	let len = leaf.len();
+	for i in range(0, len) {
+		let key = Vec::from_slice(leaf.node.key(i));
+		assert_eq!(leaf.rank(key.as_slice(), false, bytewise), i);
+		assert_eq!(leaf.rank(key.as_slice(), true, bytewise), i + 1);
+		let smaller = (i as u64 * 2 + 1).encode();
+		assert_eq!(leaf.rank(smaller.as_slice(), true, bytewise), linear_rank(&leaf.node, smaller.as_slice()));
+	}
+	// the removed entry leaves a hole, which gets compacted for the second insert
+	leaf.node.remove(0);
+	assert_eq!(leaf.node.key(0), 4_u64.encode().as_slice());
+	assert!(leaf.node.fits(8));
+	leaf.node.insert(0, 1_u64.encode().as_slice(), 1);
+	leaf.node.insert(0, 0_u64.encode().as_slice(), 0);
+	assert_eq!(leaf.len(), len + 1);
+	for i in range(0, len + 1) {
+		let key = if i < 2 {i as u64} else {i as u64 * 2};
+		assert_eq!(leaf.node.key(i), key.encode().as_slice());
+	}
+	assert_eq!(leaf.node.value(1), 1);
+	assert_eq!(leaf.tid(2), schema::TID::new(4, 0));
+	assert!(!leaf.node.fits(8));
+	// the header keeps count of the bytes in use, holes left out
+	let used = range(0, leaf.len()).fold(0, |used, i| used + entry_size(leaf.node.key(i).len()));
+	assert_eq!(leaf.node.used(), used);
+}
+
+#[test]
+fn moves_between_nodes() {
+	let manager = Arc::new(buffer::BufferManager::with_storage(16,
+		~storage::MemoryStorage::new() as ~storage::Storage:Send, replacement::LRU).unwrap());
+	let mut left = full_leaf(manager.clone(), 40);
+	let mut bt: BTree<u64> = BTree::open(23, manager.clone());
+	let lazy_node = bt.create_leaf_node();
+	let mut right = load_leaf(&manager, lazy_node.page_id);
+	let len = left.len();
+	// back and forth, leaving holes behind
+	left.node.move_to_front(&mut right.node, 30);
+	right.node.move_to_end(&mut left.node, 10);
+	left.node.remove(0);
+	right.node.move_to_end(&mut left.node, 20);
+	left.node.insert(0, 2_u64.encode().as_slice(), schema::TID::new(2, 0).as_u64());
+	assert_eq!((left.len(), right.len()), (len, 0));
+	assert_eq!(right.node.used(), 0);
+	for i in range(0, len) {
+		let key = i as u64 * 2 + 2;
+		assert_eq!(left.node.key(i), key.encode().as_slice());
+		assert_eq!(left.tid(i), schema::TID::new(key, 0));
+	}
+	assert_eq!(left.node.used(), len * entry_size(8));
+}
+
+#[test]
+fn key_encodings_sort() {
+	let signed = [-300_i64, -1, 0, 1, 300];
+	for pair in signed.windows(2) {
+		assert!(pair[0].encode() < pair[1].encode());
+		assert_eq!(Keyish::decode(pair[0].encode().as_slice()), pair[0]);
+	}
+	let small = [-7_i32, 0, 7];
+	for pair in small.windows(2) {
+		assert!(pair[0].encode() < pair[1].encode());
+		assert_eq!(Keyish::decode(pair[1].encode().as_slice()), pair[1]);
+	}
+	assert!(255_u32.encode() < 256_u32.encode());
+	let name: ~str = Keyish::decode((~"nagoya").encode().as_slice());
+	assert_eq!(name, ~"nagoya");
+}
+
+/*
+ * a split moves the lower half, the key right above it stays. Lookups of
+ * the keys on both sides of every split go to the node that has them.
+ */
+#[test]
+fn split_separators() {
+	let geometry = buffer::Geometry::new(512, buffer::DEFAULT_PAGE_BITS).unwrap();
+	let manager = buffer::BufferManager::create(1024,
+		~storage::MemoryStorage::new() as ~storage::Storage:Send, replacement::LRU,
+		geometry).unwrap();
+	let mut bt = BTree::new(23, Arc::new(manager));
+	for i in range(1, 300).rev() {
+		bt.insert(i, schema::TID::new(i as u64, 0));
+	}
+	for i in range(1, 300) {
+		assert_eq!(bt.lookup(&i), Some(schema::TID::new(i as u64, 0)));
+	}
+}
+
 #[test]
 fn split_branch_insert() {
 	split_insert(false);
@@ -681,36 +1442,376 @@ fn split_branch_insert() {
 
 #[test]
 fn lookup_nonexisting() {
+	let manager = buffer::BufferManager::with_storage(1024,
+		~storage::MemoryStorage::new() as ~storage::Storage:Send, replacement::LRU).unwrap();
+	let bt = BTree::new(23, Arc::new(manager));
+	let result = bt.lookup(&42);
+	assert_eq!(result, None);
+}
+
+#[test]
+fn simple_erase() {
+	let manager = buffer::BufferManager::with_storage(1024,
+		~storage::MemoryStorage::new() as ~storage::Storage:Send, replacement::LRU).unwrap();
+	let mut bt = BTree::new(23, Arc::new(manager));
+	let some_tid = schema::TID::new(23, 42);
+	let some_key = 42;
+	bt.insert(some_key, some_tid);
+	bt.erase(&some_key);
+	let result = bt.lookup(&42);
+	assert_eq!(result, None);
+}
+
+#[test]
+fn erase_rebalances() {
+	let geometry = buffer::Geometry::new(512, buffer::DEFAULT_PAGE_BITS).unwrap();
+	let manager = Arc::new(buffer::BufferManager::create(1024,
+		~storage::MemoryStorage::new() as ~storage::Storage:Send, replacement::LRU,
+		geometry).unwrap());
+	let mut bt = BTree::new(23, manager.clone());
+	for i in range(1_u64, 1000) {
+		bt.insert(tree_key(i), schema::TID::new(i, 0));
+	}
+	assert!(bt.height > 1);
+
+	for i in range(1_u64, 1000).filter(|&i| i % 2 == 0) {
+		bt.erase(&tree_key(i));
+	}
+	for i in range(1_u64, 1000) {
+		let expected = if i % 2 == 0 {None} else {Some(schema::TID::new(i, 0))};
+		assert_eq!(bt.lookup(&tree_key(i)), expected);
+	}
+	// the leaves are still chained in order
+	let keys = range_keys(&bt, Unbounded, Unbounded);
+	assert_eq!(keys.len(), 500);
+	assert!(keys.as_slice().windows(2).all(|pair| pair[0] < pair[1]));
+
+	for i in range(1_u64, 1000).filter(|&i| i % 2 == 1) {
+		bt.erase(&tree_key(i));
+	}
+	assert_eq!(range_keys(&bt, Unbounded, Unbounded), vec!());
+	// down to the metadata, the root and a single leaf
+	assert_eq!(bt.height, 1);
+	let free_space = freespace::FreeSpaceMap::new(23, manager);
+	let mut pages = 0;
+	let mut next = free_space.next_allocated(0).unwrap();
+	while next.is_some() {
+		pages += 1;
+		next = free_space.next_allocated(next.unwrap() + 1).unwrap();
+	}
+	assert_eq!(pages, 3);
+
+	// and it grows again
+	bt.insert(42, schema::TID::new(42, 0));
+	assert_eq!(bt.lookup(&42), Some(schema::TID::new(42, 0)));
+}
+
+#[test]
+fn survives_crash() {
+	let faults = faulty::FaultInjector::new();
+	{
+		let manager = Arc::new(buffer::BufferManager::with_storage(16,
+			~faults.storage() as ~storage::Storage:Send, replacement::LRU).unwrap());
+		let mut bt = BTree::new(23, manager.clone());
+		for i in range(1, 200) {
+			bt.insert(i, schema::TID::new(i as u64, 0));
+		}
+		manager.flush_all().unwrap();
+		// these get lost
+		for i in range(200, 300) {
+			bt.insert(i, schema::TID::new(i as u64, 0));
+		}
+		faults.crash();
+	}
+
+	let manager = buffer::BufferManager::with_storage(16,
+		~faults.storage() as ~storage::Storage:Send, replacement::LRU).unwrap();
+	let bt: BTree<int> = BTree::open(23, Arc::new(manager));
+	for i in range(1, 200) {
+		assert_eq!(bt.lookup(&i), Some(schema::TID::new(i as u64, 0)));
+	}
+}
+
+#[test]
+fn read_only_lookup() {
+	use std::io::TempDir;
+
 	let dir = match TempDir::new("btree") {
 		Some(temp_dir) => temp_dir,
 		None => fail!("creation of temporary directory"),
 	};
+	{
+		let manager = buffer::BufferManager::new(64, dir.path().clone(), replacement::LRU).unwrap();
+		let mut bt = BTree::new(23, Arc::new(manager));
+		for i in range(1, 200) {
+			bt.insert(i, schema::TID::new(i as u64, 0));
+		}
+	}
 
-	let p = dir.path();
-	//let p = Path::new(".");
-
-	let manager = buffer::BufferManager::new(1024, p.clone());
-	let bt = BTree::new(23, Rc::new(Mutex::new(manager)));
-	let result = bt.lookup(&42);
-	assert_eq!(result, None);
+	let manager = buffer::BufferManager::read_only(64, dir.path().clone(), replacement::LRU).unwrap();
+	let bt: BTree<int> = BTree::open(23, Arc::new(manager));
+	for i in range(1, 200) {
+		assert_eq!(bt.lookup(&i), Some(schema::TID::new(i as u64, 0)));
+	}
+	assert_eq!(bt.lookup(&500), None);
 }
 
 #[test]
-fn simple_erase() {
+fn reopen_after_root_split() {
+	use std::io::TempDir;
+
 	let dir = match TempDir::new("btree") {
 		Some(temp_dir) => temp_dir,
 		None => fail!("creation of temporary directory"),
 	};
+	// small pages, so the root gets split a couple of times
+	let geometry = buffer::Geometry::new(512, buffer::DEFAULT_PAGE_BITS).unwrap();
+	let height = {
+		let manager = buffer::BufferManager::create(64,
+			~storage::FileStorage::new(dir.path().clone()) as ~storage::Storage:Send,
+			replacement::LRU, geometry).unwrap();
+		let mut bt = BTree::new(23, Arc::new(manager));
+		for i in range(1_u64, 1000) {
+			bt.insert(i, schema::TID::new(i, 0));
+		}
+		assert!(bt.height > 1);
+		bt.height
+	};
+
+	let manager = buffer::BufferManager::new(64, dir.path().clone(), replacement::LRU).unwrap();
+	let mut bt: BTree<u64> = BTree::open(23, Arc::new(manager));
+	assert_eq!(bt.height, height);
+	for i in range(1_u64, 1000) {
+		assert_eq!(bt.lookup(&i), Some(schema::TID::new(i, 0)));
+	}
+	// and the reopened tree can grow further
+	for i in range(1000_u64, 1200) {
+		bt.insert(i, schema::TID::new(i, 0));
+	}
+	assert_eq!(bt.lookup(&1), Some(schema::TID::new(1, 0)));
+	assert_eq!(bt.lookup(&1199), Some(schema::TID::new(1199, 0)));
+}
 
-	let p = dir.path();
-	//let p = Path::new(".");
+/* keys of all sorts of lengths, all different */
+#[cfg(test)]
+fn varchar_key(i: u64) -> ~str {
+	format!("{}{}", "ab".repeat((i % 30) as uint), tree_key(i))
+}
 
-	let manager = buffer::BufferManager::new(1024, p.clone());
-	let mut bt = BTree::new(23, Rc::new(Mutex::new(manager)));
-	let some_tid = schema::TID::new(23, 42);
-	let some_key = 42;
-	bt.insert(some_key, some_tid);
-	bt.erase(&some_key);
-	let result = bt.lookup(&42);
-	assert_eq!(result, None);
+#[test]
+fn varchar_keys() {
+	let geometry = buffer::Geometry::new(512, buffer::DEFAULT_PAGE_BITS).unwrap();
+	let manager = buffer::BufferManager::create(1024,
+		~storage::MemoryStorage::new() as ~storage::Storage:Send, replacement::LRU,
+		geometry).unwrap();
+	let mut bt: BTree<~str> = BTree::new(23, Arc::new(manager));
+	for i in range(1_u64, 500) {
+		bt.insert(varchar_key(i), schema::TID::new(i, 0));
+	}
+	assert!(bt.height > 1);
+	let mut expected: Vec<~str> = range(1_u64, 500).map(|i| varchar_key(i)).collect();
+	expected.as_mut_slice().sort();
+	let keys: Vec<~str> = bt.range(Unbounded, Unbounded).map(|(k, _)| k).collect();
+	assert_eq!(keys, expected);
+
+	for i in range(1_u64, 500).filter(|&i| i % 3 == 0) {
+		bt.erase(&varchar_key(i));
+	}
+	for i in range(1_u64, 500) {
+		let expected = if i % 3 == 0 {None} else {Some(schema::TID::new(i, 0))};
+		assert_eq!(bt.lookup(&varchar_key(i)), expected);
+	}
+	assert_eq!(bt.lookup(&~""), None);
+}
+
+#[test]
+#[should_fail]
+fn key_too_long() {
+	let manager = buffer::BufferManager::with_storage(16,
+		~storage::MemoryStorage::new() as ~storage::Storage:Send, replacement::LRU).unwrap();
+	let mut bt = BTree::new(23, Arc::new(manager));
+	bt.insert(Vec::from_elem(4096, 1_u8), schema::TID::new(1, 0));
+}
+
+#[cfg(test)]
+static CASE_INSENSITIVE_TAG: u8 = 1;
+
+/* orders ASCII letters regardless of their case */
+#[cfg(test)]
+fn case_insensitive(a: &[u8], b: &[u8]) -> Ordering {
+	let lower = |c: u8| if c >= 'A' as u8 && c <= 'Z' as u8 {c + 32} else {c};
+	let a: Vec<u8> = a.iter().map(|&c| lower(c)).collect();
+	let b: Vec<u8> = b.iter().map(|&c| lower(c)).collect();
+	a.as_slice().cmp(&b.as_slice())
+}
+
+#[test]
+fn custom_comparator() {
+	let manager = Arc::new(buffer::BufferManager::with_storage(16,
+		~storage::MemoryStorage::new() as ~storage::Storage:Send, replacement::LRU).unwrap());
+	{
+		let mut bt = BTree::with_comparator(23, manager.clone(), CASE_INSENSITIVE_TAG,
+			case_insensitive);
+		bt.insert(~"banana", schema::TID::new(2, 0));
+		bt.insert(~"Cherry", schema::TID::new(3, 0));
+		bt.insert(~"apple", schema::TID::new(1, 0));
+		let keys: Vec<~str> = bt.range(Unbounded, Unbounded).map(|(k, _)| k).collect();
+		assert_eq!(keys, vec!(~"apple", ~"banana", ~"Cherry"));
+	}
+	let bt: BTree<~str> = BTree::open_with_comparator(23, manager, CASE_INSENSITIVE_TAG,
+		case_insensitive);
+	assert_eq!(bt.lookup(&~"APPLE"), Some(schema::TID::new(1, 0)));
+	assert_eq!(bt.lookup(&~"cherry"), Some(schema::TID::new(3, 0)));
+	let keys: Vec<~str> = bt.range(Excluded(~"APPLE"), Included(~"BANANA")).map(|(k, _)| k).collect();
+	assert_eq!(keys, vec!(~"banana"));
+}
+
+#[test]
+#[should_fail]
+fn wrong_comparator() {
+	let manager = Arc::new(buffer::BufferManager::with_storage(16,
+		~storage::MemoryStorage::new() as ~storage::Storage:Send, replacement::LRU).unwrap());
+	let _: BTree<~str> = BTree::with_comparator(23, manager.clone(), CASE_INSENSITIVE_TAG,
+		case_insensitive);
+	let _: BTree<~str> = BTree::open(23, manager);
+}
+
+#[test]
+fn index_varchar_column() {
+	let manager = Arc::new(buffer::BufferManager::with_storage(64,
+		~storage::MemoryStorage::new() as ~storage::Storage:Send, replacement::LRU).unwrap());
+	let mut seg = schema::SPSegment::new(1, manager.clone());
+	let mut column = schema::Column::new(~"name", schema::Varchar(16), vec!());
+	for name in ["carol", "alice", "bob"].iter() {
+		column.insert(&mut seg, schema::Record::new(Vec::from_slice(name.as_bytes())));
+	}
+	let bt = index_column(23, manager, &seg, &column);
+	assert_eq!(bt.lookup(&Vec::from_slice("bob".as_bytes())), Some(column.tids()[2]));
+	assert_eq!(bt.lookup(&Vec::from_slice("dave".as_bytes())), None);
+	let names: Vec<Vec<u8>> = bt.range(Unbounded, Unbounded).map(|(k, _)| k).collect();
+	assert_eq!(names, vec!(Vec::from_slice("alice".as_bytes()), Vec::from_slice("bob".as_bytes()),
+		Vec::from_slice("carol".as_bytes())));
+}
+
+#[test]
+fn index_integer_column() {
+	let manager = Arc::new(buffer::BufferManager::with_storage(64,
+		~storage::MemoryStorage::new() as ~storage::Storage:Send, replacement::LRU).unwrap());
+	let mut seg = schema::SPSegment::new(1, manager.clone());
+	let mut column = schema::Column::new(~"age", schema::Integer, vec!());
+	for age in [40, -3, 7].iter() {
+		column.insert(&mut seg, schema::Record::from_int(*age));
+	}
+	let bt = index_column(23, manager, &seg, &column);
+	assert_eq!(bt.lookup(&(-3_i).encode()), Some(column.tids()[1]));
+	assert_eq!(bt.lookup(&3_i.encode()), None);
+	// in the order of the numbers, not of their bytes on the record pages
+	let ages: Vec<int> = bt.range(Unbounded, Unbounded).map(|(k, _)| Keyish::decode(k.as_slice())).collect();
+	assert_eq!(ages, vec!(-3, 7, 40));
+}
+
+/* all different, but not in order */
+#[cfg(test)]
+fn tree_key(i: u64) -> u64 {
+	(i * 7919) % 1000 + 1
+}
+
+#[cfg(test)]
+fn tree_work(manager: ConcurrentManager, step: ||) {
+	let mut bt = BTree::new(23, manager.clone());
+	step();
+	for i in range(1_u64, 300) {
+		bt.insert(tree_key(i), schema::TID::new(i, 0));
+		if i % 25 == 0 {
+			manager.flush_all().unwrap();
+		}
+		step();
+	}
+}
+
+#[test]
+fn survives_crash_in_split() {
+	let writes = recovery::writes_per_step(64, tree_work);
+	// splits move half a node, so besides the flushes they write the most
+	let mut heavy: Vec<(uint, uint, uint)> = range(1, writes.len()).filter(|&i| i % 25 != 0)
+		.map(|i| (*writes.get(i) - *writes.get(i - 1), *writes.get(i - 1), *writes.get(i)))
+		.collect();
+	heavy.as_mut_slice().sort_by(|a, b| b.cmp(a));
+	let points = recovery::crash_points(4, |i, random| {
+		let &(_, from, to) = heavy.get(i);
+		from + 1 + random % (to - from)
+	});
+
+	for (steps, manager) in recovery::check_recovery(tree_work, 23, 16, points.as_slice()).move_iter() {
+		let bt: BTree<u64> = BTree::open(23, manager);
+		// the first step only set up the tree
+		for i in range(1, steps as u64) {
+			assert_eq!(bt.lookup(&tree_key(i)), Some(schema::TID::new(i, 0)));
+		}
+		assert_eq!(bt.lookup(&tree_key(steps as u64)), None);
+	}
+}
+
+/* a leaf with the even keys from 2 on, room for fewer than `free` + 1 more */
+#[cfg(test)]
+fn full_leaf<'a>(manager: ConcurrentManager, free: uint) -> LeafNode<'a> {
+	let mut bt: BTree<u64> = BTree::new(23, manager);
+	let lazy_node = bt.create_leaf_node();
+	let mut leaf = load_leaf(&bt.manager, lazy_node.page_id);
+	let mut key = 2_u64;
+	while leaf.node.used() + (free + 1) * entry_size(8) <= leaf.node.capacity() {
+		leaf.insert_value(&mut bt, key.encode().as_slice(), schema::TID::new(key, 0));
+		key += 2;
+	}
+	leaf
+}
+
+/* how nodes got searched before, to benchmark against */
+#[cfg(test)]
+fn linear_rank(node: &SlottedNode, key: &[u8]) -> uint {
+	for i in range(0, node.len()) {
+		if node.key(i) >= key {
+			return i;
+		}
+	}
+	node.len()
+}
+
+#[cfg(test)]
+fn bench_manager() -> ConcurrentManager {
+	Arc::new(buffer::BufferManager::with_storage(16,
+		~storage::MemoryStorage::new() as ~storage::Storage:Send, replacement::LRU).unwrap())
+}
+
+#[bench]
+fn search_linear(bh: &mut BenchHarness) {
+	let leaf = full_leaf(bench_manager(), 0);
+	bh.iter(|| {
+		for i in range(0, leaf.len()) {
+			linear_rank(&leaf.node, leaf.node.key(i));
+		}
+	});
+}
+
+#[bench]
+fn search_binary(bh: &mut BenchHarness) {
+	let leaf = full_leaf(bench_manager(), 0);
+	bh.iter(|| {
+		for i in range(0, leaf.len()) {
+			leaf.node.bisect(leaf.node.key(i), false, bytewise);
+		}
+	});
+}
+
+#[bench]
+fn insert_and_lookup(bh: &mut BenchHarness) {
+	bh.iter(|| {
+		let mut bt = BTree::new(23, bench_manager());
+		for i in range(1_u64, 1000) {
+			bt.insert(tree_key(i), schema::TID::new(i, 0));
+		}
+		for i in range(1_u64, 1000) {
+			bt.lookup(&tree_key(i));
+		}
+	});
 }
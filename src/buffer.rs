@@ -1,44 +1,350 @@
-use collections::HashMap;
-use std::io::{Open, Read, Write, TempDir};
-use std::num::Zero;
+use collections::{HashMap, HashSet};
+use std::io::{TempDir, SeekSet, BufReader, BufWriter, MemWriter};
+use std::io::{IoError, OtherIoError};
 use std::comm::{Data, Empty, Disconnected};
-use sync::{Arc, RWLock};
+use std::sync::atomics::{AtomicUint, AtomicBool, SeqCst, INIT_ATOMIC_UINT};
+use sync::{Arc, Mutex, RWLock, RWLockReadGuard, RWLockWriteGuard};
 use sync::Future;
-use rand::task_rng;
-use rand::distributions::{IndependentSample, Range};
-use rand::distributions::range::SampleRange;
-use native::io::file;
-use native::io::file::FileDesc;
-use std::rt::rtio::RtioFileStream;
-
-/* Linux seems to use 4K segments, that's a good bet */
-pub static PAGE_SIZE: uint = 4 * 1024;
+use std::cmp::{min, max};
+use std::io::timer;
+use std::io::{fs, UserRWX};
+use std::uint;
+use std::mem;
+use std::task;
+use std::local_data;
+use replacement;
+use replacement::{Policy, ReplacementPolicy};
+use storage::{Storage, FileStorage, MappedSegment};
+use wal;
+use recovery;
+use faulty;
+
+/*
+ * Linux seems to use 4K segments, that's a good bet. Databases can be created
+ * with another page size, see Geometry.
+ */
+pub static DEFAULT_PAGE_SIZE: uint = 4 * 1024;
 /*
  * how much of the page_id should be reserved for pages?
  * the rest goes for segments. The bigger this is, the larger the segment files are
- * and the more pages they contain. (2^PAGE_BITS)*PAGE_SIZE gives you segment size.
- *
- * Adjust as required.
+ * and the more pages they contain. (2^page_bits)*page_size gives you segment size.
+ */
+pub static DEFAULT_PAGE_BITS: uint = 32;
+/*
+ * every page on disk starts with a header that the users of the buffer never
+ * see: a CRC32 (4 bytes), a marker that the page was written by us (4 bytes)
+ * and the LSN of the last logged change of the page (8 bytes)
+ */
+pub static PAGE_HEADER_SIZE: uint = 16;
+/* "NCPG", so random garbage is unlikely to look like a written page */
+static PAGE_MAGIC: u32 = 0x4E435047;
+/* "NCDB", starts the geometry the storage keeps for us */
+static META_MAGIC: u32 = 0x4E434442;
+static META_VERSION: u32 = 1;
+/*
+ * the buffer is split into this many independently locked partitions, so
+ * threads fixing different pages rarely wait for each other
  */
-pub static PAGE_BITS: uint = 32;
+static PARTITIONS: uint = 16;
+/* this many misses on consecutive pages count as a sequential scan */
+static SEQUENTIAL_AFTER: uint = 4;
+/* how far ahead of a sequential scan pages are read */
+static READ_AHEAD: uint = 16;
+
+/*
+ * How big pages are and how page ids are split into segment and page number.
+ * This is fixed when a database is created, the storage remembers it.
+ */
+#[deriving(Clone, Eq, Show)]
+pub struct Geometry {
+	pub page_size: uint,
+	pub page_bits: uint,
+}
+
+impl Geometry {
+	pub fn default() -> Geometry {
+		Geometry {page_size: DEFAULT_PAGE_SIZE, page_bits: DEFAULT_PAGE_BITS}
+	}
+
+	/*
+	 * returns None for geometries the rest of the code can't handle: pages
+	 * have to be a power of two between 512 bytes and 16 MiB (the slotted
+	 * pages store offsets in 24 bits) and page numbers need between 1 and
+	 * 48 bits
+	 */
+	pub fn new(page_size: uint, page_bits: uint) -> Option<Geometry> {
+		if page_size < 512 || page_size > 1 << 24 || page_size & (page_size - 1) != 0 {
+			return None;
+		}
+		if page_bits < 1 || page_bits > 48 {
+			return None;
+		}
+		Some(Geometry {page_size: page_size, page_bits: page_bits})
+	}
 
-pub type ConcurrentFrame = Arc<RWLock<BufferFrame>>;
+	/* what a page actually takes up in the segment */
+	pub fn disk_page_size(&self) -> uint {
+		self.page_size + PAGE_HEADER_SIZE
+	}
+
+	pub fn pages_per_segment(&self) -> u64 {
+		1 << self.page_bits
+	}
 
+	pub fn split_segment(&self, page_id: u64) -> (u64, u64) {
+		let high = page_id >> self.page_bits;
+		let low = page_id & (self.pages_per_segment() - 1);
+		(high, low)
+	}
+
+	pub fn join_segment(&self, segment: u64, page: u64) -> u64 {
+		assert!(page < self.pages_per_segment());
+		(segment << self.page_bits) | page
+	}
+
+	fn encode(&self) -> Vec<u8> {
+		let mut writer = MemWriter::new();
+		writer.write_le_u32(META_MAGIC).unwrap();
+		writer.write_le_u32(META_VERSION).unwrap();
+		writer.write_le_u64(self.page_size as u64).unwrap();
+		writer.write_le_u64(self.page_bits as u64).unwrap();
+		Vec::from_slice(writer.get_ref())
+	}
+
+	fn decode(data: &[u8]) -> Option<Geometry> {
+		let mut reader = BufReader::new(data);
+		match (reader.read_le_u32(), reader.read_le_u32()) {
+			(Ok(META_MAGIC), Ok(META_VERSION)) => (),
+			_ => return None,
+		}
+		match (reader.read_le_u64(), reader.read_le_u64()) {
+			(Ok(page_size), Ok(page_bits)) => Geometry::new(page_size as uint, page_bits as uint),
+			_ => None,
+		}
+	}
+}
+
+/* what the buffer and the guards share about a frame */
+struct SharedFrame {
+	/* how many guards for this frame exist, only 0 may be evicted */
+	fixed: AtomicUint,
+	/* the data points into a mapped file and must not be written */
+	mapped: bool,
+	/* how many open transactions changed the frame without logging it yet */
+	unlogged: AtomicUint,
+	/*
+	 * the open transaction that changed the page, 0 if there is none.
+	 * Others wait for it to end before they change the page.
+	 */
+	locked_by: Mutex<u64>,
+	/*
+	 * the log has an image of the whole page since it was last written,
+	 * redo can rebuild it from there if the next write gets torn
+	 */
+	imaged: AtomicBool,
+	/*
+	 * the page was thrown away with its segment, whoever still holds the
+	 * frame must not write it anymore
+	 */
+	discarded: AtomicBool,
+	frame: RWLock<BufferFrame>,
+}
+
+type ConcurrentFrame = Arc<SharedFrame>;
+
+/*
+ * The buffer manager can be shared between threads, e.g. in an Arc. The
+ * actual buffer is in the pool, which the manager shares with its background
+ * threads.
+ */
 pub struct BufferManager {
+	pool: Arc<Pool>,
+	/* reads pages ahead if it was started */
+	prefetcher: Mutex<Option<Worker<Option<(u64, uint)>>>>,
+	/* writes dirty pages in the background if it was started */
+	writer: Mutex<Option<Worker<()>>>,
+}
+
+/* a background thread and how to talk to it */
+struct Worker<T> {
+	tx: Sender<T>,
+	done: Future<()>,
+}
+
+/*
+ * Every page belongs to one partition which has its own lock, frames have a
+ * latch of their own. Partitions are only locked for bookkeeping, never
+ * while the storage is used. A partition may be locked while holding a
+ * latch, but not the other way round.
+ */
+struct Pool {
+	/* tells the transactions on this pool apart from those on others */
+	id: uint,
+	geometry: Geometry,
+	partitions: Vec<Mutex<Partition>>,
+	/* how many frames there are in total, the partitions share them */
 	size: uint,
-	entries: HashMap<u64, BufferEntry>,
+	/* frames that hold a page or are reserved for one that is being loaded */
+	used: AtomicUint,
+	storage: Mutex<~Storage:Send>,
+	stats: Counters,
+	/* what the storage reported for opens_saved on the last reset */
+	opens_saved_base: AtomicUint,
+	/* segments that were written to since they were last synced */
+	unsynced: Mutex<HashSet<u64>>,
+	/* bumped after every write, so read-ahead notices it might be stale */
+	write_epoch: AtomicUint,
+	sequential: Mutex<Sequential>,
+	/* no segment below this is handed out by create_segment anymore */
+	next_segment: Mutex<u64>,
+	/* only in read only mode */
+	mapped: Option<Mapped>,
+	/* pages are only written once the log has their changes */
+	log: Option<wal::Log>,
+}
+
+/* the transaction a task has open on a pool */
+struct Txn {
+	id: u64,
+	/* the LSN of the transaction's last record */
+	last_lsn: u64,
+	/* how much more often begin was called than commit */
+	depth: uint,
+	/* a nested transaction was aborted, so this one can't commit */
+	aborted: bool,
+	/* the frames changed so far, with their data from before the first change */
+	pages: Vec<(ConcurrentFrame, Vec<u8>)>,
+}
+
+/* the transactions the current task has open, by the id of their pool */
+local_data_key!(open_txns: HashMap<uint, Txn>)
+
+/* hands out the ids of the pools */
+static mut NEXT_POOL_ID: AtomicUint = INIT_ATOMIC_UINT;
+
+/* the segment files of a read only buffer, mapped when first used */
+struct Mapped {
 	path: Path,
+	segments: Mutex<HashMap<u64, Option<Arc<MappedSegment>>>>,
 }
 
-struct BufferEntry {
-	frame: ConcurrentFrame,
-	written: Cleanliness,
+/* keeps track of whether the pages are fixed one after another */
+struct Sequential {
+	last: u64,
+	run: uint,
+	/* the last page that was already requested to be read ahead */
+	ahead_until: u64,
 }
 
-#[deriving(Eq)]
-enum Status {
-	Free,
-	Fixed(uint),
+/*
+ * a part of the buffer with its own replacement policy. The frames come from
+ * the pool, so a partition with many hot pages can hold more than its share.
+ */
+struct Partition {
+	entries: HashMap<u64, ConcurrentFrame>,
+	/*
+	 * bumped whenever a page leaves the partition. Only such a page can
+	 * have been written since, so a page read while this stayed the same
+	 * is up to date.
+	 */
+	generation: uint,
+	policy: ~ReplacementPolicy:Send,
+}
+
+/*
+ * counters on how the buffer behaved since it was created or since the
+ * last call to reset_stats
+ */
+#[deriving(Clone, Eq, Show)]
+pub struct Stats {
+	/* fix_page found the page in the buffer */
+	pub hits: u64,
+	/* fix_page had to load the page */
+	pub misses: u64,
+	/* pages that were thrown out of the buffer to make room */
+	pub evictions: u64,
+	/* dirty pages that were written back */
+	pub writebacks: u64,
+	/* fix_page calls that failed because all frames were fixed */
+	pub failed_fixes: u64,
+	pub bytes_read: u64,
+	pub bytes_written: u64,
+	/* segment opens that were avoided because the file was still open */
+	pub opens_saved: u64,
+	/* pages that were loaded by read-ahead before anyone asked for them */
+	pub prefetched: u64,
+	/* writebacks done by the background writer, these count as writebacks too */
+	pub background_writebacks: u64,
+}
+
+impl Stats {
+	pub fn new() -> Stats {
+		Stats {hits: 0, misses: 0, evictions: 0, writebacks: 0,
+			failed_fixes: 0, bytes_read: 0, bytes_written: 0, opens_saved: 0,
+			prefetched: 0, background_writebacks: 0}
+	}
+
+	/* fraction of fix_page calls that could be served from the buffer */
+	pub fn hit_rate(&self) -> f64 {
+		let total = self.hits + self.misses;
+		if total == 0 {
+			return 0.0;
+		}
+		self.hits as f64 / total as f64
+	}
+}
+
+/* the live counters behind Stats, can be bumped without taking a lock */
+struct Counters {
+	hits: AtomicUint,
+	misses: AtomicUint,
+	evictions: AtomicUint,
+	writebacks: AtomicUint,
+	failed_fixes: AtomicUint,
+	bytes_read: AtomicUint,
+	bytes_written: AtomicUint,
+	prefetched: AtomicUint,
+	background_writebacks: AtomicUint,
+}
+
+impl Counters {
+	fn new() -> Counters {
+		Counters {
+			hits: AtomicUint::new(0),
+			misses: AtomicUint::new(0),
+			evictions: AtomicUint::new(0),
+			writebacks: AtomicUint::new(0),
+			failed_fixes: AtomicUint::new(0),
+			bytes_read: AtomicUint::new(0),
+			bytes_written: AtomicUint::new(0),
+			prefetched: AtomicUint::new(0),
+			background_writebacks: AtomicUint::new(0),
+		}
+	}
+
+	fn snapshot(&self) -> Stats {
+		Stats {
+			hits: self.hits.load(SeqCst) as u64,
+			misses: self.misses.load(SeqCst) as u64,
+			evictions: self.evictions.load(SeqCst) as u64,
+			writebacks: self.writebacks.load(SeqCst) as u64,
+			failed_fixes: self.failed_fixes.load(SeqCst) as u64,
+			bytes_read: self.bytes_read.load(SeqCst) as u64,
+			bytes_written: self.bytes_written.load(SeqCst) as u64,
+			opens_saved: 0,
+			prefetched: self.prefetched.load(SeqCst) as u64,
+			background_writebacks: self.background_writebacks.load(SeqCst) as u64,
+		}
+	}
+
+	fn reset(&self) {
+		for counter in [&self.hits, &self.misses, &self.evictions,
+				&self.writebacks, &self.failed_fixes, &self.bytes_read,
+				&self.bytes_written, &self.prefetched,
+				&self.background_writebacks].iter() {
+			counter.store(0, SeqCst);
+		}
+	}
 }
 
 #[deriving(Eq)]
@@ -49,229 +355,1483 @@ enum Cleanliness {
 
 pub struct BufferFrame {
 	page_id: u64,
-	data: Vec<u8>,
-	fixed: Status,
+	data: FrameData,
+	written: Cleanliness,
+	/* the last logged change, 0 if there is none */
+	lsn: u64,
+}
+
+enum FrameData {
+	Owned(Vec<u8>),
+	/* read only mode: where the page data starts in the mapping and how long it is */
+	Mapped(Arc<MappedSegment>, uint, uint),
+}
+
+/*
+ * A fixed page. The page stays in the buffer for as long as the guard lives
+ * and is unfixed when the guard goes out of scope. Borrowing the page data
+ * mutably marks the page as dirty.
+ */
+pub struct PageGuard {
+	frame: ConcurrentFrame,
+	/* the pool, if it logs changes */
+	owner: Option<uint>,
+}
+
+/*
+ * Everything that can go wrong in the buffer manager
+ */
+#[deriving(Show)]
+pub enum BufferError {
+	/* reading, writing or syncing a segment failed */
+	IoFailed(IoError),
+	/* all frames are fixed, so no page could be evicted to make room */
+	BufferFull,
+	/* the page on disk does not match its checksum, e.g. after a torn write */
+	Corrupted(u64),
+	/* the database was created with another geometry, this is the right one */
+	WrongGeometry(Geometry),
+	/* the geometry stored with the database can't be read */
+	BadMeta,
+	/*
+	 * the page is fixed or an open transaction changed it, so its segment
+	 * can't be dropped or truncated
+	 */
+	PageFixed(u64),
+	/* every page of the segment is allocated */
+	SegmentFull(u64),
+	/* the page was freed, but it is not in use or it is a free space map page */
+	NotAllocated(u64),
+	/* the page is neither blank nor in the format it should have */
+	BadFormat(u64),
+	/* the buffer was opened read only, nothing can be written */
+	ReadOnly,
+	/* the log is not one of ours */
+	BadLog,
+	/* a nested transaction was aborted, so everything was rolled back */
+	Aborted,
+	/* commit or abort without an open transaction */
+	NoTransaction,
+}
+
+pub type BufferResult<T> = Result<T, BufferError>;
+
+impl BufferError {
+	/* for code that has to report errors via the std::io traits */
+	pub fn to_io_error(self) -> IoError {
+		match self {
+			IoFailed(e) => e,
+			other => IoError {
+				kind: OtherIoError,
+				desc: "buffer manager failure",
+				detail: Some(format!("{}", other)),
+			},
+		}
+	}
 }
 
 /* Destructor trait implementation */
 impl Drop for BufferManager {
 	fn drop(&mut self) {
-		for (page, entry) in self.entries.iter() {
-			info!("Drop k == {}, dirty? {:?}", page, entry.written);
-			match entry.written {
-				Clean => (),
-				Dirty => {
-					let frame = entry.frame.read();
-					self.write_page(frame.page_id, frame.get_data());
-				},
-			}
+		self.stop_writer();
+		self.stop_prefetcher();
+		match self.flush_all() {
+			Ok(()) => (),
+			// nobody left to report this to
+			Err(e) => error!("Flushing buffer on drop failed: {}", e),
 		}
 	}
 }
 
 impl BufferManager {
-	pub fn new(size: uint, path: Path) -> BufferManager {
-		let h = HashMap::with_capacity(size);
-		BufferManager {size: size, entries: h, path: path}
+	/* keeps the segments as files in the directory `path` */
+	pub fn new(size: uint, path: Path, policy: Policy) -> BufferResult<BufferManager> {
+		BufferManager::with_storage(size, ~FileStorage::new(path) as ~Storage:Send, policy)
+	}
+
+	/*
+	 * opens the database in the storage with the geometry it was created
+	 * with. Empty storages get the default geometry.
+	 */
+	pub fn with_storage(size: uint, storage: ~Storage:Send, policy: Policy) -> BufferResult<BufferManager> {
+		BufferManager::open(size, storage, policy, None)
+	}
+
+	/*
+	 * like new, but with a log in the subdirectory "log". Whatever a crash
+	 * left behind is recovered first.
+	 */
+	pub fn new_logged(size: uint, path: Path, policy: Policy) -> BufferResult<BufferManager> {
+		let log_path = path.join("log");
+		if !log_path.exists() {
+			try!(fs::mkdir(&log_path, UserRWX).map_err(|e| IoFailed(e)));
+		}
+		BufferManager::logged(size, ~FileStorage::new(path) as ~Storage:Send,
+			~FileStorage::new(log_path) as ~Storage:Send, policy)
+	}
+
+	/*
+	 * like with_storage, but the changes transactions make are logged to
+	 * the log in `log`, see begin and commit. Opening runs recovery, so
+	 * the pages reflect what the committed transactions did and nothing
+	 * else.
+	 */
+	pub fn logged(size: uint, storage: ~Storage:Send, log: ~Storage:Send, policy: Policy) -> BufferResult<BufferManager> {
+		BufferManager::with_log(size, storage, try!(wal::Log::open(log)), policy)
+	}
+
+	/*
+	 * like logged, but with a log that was opened already, e.g. one in a
+	 * segment of the data's storage
+	 */
+	pub fn with_log(size: uint, storage: ~Storage:Send, log: wal::Log, policy: Policy) -> BufferResult<BufferManager> {
+		let manager = try!(BufferManager::open(size, storage, policy, Some(log)));
+		try!(recovery::recover(&manager));
+		Ok(manager)
 	}
 
-	fn open_or_create(&self, page_id: u64) -> FileDesc {
-		let (segment, _) = split_segment(page_id);
-		let file_path = self.path.join(segment.to_str());
-		match file::open(&file_path.to_c_str(), Open, Read) {
-			Ok(f) => return f,
-			Err(_) => self.create(page_id),
+	fn open(size: uint, storage: ~Storage:Send, policy: Policy, log: Option<wal::Log>) -> BufferResult<BufferManager> {
+		let mut storage = storage;
+		let geometry = match try!(stored_geometry(&mut storage)) {
+			Some(geometry) => geometry,
+			None => {
+				let geometry = Geometry::default();
+				try!(storage.write_meta(geometry.encode().as_slice()).map_err(|e| IoFailed(e)));
+				geometry
+			},
 		};
-		match file::open(&file_path.to_c_str(), Open, Read) {
-			Ok(f) => f,
-			Err(e) => fail!("Failed reopening written file: {}", e),
+		Ok(BufferManager::start(size, storage, policy, geometry, None, log))
+	}
+
+	/*
+	 * opens the database in the directory read only. The segment files are
+	 * mapped into memory and fixed pages point right into the mapping
+	 * instead of being copied. Writing to such a page fails, anything else
+	 * that would write returns ReadOnly.
+	 */
+	pub fn read_only(size: uint, path: Path, policy: Policy) -> BufferResult<BufferManager> {
+		let mut storage = ~FileStorage::new(path.clone()) as ~Storage:Send;
+		let geometry = match try!(stored_geometry(&mut storage)) {
+			Some(geometry) => geometry,
+			None => Geometry::default(),
+		};
+		Ok(BufferManager::start(size, storage, policy, geometry, Some(path), None))
+	}
+
+	/*
+	 * creates a database with the given geometry. Opening an existing
+	 * database works too, as long as it has the same geometry.
+	 */
+	pub fn create(size: uint, storage: ~Storage:Send, policy: Policy, geometry: Geometry) -> BufferResult<BufferManager> {
+		let mut storage = storage;
+		match try!(stored_geometry(&mut storage)) {
+			Some(stored) => if stored != geometry {
+				return Err(WrongGeometry(stored));
+			},
+			None => try!(storage.write_meta(geometry.encode().as_slice()).map_err(|e| IoFailed(e))),
 		}
+		Ok(BufferManager::start(size, storage, policy, geometry, None, None))
+	}
+
+	fn start(size: uint, storage: ~Storage:Send, policy: Policy, geometry: Geometry,
+			mapped: Option<Path>, log: Option<wal::Log>) -> BufferManager {
+		info!("Starting buffer with {}", geometry);
+		BufferManager {pool: Arc::new(Pool::new(size, storage, policy, geometry, mapped, log)),
+			prefetcher: Mutex::new(None), writer: Mutex::new(None)}
+	}
+
+	pub fn geometry(&self) -> Geometry {
+		self.pool.geometry.clone()
 	}
 
-	fn create(&self, page_id: u64) {
-		let (segment, offset) = split_segment(page_id);
-		let path = self.path.join(segment.to_str());
+	/* how many bytes a fixed page has */
+	pub fn page_size(&self) -> uint {
+		self.pool.geometry.page_size
+	}
 
-		match file::open(&path.to_c_str(), Open, Write) {
-			Ok(mut f) => {
-				match f.pwrite([0_u8, ..PAGE_SIZE], offset * PAGE_SIZE as u64) {
-					Ok(_) => (),
-					//Err(e) => fail!("Failed pwriting to file {}: {}", &path.to_c_str().as_str(), e),
-					// in this case, err means everything is fine, since there is a bug in Rust' libnative
-					Err(_) => (),
+	/* the page id of page number `page` in `segment` */
+	pub fn join_segment(&self, segment: u64, page: u64) -> u64 {
+		self.pool.geometry.join_segment(segment, page)
+	}
+
+	pub fn split_segment(&self, page_id: u64) -> (u64, u64) {
+		self.pool.geometry.split_segment(page_id)
+	}
+
+	/* returns a snapshot of the counters */
+	pub fn stats(&self) -> Stats {
+		self.pool.stats()
+	}
+
+	pub fn reset_stats(&self) {
+		self.pool.reset_stats();
+	}
+
+	/*
+	 * fixes the page in the buffer, it gets unfixed when the returned guard
+	 * is dropped. Can be called from many threads at once.
+	 */
+	pub fn fix_page(&self, page_id: u64) -> BufferResult<PageGuard> {
+		let guard = try!(self.pool.fix_page(page_id));
+		match self.pool.read_ahead(page_id) {
+			Some((first, count)) => self.prefetch(first, count),
+			None => (),
+		}
+		Ok(guard)
+	}
+
+	/* for recovery, see Pool::fix_torn_page */
+	pub fn fix_torn_page(&self, page_id: u64) -> BufferResult<PageGuard> {
+		self.pool.fix_torn_page(page_id)
+	}
+
+	/*
+	 * a hint that the `count` pages starting at `first` will be needed
+	 * soon. They are read in one go, by the prefetch thread if it runs or
+	 * right away otherwise. Errors are ignored, a later fix_page will
+	 * report them.
+	 */
+	pub fn prefetch(&self, first: u64, count: uint) {
+		match *self.prefetcher.lock() {
+			Some(ref worker) => {
+				worker.tx.send(Some((first, count)));
+				return;
+			},
+			None => (),
+		}
+		match self.pool.prefetch(first, count) {
+			Ok(()) => (),
+			Err(e) => info!("Prefetching {} pages from {} failed: {}", count, first, e),
+		}
+	}
+
+	/*
+	 * starts a thread that does the reading for prefetch, so the caller
+	 * doesn't have to wait for it
+	 */
+	pub fn start_prefetcher(&self) {
+		let mut prefetcher = self.prefetcher.lock();
+		if prefetcher.is_some() {
+			return;
+		}
+		let (tx, rx) = channel();
+		let pool = self.pool.clone();
+		let done = Future::spawn(proc() {
+			// None asks us to stop
+			loop {
+				match rx.recv_opt() {
+					Some(Some((first, count))) => match pool.prefetch(first, count) {
+						Ok(()) => (),
+						Err(e) => info!("Prefetching {} pages from {} failed: {}",
+							count, first, e),
+					},
+					_ => break,
+				}
+			}
+		});
+		*prefetcher = Some(Worker {tx: tx, done: done});
+	}
+
+	/* stops the prefetch thread after it handled what was already requested */
+	pub fn stop_prefetcher(&self) {
+		let worker = self.prefetcher.lock().take();
+		match worker {
+			Some(mut worker) => {
+				worker.tx.send(None);
+				worker.done.get();
+			},
+			None => (),
+		}
+	}
+
+	/*
+	 * starts a thread that writes back up to `batch` dirty pages every
+	 * `interval_ms` milliseconds, so evictions rarely have to wait for a
+	 * write. Fixed pages are left alone.
+	 */
+	pub fn start_writer(&self, interval_ms: u64, batch: uint) {
+		let mut writer = self.writer.lock();
+		if writer.is_some() {
+			return;
+		}
+		let (tx, rx) = channel();
+		let pool = self.pool.clone();
+		let done = Future::spawn(proc() {
+			loop {
+				timer::sleep(interval_ms);
+				// anything arriving means stop, but write everything before
+				let stop = match rx.try_recv() {
+					Empty => false,
+					Data(()) | Disconnected => true,
 				};
+				let batch = if stop {uint::MAX} else {batch};
+				match pool.write_dirty(batch) {
+					Ok(n) => debug!("Background writer wrote {} pages", n),
+					Err(e) => error!("Background writer failed: {}", e),
+				}
+				if stop {
+					break;
+				}
+			}
+		});
+		*writer = Some(Worker {tx: tx, done: done});
+	}
+
+	/* stops the background writer once it wrote all unfixed dirty pages */
+	pub fn stop_writer(&self) {
+		let worker = self.writer.lock().take();
+		match worker {
+			Some(mut worker) => {
+				worker.tx.send(());
+				worker.done.get();
 			},
-			Err(e) => fail!("Failed opening file for write: {}", e),
+			None => (),
+		}
+	}
+
+	/*
+	 * writes the page back if it is dirty and makes sure it reached the disk
+	 */
+	pub fn flush_page(&self, page_id: u64) -> BufferResult<()> {
+		self.pool.flush_page(page_id)
+	}
+
+	/* writes back all dirty pages of a segment and syncs the segment file */
+	pub fn flush_segment(&self, segment: u64) -> BufferResult<()> {
+		self.pool.flush_segment(segment)
+	}
+
+	/* checkpoint: writes back every dirty page and syncs all touched segments */
+	pub fn flush_all(&self) -> BufferResult<()> {
+		self.pool.flush_all()
+	}
+
+	/* the segments that exist in the storage */
+	pub fn segments(&self) -> BufferResult<Vec<u64>> {
+		self.pool.storage.lock().segments().map_err(|e| IoFailed(e))
+	}
+
+	/*
+	 * returns the id of a new, empty segment. It is higher than any segment
+	 * the storage or the buffer knows about.
+	 */
+	pub fn create_segment(&self) -> BufferResult<u64> {
+		self.pool.create_segment()
+	}
+
+	/*
+	 * throws away the buffered pages of the segment, dirty or not, and
+	 * deletes it. Fails with PageFixed if any of its pages is fixed.
+	 */
+	pub fn drop_segment(&self, segment: u64) -> BufferResult<()> {
+		self.pool.drop_segment(segment)
+	}
+
+	/*
+	 * keeps only the first `pages` pages of the segment, the buffered pages
+	 * behind that are thrown away. Fails with PageFixed like drop_segment.
+	 */
+	pub fn truncate_segment(&self, segment: u64, pages: u64) -> BufferResult<()> {
+		self.pool.truncate_segment(segment, pages)
+	}
+
+	/*
+	 * starts a transaction of the current task. The changes the task makes
+	 * through PageGuard::write or mark_dirty until commit are logged as one
+	 * transaction, changes outside of a transaction are not logged at all.
+	 * Transactions nest, only the outermost commit counts. Does nothing if
+	 * the buffer has no log.
+	 *
+	 * Changed pages can't be written before commit, so they stay in the
+	 * buffer. A transaction can change at most as many pages as there are
+	 * frames, fewer if other pages are fixed. Beyond that fix_page fails with
+	 * BufferFull and the transaction has to be aborted.
+	 *
+	 * A page a transaction changed is locked until it ends, PageGuard::write
+	 * of another transaction waits for that. So only one open transaction
+	 * changes a page at a time and commit and abort only log or undo its
+	 * own changes. Reading is not locked, it sees changes that might still
+	 * be rolled back, and neither are changes outside of transactions. Two
+	 * transactions that wait for pages the other one changed wait forever,
+	 * there is no deadlock detection. Neither may a task hold a latch while
+	 * it calls write, the transaction it waits for might need it to end.
+	 */
+	pub fn begin(&self) {
+		self.pool.begin()
+	}
+
+	/*
+	 * logs what the transaction changed and returns once its commit record
+	 * is durable. Fails with Aborted if a nested transaction was aborted and
+	 * with NoTransaction if none is open.
+	 */
+	pub fn commit(&self) -> BufferResult<()> {
+		self.pool.commit()
+	}
+
+	/*
+	 * puts the pages the transaction changed back the way they were. Inside
+	 * of a nested transaction that happens once the outermost one ends,
+	 * whether it commits or aborts. If a task ends without finishing its
+	 * transaction, it gets rolled back too. Fails with NoTransaction if none
+	 * is open.
+	 */
+	pub fn abort(&self) -> BufferResult<()> {
+		self.pool.abort()
+	}
+
+	pub fn log<'a>(&'a self) -> Option<&'a wal::Log> {
+		self.pool.log.as_ref()
+	}
+}
+
+impl Pool {
+	fn new(size: uint, storage: ~Storage:Send, policy: Policy, geometry: Geometry,
+			mapped: Option<Path>, log: Option<wal::Log>) -> Pool {
+		// no more partitions than frames, so each one can expect some
+		let count = if size < PARTITIONS {size} else {PARTITIONS};
+		let count = if count == 0 {1} else {count};
+		// the policies only use this for tuning, so it's fine to round up
+		let share = (size + count - 1) / count;
+		let partitions = Vec::from_fn(count, |_| {
+			Mutex::new(Partition {
+				entries: HashMap::with_capacity(share),
+				generation: 0,
+				policy: policy.instantiate(share),
+			})
+		});
+		// pools are never around long enough for this to wrap
+		let id = unsafe { NEXT_POOL_ID.fetch_add(1, SeqCst) };
+		Pool {id: id, geometry: geometry, partitions: partitions, size: size, used: AtomicUint::new(0),
+			storage: Mutex::new(storage),
+			stats: Counters::new(), opens_saved_base: AtomicUint::new(0),
+			unsynced: Mutex::new(HashSet::new()), write_epoch: AtomicUint::new(0),
+			sequential: Mutex::new(Sequential {last: 0, run: 0, ahead_until: 0}),
+			// segment 0 belongs to the schema
+			next_segment: Mutex::new(1),
+			mapped: mapped.map(|path| Mapped {path: path, segments: Mutex::new(HashMap::new())}),
+			log: log}
+	}
+
+	fn id(&self) -> uint {
+		self.id
+	}
+
+	/* how many frames there are in total */
+	fn size(&self) -> uint {
+		self.size
+	}
+
+	fn stats(&self) -> Stats {
+		let mut stats = self.stats.snapshot();
+		// the storage counts these itself, we only remember where we started
+		let base = self.opens_saved_base.load(SeqCst) as u64;
+		stats.opens_saved = self.storage.lock().opens_saved() - base;
+		stats
+	}
+
+	fn reset_stats(&self) {
+		self.stats.reset();
+		self.opens_saved_base.store(self.storage.lock().opens_saved() as uint, SeqCst);
+	}
+
+	/* the partition a page belongs to */
+	fn partition<'a>(&'a self, page_id: u64) -> &'a Mutex<Partition> {
+		self.partitions.get(self.partition_index(page_id))
+	}
+
+	fn partition_index(&self, page_id: u64) -> uint {
+		// Fibonacci hashing, so consecutive pages end up in different partitions
+		let hash = (page_id * 0x9E3779B97F4A7C15) >> 32;
+		(hash % self.partitions.len() as u64) as uint
+	}
+
+	/*
+	 * takes a frame for a page that is about to be loaded, evicting a page
+	 * of any partition if there is no free one. Fails with BufferFull if
+	 * everything is fixed. The caller must not hold a partition lock, as
+	 * they are taken one after another here.
+	 */
+	fn reserve_frame(&self, page_id: u64) -> BufferResult<()> {
+		// the page's own partition goes first, so hot partitions keep their pages
+		let home = self.partition_index(page_id);
+		loop {
+			let used = self.used.load(SeqCst);
+			if used < self.size {
+				if self.used.compare_and_swap(used, used + 1, SeqCst) == used {
+					return Ok(());
+				}
+				continue;
+			}
+			let mut evicted = false;
+			for i in range(0, self.partitions.len()) {
+				let partition = self.partitions.get((home + i) % self.partitions.len());
+				if try!(self.evict_page(partition)) {
+					evicted = true;
+					break;
+				}
+			}
+			// somebody else might take the frame first, then we just evict again
+			if !evicted {
+				return Err(BufferFull);
+			}
+		}
+	}
+
+	/* gives back a frame reserve_frame handed out that wasn't used after all */
+	fn release_frame(&self) {
+		self.used.fetch_sub(1, SeqCst);
+	}
+
+	/* reads the page into a new frame, no partition may be locked for this */
+	fn read_frame(&self, page_id: u64) -> BufferResult<BufferFrame> {
+		match self.mapped {
+			Some(ref mapped) => return self.map_page(mapped, page_id),
+			None => (),
+		}
+		let (segment, offset) = self.geometry.split_segment(page_id);
+
+		let mut buf = Vec::from_elem(self.geometry.disk_page_size(), 0_u8);
+		let n = try!(self.storage.lock().read_page(segment, offset, buf.as_mut_slice()).map_err(|e| IoFailed(e)));
+		self.stats.bytes_read.fetch_add(n, SeqCst);
+		try!(verify_page(page_id, buf.as_slice()));
+		Ok(page_frame(page_id, buf.as_slice()))
+	}
+
+	/*
+	 * a frame pointing into the mapped segment file. Pages behind the end
+	 * of the file were never written and get a blank frame of their own.
+	 */
+	fn map_page(&self, mapped: &Mapped, page_id: u64) -> BufferResult<BufferFrame> {
+		let (segment, offset) = self.geometry.split_segment(page_id);
+		let mapping = {
+			let mut segments = mapped.segments.lock();
+			if !segments.contains_key(&segment) {
+				let mapping = try!(MappedSegment::map(&mapped.path, segment).map_err(|e| IoFailed(e)));
+				segments.insert(segment, mapping.map(|m| Arc::new(m)));
+			}
+			segments.get(&segment).clone()
 		};
+		let disk_page_size = self.geometry.disk_page_size();
+		let start = offset as uint * disk_page_size;
+		let mapping = match mapping {
+			Some(ref mapping) if start < mapping.len() => mapping.clone(),
+			_ => {
+				let data = Vec::from_elem(self.geometry.page_size, 0_u8);
+				return Ok(BufferFrame {page_id: page_id, data: Owned(data), written: Clean, lsn: 0});
+			},
+		};
+		if start + disk_page_size > mapping.len() {
+			error!("Page {} is cut off at the end of the file", page_id);
+			return Err(Corrupted(page_id));
+		}
+		try!(verify_page(page_id, mapping.slice(start, disk_page_size)));
+		let lsn = page_lsn(mapping.slice(start, disk_page_size));
+		Ok(BufferFrame {page_id: page_id, written: Clean, lsn: lsn,
+			data: Mapped(mapping, start + PAGE_HEADER_SIZE, self.geometry.page_size)})
 	}
 
 	/*
-	 * returns true if page could be loaded
+	 * frees the frame of a page of the partition, returns false if all of
+	 * its pages are fixed. A dirty victim is written with the partition
+	 * unlocked, so fixing other pages doesn't have to wait for the disk.
 	 */
-	fn load_page(&mut self, page_id: u64) -> bool {
-		if self.entries.len() == self.size {
-			if !self.evict_page() {
-				return false;
+	fn evict_page(&self, mutex: &Mutex<Partition>) -> BufferResult<bool> {
+		loop {
+			let (key, shared) = {
+				let mut partition = mutex.lock();
+				let victim = {
+					let entries = &partition.entries;
+					// only frames that are not fixed by anyone can be evicted
+					partition.policy.victim(|page_id| {
+						match entries.find(&page_id) {
+							Some(frame) => frame.fixed.load(SeqCst) == 0 &&
+								frame.unlogged.load(SeqCst) == 0,
+							None => false,
+						}
+					})
+				};
+				match victim {
+					None => return Ok(false),
+					Some(key) => (key, partition.entries.get(&key).clone()),
+				}
+			};
+			// with the latch held nobody can change the page after it was
+			// written, so it's still clean when we drop it. Write it before
+			// dropping it, so a failed write keeps the page.
+			let mut frame = shared.frame.write();
+			try!(self.write_frame(&*shared, &mut *frame));
+			let mut partition = mutex.lock();
+			let same = match partition.entries.find(&key) {
+				Some(current) => &**current as *SharedFrame == &*shared as *SharedFrame,
+				None => false,
+			};
+			// New guards are only handed out with the partition locked and
+			// unlogged changes only happen through guards, so if it's still
+			// unfixed it stays that way. Otherwise look for another one.
+			if same && frame.written == Clean && shared.fixed.load(SeqCst) == 0 &&
+					shared.unlogged.load(SeqCst) == 0 {
+				partition.entries.remove(&key);
+				partition.policy.removed(key);
+				partition.generation += 1;
+				self.used.fetch_sub(1, SeqCst);
+				info!("Evicted page: {}", key);
+				self.stats.evictions.fetch_add(1, SeqCst);
+				return Ok(true);
 			}
 		}
-		let (_, offset) = split_segment(page_id);
+	}
 
-		let mut file_handle = self.open_or_create(page_id);
-		let mut buf = [0_u8, ..PAGE_SIZE];
-		let content = match file_handle.pread(buf, offset * PAGE_SIZE as u64) {
-			Ok(_) => {
-				Vec::from_slice(buf)
-				/*
-				if n == PAGE_SIZE as int {
-					Vec::from_slice(buf)
-				} else {
-					fail!("pread failed: wanted {}, got {}",
-						PAGE_SIZE, n)
-				}*/
+	fn fix_page(&self, page_id: u64) -> BufferResult<PageGuard> {
+		self.fix(page_id, false)
+	}
+
+	/*
+	 * for recovery: fixes the page like fix_page, but if it fails its
+	 * checksum it gets a blank frame with LSN 0 instead. That is where a
+	 * torn write leaves a page, and redo rebuilds it from the image the log
+	 * has of it since the write before.
+	 */
+	fn fix_torn_page(&self, page_id: u64) -> BufferResult<PageGuard> {
+		self.fix(page_id, true)
+	}
+
+	fn fix(&self, page_id: u64, torn: bool) -> BufferResult<PageGuard> {
+		let mutex = self.partition(page_id);
+		{
+			let mut partition = mutex.lock();
+			if partition.entries.contains_key(&page_id) {
+				partition.policy.accessed(page_id);
+				self.stats.hits.fetch_add(1, SeqCst);
+				return Ok(self.guard(partition.entries.get(&page_id)));
+			}
+		}
+		self.stats.misses.fetch_add(1, SeqCst);
+		match self.reserve_frame(page_id) {
+			Ok(()) => (),
+			Err(BufferFull) => {
+				self.stats.failed_fixes.fetch_add(1, SeqCst);
+				return Err(BufferFull);
 			},
-			Err(e) => fail!("Couldn't read from page: {}", e),
+			Err(e) => return Err(e),
+		}
+		loop {
+			let generation = {
+				let mut partition = mutex.lock();
+				if partition.entries.contains_key(&page_id) {
+					// somebody else loaded it while we were busy
+					self.release_frame();
+					partition.policy.accessed(page_id);
+					return Ok(self.guard(partition.entries.get(&page_id)));
+				}
+				partition.generation
+			};
+			// the partition stays unlocked while reading, so the other pages
+			// in it can be fixed meanwhile
+			let frame = match self.read_frame(page_id) {
+				Ok(frame) => frame,
+				Err(Corrupted(_)) if torn => {
+					info!("Page {} is torn, it gets rebuilt from the log", page_id);
+					BufferFrame {page_id: page_id, written: Clean, lsn: 0,
+						data: Owned(Vec::from_elem(self.geometry.page_size, 0_u8))}
+				},
+				Err(e) => {
+					self.release_frame();
+					return Err(e);
+				},
+			};
+			let mut partition = mutex.lock();
+			// if a page left the partition, it might have been this one,
+			// written back after we read it. Then read it again.
+			if !partition.entries.contains_key(&page_id) && partition.generation == generation {
+				admit_frame(partition.deref_mut(), frame);
+				return Ok(self.guard(partition.entries.get(&page_id)));
+			}
+		}
+	}
+
+	/* fixes a buffered frame, its partition has to be locked */
+	fn guard(&self, frame: &ConcurrentFrame) -> PageGuard {
+		frame.fixed.fetch_add(1, SeqCst);
+		// Arcs can be cloned and they will all point to the same frame
+		let owner = if self.log.is_some() {Some(self.id())} else {None};
+		PageGuard {frame: frame.clone(), owner: owner}
+	}
+
+	/*
+	 * watches for fixes of consecutive pages, returns which pages should be
+	 * read ahead once a scan is detected
+	 */
+	fn read_ahead(&self, page_id: u64) -> Option<(u64, uint)> {
+		let mut sequential = self.sequential.lock();
+		if page_id == sequential.last + 1 {
+			sequential.run += 1;
+		} else if page_id != sequential.last {
+			sequential.run = 1;
+			sequential.ahead_until = page_id;
+		}
+		sequential.last = page_id;
+		// only start reading when the scan is half way through what was read
+		if sequential.run < SEQUENTIAL_AFTER ||
+				page_id + (READ_AHEAD / 2) as u64 <= sequential.ahead_until {
+			return None;
+		}
+		let first = if sequential.ahead_until > page_id {sequential.ahead_until + 1} else {page_id + 1};
+		let until = page_id + READ_AHEAD as u64;
+		sequential.ahead_until = until;
+		Some((first, (until - first + 1) as uint))
+	}
+
+	/*
+	 * reads the pages in one go and puts those that are not buffered yet
+	 * into the buffer. Pages that don't exist on disk are not loaded and
+	 * nothing that is fixed gets evicted for read-ahead.
+	 */
+	fn prefetch(&self, first: u64, count: uint) -> BufferResult<()> {
+		let (segment, offset) = self.geometry.split_segment(first);
+		// don't let read-ahead take over the buffer or run into the next segment
+		let count = min(count, min(self.size() / 4 + 1,
+			(self.geometry.pages_per_segment() - offset) as uint));
+		let disk_page_size = self.geometry.disk_page_size();
+		// a mapping needs no reading ahead
+		if count == 0 || self.mapped.is_some() {
+			return Ok(());
+		}
+
+		// if anything got written while we read, the data might be stale
+		let epoch = self.write_epoch.load(SeqCst);
+		let mut buf = Vec::from_elem(count * disk_page_size, 0_u8);
+		let n = try!(self.storage.lock().read_pages(segment, offset, disk_page_size,
+			buf.as_mut_slice()).map_err(|e| IoFailed(e)));
+		self.stats.bytes_read.fetch_add(n, SeqCst);
+
+		for (i, page) in buf.as_slice().chunks(disk_page_size).take(n / disk_page_size).enumerate() {
+			let page_id = first + i as u64;
+			let mutex = self.partition(page_id);
+			if mutex.lock().entries.contains_key(&page_id) ||
+					self.write_epoch.load(SeqCst) != epoch {
+				continue;
+			}
+			// broken pages are reported when somebody fixes them
+			if verify_page(page_id, page).is_err() {
+				continue;
+			}
+			match self.reserve_frame(page_id) {
+				Ok(()) => (),
+				Err(BufferFull) => break,
+				Err(e) => return Err(e),
+			}
+			let mut partition = mutex.lock();
+			if partition.entries.contains_key(&page_id) ||
+					self.write_epoch.load(SeqCst) != epoch {
+				self.release_frame();
+				continue;
+			}
+			admit(partition.deref_mut(), page_id, page);
+			self.stats.prefetched.fetch_add(1, SeqCst);
+		}
+		Ok(())
+	}
+
+	fn flush_page(&self, page_id: u64) -> BufferResult<()> {
+		let frame = {
+			let partition = self.partition(page_id).lock();
+			partition.entries.find(&page_id).map(|frame| frame.clone())
 		};
+		match frame {
+			Some(frame) => {
+				try!(self.write_back(&frame));
+			},
+			None => (),
+		}
+		let (segment, _) = self.geometry.split_segment(page_id);
+		self.sync_segment(segment)
+	}
+
+	fn flush_segment(&self, segment: u64) -> BufferResult<()> {
+		for frame in self.frames(|page_id| {
+			let (s, _) = self.geometry.split_segment(page_id);
+			s == segment
+		}).iter() {
+			try!(self.write_back(frame));
+		}
+		self.sync_segment(segment)
+	}
+
+	fn flush_all(&self) -> BufferResult<()> {
+		for frame in self.frames(|_| true).iter() {
+			try!(self.write_back(frame));
+		}
+		let segments: Vec<u64> = self.unsynced.lock().iter().map(|s| *s).collect();
+		for segment in segments.move_iter() {
+			try!(self.sync_segment(segment));
+		}
+		Ok(())
+	}
+
+	fn create_segment(&self) -> BufferResult<u64> {
+		if self.mapped.is_some() {
+			return Err(ReadOnly);
+		}
+		// only one at a time, so nobody gets the same id twice
+		let mut next = self.next_segment.lock();
+		let mut segment = *next;
+		// pages that were never written only exist in the buffer
+		for partition in self.partitions.iter() {
+			for page_id in partition.lock().entries.keys() {
+				let (s, _) = self.geometry.split_segment(*page_id);
+				segment = max(segment, s + 1);
+			}
+		}
+		let mut storage = self.storage.lock();
+		for s in try!(storage.segments().map_err(|e| IoFailed(e))).iter() {
+			segment = max(segment, *s + 1);
+		}
+		// an empty segment, so it shows up in segments() from now on
+		try!(storage.truncate(segment, 0, self.geometry.disk_page_size()).map_err(|e| IoFailed(e)));
+		try!(storage.sync(segment).map_err(|e| IoFailed(e)));
+		*next = segment + 1;
+		info!("Created segment {}", segment);
+		Ok(segment)
+	}
+
+	fn drop_segment(&self, segment: u64) -> BufferResult<()> {
+		if self.mapped.is_some() {
+			return Err(ReadOnly);
+		}
+		try!(self.discard(|page_id| {
+			let (s, _) = self.geometry.split_segment(page_id);
+			s == segment
+		}));
+		// read-ahead that is under way must not bring the pages back
+		self.write_epoch.fetch_add(1, SeqCst);
+		self.unsynced.lock().remove(&segment);
+		try!(self.storage.lock().delete_segment(segment).map_err(|e| IoFailed(e)));
+		info!("Dropped segment {}", segment);
+		Ok(())
+	}
 
-		let frame = BufferFrame {data: content, page_id: page_id, fixed: Free};
-		let entry = BufferEntry {frame: Arc::new(RWLock::new(frame)), written: Clean};
-		self.entries.insert(page_id, entry);
-		true
+	fn truncate_segment(&self, segment: u64, pages: u64) -> BufferResult<()> {
+		if self.mapped.is_some() {
+			return Err(ReadOnly);
+		}
+		try!(self.discard(|page_id| {
+			let (s, p) = self.geometry.split_segment(page_id);
+			s == segment && p >= pages
+		}));
+		self.write_epoch.fetch_add(1, SeqCst);
+		try!(self.storage.lock().truncate(segment, pages, self.geometry.disk_page_size())
+			.map_err(|e| IoFailed(e)));
+		// the sync covers the pages that are left as well
+		self.unsynced.lock().insert(segment);
+		try!(self.sync_segment(segment));
+		info!("Truncated segment {} to {} pages", segment, pages);
+		Ok(())
 	}
 
 	/*
-	 * return false if no page could be evicted
+	 * removes matching pages from the buffer without writing them. Either
+	 * all of them go or, if one is fixed or changed by an open transaction,
+	 * none. All partitions stay locked
+	 * meanwhile, so nobody can fix one of them in between. The background
+	 * writer or an eviction might still hold on to a frame, so they are
+	 * marked and once this returns none of them gets written anymore.
 	 */
-	fn evict_page(&mut self) -> bool {
-		let random_key = {
-			let mut free_keys = self.entries.iter().filter_map(|e| {
-				// iterate over entries, check if they are free
-				// and return the keys of the free entries
-				let (k,v) = e;
-				let frame = v.frame.read();
-				if frame.fixed == Free {
-					Some(k)
-				} else {
-					None
+	fn discard(&self, matching: |u64| -> bool) -> BufferResult<()> {
+		let mut partitions = Vec::with_capacity(self.partitions.len());
+		for partition in self.partitions.iter() {
+			partitions.push(partition.lock());
+		}
+		let mut doomed = Vec::new();
+		for (i, partition) in partitions.iter().enumerate() {
+			for (page_id, frame) in partition.entries.iter() {
+				if !matching(*page_id) {
+					continue;
 				}
-			});
-			// pick a random key
-			sample(&mut free_keys).map(|v| v.clone())
+				// the changes of open transactions would get lost
+				if frame.fixed.load(SeqCst) != 0 || frame.unlogged.load(SeqCst) != 0 {
+					return Err(PageFixed(*page_id));
+				}
+				doomed.push((i, *page_id));
+			}
+		}
+		let mut frames = Vec::with_capacity(doomed.len());
+		for &(i, page_id) in doomed.iter() {
+			let partition = partitions.get_mut(i);
+			let frame = partition.entries.pop(&page_id).unwrap();
+			frame.discarded.store(true, SeqCst);
+			frames.push(frame);
+			partition.policy.removed(page_id);
+			partition.generation += 1;
+		}
+		self.used.fetch_sub(doomed.len(), SeqCst);
+		drop(partitions);
+		// writes check the mark under the latch, so one that started before
+		// is done once we get it
+		for frame in frames.iter() {
+			drop(frame.frame.read());
+		}
+		debug!("Discarded {} pages", doomed.len());
+		Ok(())
+	}
+
+	/*
+	 * collects the buffered frames of matching pages. The partitions are
+	 * only locked while collecting, so the frames can be written without
+	 * blocking threads that want to fix pages.
+	 */
+	fn frames(&self, matching: |u64| -> bool) -> Vec<ConcurrentFrame> {
+		let mut frames = Vec::new();
+		for partition in self.partitions.iter() {
+			let partition = partition.lock();
+			for (page_id, frame) in partition.entries.iter() {
+				if matching(*page_id) {
+					frames.push(frame.clone());
+				}
+			}
+		}
+		frames
+	}
+
+	/* writes back up to `batch` dirty pages nobody has fixed, returns how many */
+	fn write_dirty(&self, batch: uint) -> BufferResult<uint> {
+		let mut written = 0;
+		for frame in self.frames(|_| true).iter() {
+			if written >= batch {
+				break;
+			}
+			if frame.fixed.load(SeqCst) != 0 {
+				continue;
+			}
+			if try!(self.write_back(frame)) {
+				written += 1;
+			}
+		}
+		self.stats.background_writebacks.fetch_add(written, SeqCst);
+		Ok(written)
+	}
+
+	/*
+	 * writes the frame if it is dirty and marks it clean again, returns
+	 * whether it had to write. This happens under the frame's write latch,
+	 * so no change can slip in between writing and marking it clean.
+	 * Frames with changes that are not logged yet or of dropped pages are
+	 * left alone and before a frame is written, the log is flushed up to
+	 * its LSN.
+	 */
+	fn write_back(&self, shared: &ConcurrentFrame) -> BufferResult<bool> {
+		let mut frame = shared.frame.write();
+		self.write_frame(&**shared, &mut *frame)
+	}
+
+	/* write_back for a frame whose write latch is already held */
+	fn write_frame(&self, shared: &SharedFrame, frame: &mut BufferFrame) -> BufferResult<bool> {
+		if frame.written == Clean || shared.unlogged.load(SeqCst) != 0 ||
+				shared.discarded.load(SeqCst) {
+			return Ok(false);
+		}
+		match self.log {
+			Some(ref log) => try!(log.flush(frame.lsn)),
+			None => (),
+		}
+		// the next committed change has to log the whole page again
+		shared.imaged.store(false, SeqCst);
+		try!(self.write_page(frame.page_id, frame.lsn, frame.get_data()));
+		frame.written = Clean;
+		Ok(true)
+	}
+
+	/*
+	 * syncs the segment if anything was written to it since the last sync.
+	 * It's marked synced before the sync, so a write that happens meanwhile
+	 * marks it again instead of getting lost.
+	 */
+	fn sync_segment(&self, segment: u64) -> BufferResult<()> {
+		if !self.unsynced.lock().remove(&segment) {
+			return Ok(());
+		}
+		match self.storage.lock().sync(segment) {
+			Ok(()) => Ok(()),
+			Err(e) => {
+				self.unsynced.lock().insert(segment);
+				Err(IoFailed(e))
+			},
+		}
+	}
+
+	fn write_page(&self, page_id: u64, lsn: u64, data: &[u8]) -> BufferResult<()> {
+		if self.mapped.is_some() {
+			return Err(ReadOnly);
+		}
+		let (segment, offset) = self.geometry.split_segment(page_id);
+		let page = seal_page(page_id, lsn, data);
+		// from here on the segment might differ from what is on disk
+		self.unsynced.lock().insert(segment);
+		try!(self.storage.lock().write_page(segment, offset, page.as_slice()).map_err(|e| IoFailed(e)));
+		self.write_epoch.fetch_add(1, SeqCst);
+		self.stats.writebacks.fetch_add(1, SeqCst);
+		self.stats.bytes_written.fetch_add(page.len(), SeqCst);
+		Ok(())
+	}
+
+	fn begin(&self) {
+		let log = match self.log {
+			Some(ref log) => log,
+			None => return,
 		};
+		let id = self.id();
+		with_open_txns(|txns| {
+			let nested = match txns.find_mut(&id) {
+				Some(txn) => {
+					txn.depth += 1;
+					true
+				},
+				None => false,
+			};
+			if !nested {
+				let txn = log.next_txn();
+				let lsn = log.append(&wal::Begin(txn));
+				txns.insert(id, Txn {id: txn, last_lsn: lsn, depth: 1, aborted: false,
+					pages: Vec::new()});
+			}
+		});
+	}
 
-		match random_key {
-			None => false,
-			Some(key) => {
-				match self.entries.pop(&key) {
-					None => false,
-					Some(entry) => {
-						info!("Evicting entry: {:?}", entry);
-						if entry.written == Dirty {
-							let frame = entry.frame.read();
-							self.write_page(frame.page_id, frame.get_data());
-						}
-						true
-					},
+	/*
+	 * logs one update per changed page, covering everything from the
+	 * first to the last byte that changed, and stamps the page with it.
+	 * The first change of a page since it was last written also logs the
+	 * whole page as it was before, see Pool::fix_torn_page.
+	 */
+	fn commit(&self) -> BufferResult<()> {
+		let log = match self.log {
+			Some(ref log) => log,
+			None => return Ok(()),
+		};
+		let id = self.id();
+		let txn = with_open_txns(|txns| {
+			let done = match txns.find_mut(&id) {
+				Some(txn) => {
+					txn.depth -= 1;
+					txn.depth == 0
+				},
+				None => return Err(NoTransaction),
+			};
+			Ok(if done {txns.pop(&id)} else {None})
+		});
+		let mut txn = match try!(txn) {
+			Some(txn) => txn,
+			None => return Ok(()),
+		};
+		if txn.aborted {
+			roll_back(mem::replace(&mut txn.pages, Vec::new()));
+			log.append(&wal::Abort(txn.id, txn.last_lsn));
+			return Err(Aborted);
+		}
+		let pages = mem::replace(&mut txn.pages, Vec::new());
+		let mut last_lsn = txn.last_lsn;
+		for &(ref shared, ref before) in pages.iter() {
+			let mut frame = shared.frame.write();
+			match changed_range(before.as_slice(), frame.get_data()) {
+				Some((start, end)) => {
+					if !shared.imaged.swap(true, SeqCst) {
+						last_lsn = log.append(&wal::PageImage(txn.id, last_lsn, frame.page_id,
+							before.clone()));
+					}
+					let update = wal::PageUpdate {page_id: frame.page_id, offset: start,
+						before: Vec::from_slice(before.slice(start, end)),
+						after: Vec::from_slice(frame.get_data().slice(start, end))};
+					last_lsn = log.append(&wal::Update(txn.id, last_lsn, update));
+					frame.lsn = last_lsn;
+				},
+				None => (),
+			}
+			shared.unlogged.fetch_sub(1, SeqCst);
+			// the next transaction's before image starts from here
+			unlock_page(&**shared);
+		}
+		let lsn = log.append(&wal::Commit(txn.id, last_lsn));
+		log.flush(lsn)
+	}
+
+	/* nothing was logged for the changes, so there's nothing to undo in the log */
+	fn abort(&self) -> BufferResult<()> {
+		let log = match self.log {
+			Some(ref log) => log,
+			None => return Ok(()),
+		};
+		let id = self.id();
+		let txn = with_open_txns(|txns| {
+			let done = match txns.find_mut(&id) {
+				Some(txn) => {
+					txn.depth -= 1;
+					txn.aborted = true;
+					txn.depth == 0
+				},
+				None => return Err(NoTransaction),
+			};
+			Ok(if done {txns.pop(&id)} else {None})
+		});
+		match try!(txn) {
+			Some(mut txn) => {
+				roll_back(mem::replace(&mut txn.pages, Vec::new()));
+				log.append(&wal::Abort(txn.id, txn.last_lsn));
+				info!("Aborted transaction {}", txn.id);
+			},
+			None => (),
+		}
+		Ok(())
+	}
+}
+
+/*
+ * puts the data from before the transaction back into the frames, after
+ * that they may be written and changed by others again
+ */
+fn roll_back(pages: Vec<(ConcurrentFrame, Vec<u8>)>) {
+	for (shared, before) in pages.move_iter() {
+		shared.frame.write().get_mut_data().copy_from(before.as_slice());
+		shared.unlogged.fetch_sub(1, SeqCst);
+		unlock_page(&*shared);
+	}
+}
+
+/* a transaction that is dropped unfinished, e.g. because its task failed */
+impl Drop for Txn {
+	fn drop(&mut self) {
+		if self.pages.is_empty() {
+			return;
+		}
+		error!("Transaction {} was not finished, rolling it back", self.id);
+		for (shared, before) in mem::replace(&mut self.pages, Vec::new()).move_iter() {
+			// if the task failed while changing the page, its latch is
+			// poisoned. Then it stays unlogged and never gets written.
+			let frame = shared.clone();
+			match task::try(proc() { frame.frame.write().get_mut_data().copy_from(before.as_slice()) }) {
+				Ok(()) => {
+					shared.unlogged.fetch_sub(1, SeqCst);
+				},
+				Err(_) => error!("Rolling back a page of transaction {} failed", self.id),
+			}
+			// others must not wait for it forever
+			unlock_page(&*shared);
+		}
+	}
+}
+
+/* hands the open transactions of this task to `f` */
+fn with_open_txns<T>(f: |&mut HashMap<uint, Txn>| -> T) -> T {
+	let mut txns = match local_data::pop(open_txns) {
+		Some(txns) => txns,
+		None => HashMap::new(),
+	};
+	let result = f(&mut txns);
+	local_data::set(open_txns, txns);
+	result
+}
+
+/* the open transaction of this task on the pool `owner` */
+fn open_txn(owner: uint) -> Option<u64> {
+	with_open_txns(|txns| txns.find(&owner).map(|txn| txn.id))
+}
+
+/*
+ * waits until no other transaction has the page locked and locks it for
+ * `txn`. Returns false if `txn` had it already.
+ */
+fn lock_page(shared: &SharedFrame, txn: u64) -> bool {
+	let mut locked_by = shared.locked_by.lock();
+	if *locked_by == txn {
+		return false;
+	}
+	while *locked_by != 0 {
+		locked_by.cond.wait();
+	}
+	*locked_by = txn;
+	true
+}
+
+fn unlock_page(shared: &SharedFrame) {
+	let mut locked_by = shared.locked_by.lock();
+	*locked_by = 0;
+	locked_by.cond.broadcast();
+}
+
+/*
+ * remembers what the frame looked like before the open transaction of this
+ * task on the pool `owner` changes it for the first time. Returns whether
+ * the transaction knows the frame now.
+ */
+fn track_change(owner: uint, shared: &ConcurrentFrame, data: &[u8]) -> bool {
+	with_open_txns(|txns| {
+		match txns.find_mut(&owner) {
+			Some(txn) => {
+				let known = txn.pages.iter().any(|&(ref other, _)| {
+					&**other as *SharedFrame == &**shared as *SharedFrame
+				});
+				if !known {
+					shared.unlogged.fetch_add(1, SeqCst);
+					txn.pages.push((shared.clone(), Vec::from_slice(data)));
 				}
+				true
+			},
+			None => false,
+		}
+	})
+}
+
+/* the first and behind the last byte in which the pages differ */
+fn changed_range(before: &[u8], after: &[u8]) -> Option<(uint, uint)> {
+	let start = match range(0, before.len()).find(|&i| before[i] != after[i]) {
+		Some(start) => start,
+		None => return None,
+	};
+	let end = range(start, before.len()).rev().find(|&i| before[i] != after[i]).unwrap();
+	Some((start, end + 1))
+}
+
+/* the geometry the storage remembers, if it has one */
+fn stored_geometry(storage: &mut ~Storage:Send) -> BufferResult<Option<Geometry>> {
+	match try!(storage.read_meta().map_err(|e| IoFailed(e))) {
+		Some(meta) => match Geometry::decode(meta.as_slice()) {
+			Some(geometry) => Ok(Some(geometry)),
+			None => Err(BadMeta),
+		},
+		None => Ok(None),
+	}
+}
+
+/*
+ * puts a page as read from disk into the partition, a frame has to be
+ * reserved for it
+ */
+fn admit(partition: &mut Partition, page_id: u64, page: &[u8]) {
+	admit_frame(partition, page_frame(page_id, page));
+}
+
+/* a frame with the data of a page as read from disk */
+fn page_frame(page_id: u64, page: &[u8]) -> BufferFrame {
+	let data = Vec::from_slice(page.slice_from(PAGE_HEADER_SIZE));
+	BufferFrame {data: Owned(data), page_id: page_id, written: Clean, lsn: page_lsn(page)}
+}
+
+fn admit_frame(partition: &mut Partition, frame: BufferFrame) {
+	let page_id = frame.page_id;
+	let mapped = match frame.data {
+		Mapped(..) => true,
+		Owned(_) => false,
+	};
+	let shared = SharedFrame {fixed: AtomicUint::new(0), mapped: mapped,
+		unlogged: AtomicUint::new(0), locked_by: Mutex::new(0), imaged: AtomicBool::new(false),
+		discarded: AtomicBool::new(false), frame: RWLock::new(frame)};
+	partition.entries.insert(page_id, Arc::new(shared));
+	partition.policy.admitted(page_id);
+}
+
+/*
+ * puts the header in front of the page data. The checksum covers the page id
+ * too, so a page that ended up at the wrong place is detected as well.
+ */
+fn seal_page(page_id: u64, lsn: u64, data: &[u8]) -> Vec<u8> {
+	let mut page = Vec::from_elem(data.len() + PAGE_HEADER_SIZE, 0_u8);
+	{
+		let mut writer = BufWriter::new(page.mut_slice(4, PAGE_HEADER_SIZE));
+		writer.write_le_u32(PAGE_MAGIC).unwrap();
+		writer.write_le_u64(lsn).unwrap();
+	}
+	page.mut_slice_from(PAGE_HEADER_SIZE).copy_from(data);
+	let checksum = page_checksum(page_id, page.slice_from(4));
+	BufWriter::new(page.mut_slice(0, 4)).write_le_u32(checksum).unwrap();
+	page
+}
+
+/*
+ * checks a page as read from disk. Pages that were never written are all
+ * zero, anything else has to carry our marker and a matching checksum.
+ */
+fn verify_page(page_id: u64, page: &[u8]) -> BufferResult<()> {
+	if page.iter().all(|b| *b == 0) {
+		return Ok(());
+	}
+	let mut reader = BufReader::new(page.slice_to(8));
+	let checksum = reader.read_le_u32().unwrap();
+	let magic = reader.read_le_u32().unwrap();
+	if magic != PAGE_MAGIC || checksum != page_checksum(page_id, page.slice_from(4)) {
+		error!("Page {} failed the checksum test", page_id);
+		return Err(Corrupted(page_id));
+	}
+	Ok(())
+}
+
+/* the LSN in the header of a page as read from disk, blank pages have 0 */
+fn page_lsn(page: &[u8]) -> u64 {
+	BufReader::new(page.slice(8, PAGE_HEADER_SIZE)).read_le_u64().unwrap()
+}
+
+fn page_checksum(page_id: u64, rest: &[u8]) -> u32 {
+	let mut id = [0_u8, ..8];
+	BufWriter::new(id).write_le_u64(page_id).unwrap();
+	let crc = crc32_update(0xFFFFFFFF, id);
+	crc32_update(crc, rest) ^ 0xFFFFFFFF
+}
+
+/* plain bitwise CRC32 (IEEE), a page is small enough that no table is needed */
+pub fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+	let mut crc = crc;
+	for byte in data.iter() {
+		crc ^= *byte as u32;
+		for _ in range(0, 8) {
+			crc = if crc & 1 == 1 {(crc >> 1) ^ 0xEDB88320} else {crc >> 1};
+		}
+	}
+	crc
+}
+
+/*
+ * A buffer frame, the unit that gets the data
+ */
+impl BufferFrame {
+	/*
+	 * get_mut_data returns a borrowed reference to a mutable slice to the vector
+	 * contents.
+	 */
+	pub fn get_mut_data<'a>(&'a mut self) -> &'a mut [u8] {
+		match self.data {
+			Owned(ref mut data) => {
+				self.written = Dirty;
+				data.as_mut_slice()
 			},
+			Mapped(..) => fail!("Page {} is mapped read only", self.page_id),
 		}
 	}
-	
-	pub fn fix_page(&mut self, page_id: u64) -> Option<ConcurrentFrame> {
-		if !self.entries.contains_key(&page_id) {
-			if !self.load_page(page_id) {
-				return None;
-			}
-		}
-		let entry = self.entries.get(&page_id);
-		{
-			let mut f = entry.frame.write();
-			f.fixed = match f.fixed {
-				Free => Fixed(1),
-				Fixed(n) => Fixed(n+1),
-			};
+	pub fn get_data<'a>(&'a self) -> &'a [u8] {
+		match self.data {
+			Owned(ref data) => data.as_slice(),
+			Mapped(ref mapping, offset, len) => mapping.slice(offset, len),
 		}
-		// Arcs can be cloned and they will all point to the same RWLock
-		Some(entry.frame.clone())
 	}
 
-	pub fn unfix_page(&mut self, frame: ConcurrentFrame, is_dirty: bool) {
-		{
-			let mut frame = frame.write();
-			frame.fixed = match frame.fixed {
-				Fixed(1) => Free,
-				Fixed(n) => Fixed(n-1),
-				Free => fail!("Unfixing unfixed page"),
-			};
+	/* for code that modifies the page without going through get_mut_data */
+	pub fn mark_dirty(&mut self) {
+		match self.data {
+			Owned(_) => self.written = Dirty,
+			Mapped(..) => fail!("Page {} is mapped read only", self.page_id),
 		}
+	}
 
-		if is_dirty {
-			let frame = frame.read();
-			let entry = self.entries.get_mut(&frame.page_id);
-			entry.written = Dirty;
-		}
+	/* the LSN of the last logged change to the page */
+	pub fn lsn(&self) -> u64 {
+		self.lsn
 	}
 
-	fn write_page(&self, page_id: u64, data: &[u8]) {
-		let (segment, offset) = split_segment(page_id);
-		let file_path = self.path.join(segment.to_str());
-		let mut handle = match file::open(&file_path.to_c_str(), Open, Write) {
-			Ok(handle) => handle,
-			Err(e) => fail!("Opening file for writing failed: {}", e),
-		};
+	/* for recovery: puts logged bytes into the page and stamps it with their LSN */
+	pub fn apply_logged(&mut self, offset: uint, bytes: &[u8], lsn: u64) {
+		self.get_mut_data().mut_slice(offset, offset + bytes.len()).copy_from(bytes);
+		self.lsn = lsn;
+	}
+}
+
+impl PageGuard {
+	pub fn read<'a>(&'a self) -> RWLockReadGuard<'a, BufferFrame> {
+		self.frame.frame.read()
+	}
 
-		info!("Writing to segment {}, offset {}", segment, offset);
-		match handle.pwrite(data, offset * PAGE_SIZE as u64) {
-			Ok(_) => (),
-			//Err(e) => fail!("Writing to file failed: {}", e),
-			// again Rust 0.10 pwrite error
-			Err(_) => (),
+	/*
+	 * the page for changing. In a transaction this waits for other
+	 * transactions that changed the page, see begin.
+	 */
+	pub fn write<'a>(&'a self) -> PageWrite<'a> {
+		// failing while holding the latch would poison it for everybody
+		if self.frame.mapped {
+			fail!("Page {} is mapped read only", self.frame.frame.read().page_id);
+		}
+		// the lock comes first, its holder might need the latch to end
+		let locked = match self.owner.and_then(|owner| open_txn(owner)) {
+			Some(txn) => lock_page(&*self.frame, txn),
+			None => false,
 		};
+		PageWrite {frame: self.frame.frame.write(), shared: &self.frame, owner: self.owner,
+			locked: locked, tracked: false}
+	}
+
+	pub fn mark_dirty(&self) {
+		self.write().mark_dirty();
 	}
 }
 
 /*
- * A buffer frame, the unit that gets the data
+ * The write latch of a fixed page. In a transaction, the page is remembered
+ * as it was before it is first changed through get_mut_data, mark_dirty or
+ * the mutable deref. Only reading through it leaves the page alone.
  */
-impl BufferFrame {
-	/*
-	 * get_mut_data returns a borrowed reference to a mutable slice to the vector
-	 * contents.
-	 */
-	pub fn get_mut_data<'a>(&'a mut self) -> &'a mut [u8] {
-		self.data.as_mut_slice()
+pub struct PageWrite<'a> {
+	frame: RWLockWriteGuard<'a, BufferFrame>,
+	shared: &'a ConcurrentFrame,
+	owner: Option<uint>,
+	/* the page was locked for the transaction when the latch was taken */
+	locked: bool,
+	/* the transaction knows the page */
+	tracked: bool,
+}
+
+impl<'a> PageWrite<'a> {
+	fn track(&mut self) {
+		if self.tracked {
+			return;
+		}
+		self.tracked = match self.owner {
+			Some(owner) => track_change(owner, self.shared, self.frame.get_data()),
+			None => false,
+		};
 	}
-	pub fn get_data<'a>(&'a self) -> &'a [u8] {
-		self.data.as_slice()
+
+	pub fn get_data<'b>(&'b self) -> &'b [u8] {
+		self.frame.get_data()
+	}
+
+	pub fn get_mut_data<'b>(&'b mut self) -> &'b mut [u8] {
+		self.track();
+		self.frame.get_mut_data()
+	}
+
+	pub fn lsn(&self) -> u64 {
+		self.frame.lsn()
+	}
+
+	pub fn mark_dirty(&mut self) {
+		self.track();
+		self.frame.mark_dirty();
 	}
 }
 
-fn sample<'a, T, I:Iterator<T>>(from: &'a mut I) -> Option<T> {
-	let from: ~[T] = from.collect();
-	let l = from.len();
-	if l == 0 {
-		return None;
+impl<'a> Deref<BufferFrame> for PageWrite<'a> {
+	fn deref<'b>(&'b self) -> &'b BufferFrame {
+		&*self.frame
 	}
-	let index = randrange(l);
-	Some(from[index])
 }
 
-fn randrange<X: SampleRange + Ord + Zero>(high: X) -> X {
-	let between: Range<X> = Range::new(Zero::zero(), high);
-	let mut rng = task_rng();
-	between.ind_sample(&mut rng)
+impl<'a> DerefMut<BufferFrame> for PageWrite<'a> {
+	fn deref_mut<'b>(&'b mut self) -> &'b mut BufferFrame {
+		self.track();
+		&mut *self.frame
+	}
 }
 
-fn split_segment(num: u64) -> (u64, u64) {
-	let high = num >> PAGE_BITS;
-	let low = num & ((1 << PAGE_BITS) - 1);
-	(high, low)
+/* the lock of a page that was only read is given back right away */
+#[unsafe_destructor]
+impl<'a> Drop for PageWrite<'a> {
+	fn drop(&mut self) {
+		if self.locked && !self.tracked {
+			unlock_page(&**self.shared);
+		}
+	}
 }
 
-pub fn join_segment(segment: u64, page: u64) -> u64 {
-	// TODO do proper masking before
-	let high = segment << PAGE_BITS;
-	let low = page;
-	high ^ low
+impl Drop for PageGuard {
+	fn drop(&mut self) {
+		// unfixing doesn't need the partition, the frame just becomes evictable
+		self.frame.fixed.fetch_sub(1, SeqCst);
+	}
 }
 
 #[test]
@@ -281,25 +1841,45 @@ fn test_create() {
 		None => fail!("creation of temporary directory"),
 	};
 
-	let mut bm = BufferManager::new(16, dir.path().clone());
+	let bm = BufferManager::new(16, dir.path().clone(), replacement::LRU).unwrap();
 	let pageref = match bm.fix_page(42) {
-		Some(p) => p,
-		None => fail!("Getting page failed"),
+		Ok(p) => p,
+		Err(e) => fail!("Getting page failed: {}", e),
 	};
 	{
 		let mut page = pageref.write();
 		let data = page.get_mut_data();
 		data[0] = 42;
 	}
-	bm.unfix_page(pageref, true);
+	drop(pageref);
 }
 
+/*
+ * runs the thread test against the policy given in the POLICY environment
+ * variable or against all of them if it isn't set
+ */
 #[test]
 fn test_threads() {
+	use std::os;
+
+	match os::getenv("POLICY") {
+		Some(v) => run_threads(from_str(v).expect("POLICY expects random, lru, clock or 2q")),
+		None => {
+			for policy in [replacement::Random, replacement::LRU,
+					replacement::Clock, replacement::TwoQ].iter() {
+				run_threads(*policy);
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+fn run_threads(policy: Policy) {
 	use rand::random;
-	use std::task::spawn;
 	use std::os;
+	use replacement::randrange;
 
+	info!("Running thread test with {} policy", policy);
 	let pages_in_ram: uint = match os::getenv("PAGES_IN_RAM") {
 		Some(v) => from_str(v).expect("PAGES_IN_RAM expects integer"),
 		None => 1,
@@ -312,6 +1892,11 @@ fn test_threads() {
 		Some(v) => from_str(v).expect("THREADS expects integer"),
 		None => 3,
 	};
+	let page_size: uint = match os::getenv("PAGE_SIZE") {
+		Some(v) => from_str(v).expect("PAGE_SIZE expects integer"),
+		None => DEFAULT_PAGE_SIZE,
+	};
+	let geometry = Geometry::new(page_size, DEFAULT_PAGE_BITS).expect("PAGE_SIZE is not usable");
 	let dir = match TempDir::new("buffermanager") {
 		Some(temp_dir) => temp_dir,
 		None => fail!("creation of temporary directory"),
@@ -319,20 +1904,23 @@ fn test_threads() {
 	let p = dir.path();
 	//let p = Path::new(".");
 
-	let mut buffermanager = BufferManager::new(pages_in_ram, p.clone());
+	let buffermanager = BufferManager::create(pages_in_ram,
+		~FileStorage::new(p.clone()) as ~Storage:Send, policy, geometry).unwrap();
 
 	for i in range(0, pages_on_disk) {
 		let bf = match buffermanager.fix_page(i) {
-			Some(frame) => frame,
-			None => fail!("Couldn't fix page {}", i),
+			Ok(frame) => frame,
+			Err(e) => fail!("Couldn't fix page {}: {}", i, e),
 		};
 		{
 			let mut lock = bf.write();
 			lock.get_mut_data()[0] = 0;
 		}
-		buffermanager.unfix_page(bf, true);
+		drop(bf);
 	}
-	let bm = Arc::new(RWLock::new(buffermanager));
+	// only count what happens during the concurrent part
+	buffermanager.reset_stats();
+	let bm = Arc::new(buffermanager);
 
 	// start scan thread
 	let (tx, rx) = channel();
@@ -344,17 +1932,15 @@ fn test_threads() {
 			match rx.try_recv() {
 				Empty => {
 					let page_number = randrange(pages_on_disk);
-					let mut bm = bm_scan.write();
-					let bf = match bm.fix_page(page_number) {
-						Some(frame) => frame,
-						None => fail!("Couldn't scan/fix page"),
+					let bf = match fix_retry(&*bm_scan, page_number) {
+						Ok(frame) => frame,
+						Err(e) => fail!("Couldn't scan/fix page: {}", e),
 					};
 					let current_val = {
 						let lock = bf.read();
 						lock.get_data()[0]
 					};
-					bm.unfix_page(bf, false);
-					bm.downgrade();
+					drop(bf);
 					// check if the value is going up
 					assert!(&current_val >= counters.get(page_number as uint));
 					// set to the new value
@@ -381,11 +1967,10 @@ fn test_threads() {
 			info!("Creating new {} task",
 				if is_write {"write"} else {"read"});
 			let page_number = randrange(pages_on_disk);
-			let mut bm = bm.write();
 			if is_write {
-				let bf = match bm.fix_page(page_number) {
-					Some(frame) => frame,
-					None => fail!("Couldn't fix page"),
+				let bf = match fix_retry(&*bm, page_number) {
+					Ok(frame) => frame,
+					Err(e) => fail!("Couldn't fix page: {}", e),
 				};
 				{
 					let mut lock = bf.write();
@@ -394,13 +1979,13 @@ fn test_threads() {
 					info!("Wrote to page {}", page_number);
 					debug!("data: {}", Vec::from_slice(data));
 				}
-				bm.unfix_page(bf, is_write);
+				drop(bf);
 			} else {
-				let bf = match bm.fix_page(page_number) {
-					Some(frame) => frame,
-					None => fail!("Couldn't fix page"),
+				let bf = match fix_retry(&*bm, page_number) {
+					Ok(frame) => frame,
+					Err(e) => fail!("Couldn't fix page: {}", e),
 				};
-				bm.unfix_page(bf, is_write);
+				drop(bf);
 			}
 			// return whether we wrote (1) or read (0) as future
 			if is_write {1} else {0}
@@ -415,23 +2000,652 @@ fn test_threads() {
 	tx.send("terminate");
 	scan.get();
 
+	let stats = bm.stats();
+	info!("{}: hit rate {}, {}", policy, stats.hit_rate(), stats);
+	bm.flush_all().unwrap();
+
 	// re-open the pages and check whether all numbers got saved
-	let mut bm = BufferManager::new(pages_in_ram, p.clone());
+	let bm = BufferManager::new(pages_in_ram, p.clone(), policy).unwrap();
 	let mut total_count_on_disk = 0;
 	for i in range(0, pages_on_disk) {
 		let bf = match bm.fix_page(i) {
-			Some(frame) => frame,
-			None => fail!("Couldn't fix page")
+			Ok(frame) => frame,
+			Err(e) => fail!("Couldn't fix page: {}", e),
 		};
 		let value = {
 			let lock = bf.read();
 			let data = lock.get_data();
 			data[0]
 		};
-		bm.unfix_page(bf, false);
+		drop(bf);
 		// cast up from u8 to int
 		total_count_on_disk += value as int;
 	}
 	info!("Total count on disk: {}", total_count_on_disk);
 	assert_eq!(total_count, total_count_on_disk);
 }
+
+/*
+ * with few frames and many threads every frame might be fixed for a moment,
+ * so just try again later
+ */
+#[cfg(test)]
+fn fix_retry(bm: &BufferManager, page_id: u64) -> BufferResult<PageGuard> {
+	use std::task;
+
+	loop {
+		match bm.fix_page(page_id) {
+			Err(BufferFull) => task::deschedule(),
+			result => return result,
+		}
+	}
+}
+
+/*
+ * hammers a buffer with more frames than pages from a growing number of
+ * threads, only reads so nothing has to wait for the disk. Run with
+ * RUST_LOG=cabinet::buffer=info to see the throughput.
+ */
+#[test]
+fn test_scaling() {
+	use time::precise_time_ns;
+	use replacement::randrange;
+
+	let dir = match TempDir::new("buffermanager") {
+		Some(temp_dir) => temp_dir,
+		None => fail!("creation of temporary directory"),
+	};
+	let pages = 256_u64;
+	let fixes_per_thread = 20000;
+	let bm = Arc::new(BufferManager::new(pages as uint, dir.path().clone(),
+		replacement::Clock).unwrap());
+	// load everything once
+	for i in range(0, pages) {
+		drop(bm.fix_page(i).unwrap());
+	}
+
+	for &threads in [1_u, 2, 4, 8].iter() {
+		let start = precise_time_ns();
+		let mut workers: Vec<Future<uint>> = Vec::new();
+		for _ in range(0, threads) {
+			let bm = bm.clone();
+			workers.push(Future::spawn(proc() {
+				let mut sum = 0_u;
+				for _ in range(0, fixes_per_thread) {
+					let frame = bm.fix_page(randrange(pages)).unwrap();
+					sum += frame.read().get_data()[0] as uint;
+				}
+				sum
+			}));
+		}
+		for worker in workers.mut_iter() {
+			assert_eq!(worker.get(), 0);
+		}
+		let elapsed = precise_time_ns() - start;
+		let fixes = (threads * fixes_per_thread) as f64;
+		info!("{} threads: {} fixes/s", threads,
+			fixes / (elapsed as f64 / 1e9));
+	}
+	assert_eq!(bm.stats().misses, pages);
+}
+
+/* pages that all land in one partition may use the frames of the others */
+#[test]
+fn partitions_share_frames() {
+	let dir = match TempDir::new("buffermanager") {
+		Some(temp_dir) => temp_dir,
+		None => fail!("creation of temporary directory"),
+	};
+	let bm = BufferManager::new(32, dir.path().clone(), replacement::LRU).unwrap();
+	let crowded: Vec<u64> = range(0_u64, 10000).filter(|page_id| {
+		bm.pool.partition_index(*page_id) == 0
+	}).take(24).collect();
+	let mut guards: Vec<PageGuard> = crowded.iter().map(|page_id| bm.fix_page(*page_id).unwrap()).collect();
+	let others: Vec<u64> = range(0_u64, 10000).filter(|page_id| {
+		bm.pool.partition_index(*page_id) != 0
+	}).take(8).collect();
+	for page_id in others.iter() {
+		guards.push(bm.fix_page(*page_id).unwrap());
+	}
+	assert_eq!(bm.stats().evictions, 0);
+	// all 32 frames are fixed now
+	match bm.fix_page(1000000) {
+		Err(BufferFull) => (),
+		_ => fail!("Fixing page in full buffer did not fail"),
+	}
+	// any unfixed page makes room, wherever it is
+	drop(guards.pop());
+	bm.fix_page(1000000).unwrap();
+	assert_eq!(bm.stats().evictions, 1);
+}
+
+#[test]
+fn test_stats() {
+	let dir = match TempDir::new("buffermanager") {
+		Some(temp_dir) => temp_dir,
+		None => fail!("creation of temporary directory"),
+	};
+
+	let bm = BufferManager::new(1, dir.path().clone(), replacement::LRU).unwrap();
+	let frame = bm.fix_page(0).unwrap();
+	{
+		let mut page = frame.write();
+		page.get_mut_data()[0] = 1;
+	}
+	// the only frame is fixed, so nothing can be loaded
+	match bm.fix_page(1) {
+		Err(BufferFull) => (),
+		_ => fail!("Fixing page in full buffer did not fail"),
+	}
+	drop(frame);
+	let frame = bm.fix_page(0).unwrap();
+	drop(frame);
+	// evicts the dirty page 0
+	let frame = bm.fix_page(1).unwrap();
+	drop(frame);
+
+	let stats = bm.stats();
+	assert_eq!(stats.hits, 1);
+	assert_eq!(stats.misses, 3);
+	assert_eq!(stats.failed_fixes, 1);
+	assert_eq!(stats.evictions, 1);
+	assert_eq!(stats.writebacks, 1);
+	assert_eq!(stats.bytes_written, bm.geometry().disk_page_size() as u64);
+	// neither page existed on disk yet, so there was nothing to read
+	assert_eq!(stats.bytes_read, 0);
+	// segment 0 was opened for the first read, then reused for the write and read
+	assert_eq!(stats.opens_saved, 2);
+
+	bm.reset_stats();
+	assert_eq!(bm.stats(), Stats::new());
+}
+
+#[test]
+fn test_flush() {
+	use std::io::File;
+
+	let dir = match TempDir::new("buffermanager") {
+		Some(temp_dir) => temp_dir,
+		None => fail!("creation of temporary directory"),
+	};
+
+	let bm = BufferManager::new(16, dir.path().clone(), replacement::LRU).unwrap();
+	let page_id = bm.join_segment(3, 2);
+	let frame = bm.fix_page(page_id).unwrap();
+	{
+		let mut page = frame.write();
+		page.get_mut_data()[0] = 23;
+	}
+	drop(frame);
+	bm.flush_page(page_id).unwrap();
+	assert_eq!(bm.stats().writebacks, 1);
+
+	// the page has to be on disk even though the manager is still alive
+	let mut segment = File::open(&dir.path().join("3")).unwrap();
+	let disk_page_size = bm.geometry().disk_page_size();
+	segment.seek((2 * disk_page_size + PAGE_HEADER_SIZE) as i64, SeekSet).unwrap();
+	assert_eq!(segment.read_u8().unwrap(), 23);
+
+	// the page is clean now, nothing left to write
+	bm.flush_segment(3).unwrap();
+	bm.flush_all().unwrap();
+	assert_eq!(bm.stats().writebacks, 1);
+}
+
+#[test]
+fn test_errors() {
+	let dir = match TempDir::new("buffermanager") {
+		Some(temp_dir) => temp_dir,
+		None => fail!("creation of temporary directory"),
+	};
+
+	// the database can't be created in a directory that doesn't exist
+	match BufferManager::new(16, dir.path().join("missing"), replacement::LRU) {
+		Err(IoFailed(_)) => (),
+		_ => fail!("Creating buffer in missing directory did not fail"),
+	}
+}
+
+#[test]
+fn test_guard() {
+	let dir = match TempDir::new("buffermanager") {
+		Some(temp_dir) => temp_dir,
+		None => fail!("creation of temporary directory"),
+	};
+
+	let bm = BufferManager::new(1, dir.path().clone(), replacement::LRU).unwrap();
+	{
+		let frame = bm.fix_page(0).unwrap();
+		let page = frame.read();
+		assert_eq!(page.get_data()[0], 0);
+	}
+	// the guard is gone, so page 0 can be evicted, it was only read
+	drop(bm.fix_page(1).unwrap());
+	assert_eq!(bm.stats().writebacks, 0);
+
+	{
+		let frame = bm.fix_page(1).unwrap();
+		let mut page = frame.write();
+		page.get_mut_data()[0] = 1;
+	}
+	drop(bm.fix_page(0).unwrap());
+	assert_eq!(bm.stats().writebacks, 1);
+}
+
+#[test]
+fn test_checksum() {
+	use std::io::{File, Open, ReadWrite};
+
+	let dir = match TempDir::new("buffermanager") {
+		Some(temp_dir) => temp_dir,
+		None => fail!("creation of temporary directory"),
+	};
+
+	let disk_page_size = Geometry::default().disk_page_size();
+	{
+		let bm = BufferManager::new(4, dir.path().clone(), replacement::LRU).unwrap();
+		for i in range(0_u64, 3) {
+			let frame = bm.fix_page(i).unwrap();
+			let mut page = frame.write();
+			page.get_mut_data()[100] = 42;
+		}
+		bm.flush_all().unwrap();
+	}
+
+	// flip a byte in the data of page 1, as if the write was torn
+	{
+		let mut segment = File::open_mode(&dir.path().join("0"), Open, ReadWrite).unwrap();
+		segment.seek((disk_page_size + PAGE_HEADER_SIZE + 100) as i64, SeekSet).unwrap();
+		segment.write_u8(23).unwrap();
+	}
+	// and cut page 2 in half
+	{
+		let mut segment = File::open_mode(&dir.path().join("0"), Open, ReadWrite).unwrap();
+		segment.truncate((2 * disk_page_size + disk_page_size / 2) as i64).unwrap();
+	}
+
+	let bm = BufferManager::new(4, dir.path().clone(), replacement::LRU).unwrap();
+	assert_eq!(bm.fix_page(0).unwrap().read().get_data()[100], 42);
+	match bm.fix_page(1) {
+		Err(Corrupted(1)) => (),
+		_ => fail!("Flipped byte was not detected"),
+	}
+	match bm.fix_page(2) {
+		Err(Corrupted(2)) => (),
+		_ => fail!("Truncated page was not detected"),
+	}
+	// pages that were never written are fine
+	assert_eq!(bm.fix_page(3).unwrap().read().get_data()[100], 0);
+}
+
+#[test]
+fn test_prefetch() {
+	let dir = match TempDir::new("buffermanager") {
+		Some(temp_dir) => temp_dir,
+		None => fail!("creation of temporary directory"),
+	};
+	{
+		let bm = BufferManager::new(64, dir.path().clone(), replacement::LRU).unwrap();
+		for i in range(0_u64, 32) {
+			let frame = bm.fix_page(bm.join_segment(1, i)).unwrap();
+			frame.write().get_mut_data()[0] = i as u8;
+		}
+	}
+
+	let bm = BufferManager::new(64, dir.path().clone(), replacement::LRU).unwrap();
+	bm.prefetch(bm.join_segment(1, 0), 8);
+	assert_eq!(bm.stats().prefetched, 8);
+	for i in range(0_u64, 8) {
+		assert_eq!(bm.fix_page(bm.join_segment(1, i)).unwrap().read().get_data()[0], i as u8);
+	}
+	assert_eq!(bm.stats().misses, 0);
+	// only pages that exist get loaded
+	let prefetched = bm.stats().prefetched;
+	bm.prefetch(bm.join_segment(1, 30), 8);
+	assert_eq!(bm.stats().prefetched, prefetched + 2);
+
+	// a scan gets detected and read ahead
+	let bm = BufferManager::new(64, dir.path().clone(), replacement::LRU).unwrap();
+	for i in range(0_u64, 32) {
+		assert_eq!(bm.fix_page(bm.join_segment(1, i)).unwrap().read().get_data()[0], i as u8);
+	}
+	let stats = bm.stats();
+	assert_eq!(stats.misses + stats.prefetched, 32);
+	assert!(stats.misses <= SEQUENTIAL_AFTER as u64 + 1);
+
+	// the same with a prefetch thread, misses depend on how fast it is
+	let bm = BufferManager::new(64, dir.path().clone(), replacement::LRU).unwrap();
+	bm.start_prefetcher();
+	for i in range(0_u64, 32) {
+		assert_eq!(bm.fix_page(bm.join_segment(1, i)).unwrap().read().get_data()[0], i as u8);
+	}
+	bm.stop_prefetcher();
+	assert_eq!(bm.stats().hits + bm.stats().misses, 32);
+}
+
+#[test]
+fn test_writer() {
+	use storage::MemoryStorage;
+
+	// plenty of frames, nothing may be evicted so only the writer writes
+	let bm = BufferManager::with_storage(64, ~MemoryStorage::new() as ~Storage:Send,
+		replacement::LRU).unwrap();
+	let fixed = bm.fix_page(100).unwrap();
+	fixed.write().get_mut_data()[0] = 1;
+	for i in range(0_u64, 8) {
+		let frame = bm.fix_page(i).unwrap();
+		frame.write().get_mut_data()[0] = 1;
+	}
+
+	// the passes of the writer, without waiting for it
+	for _ in range(0, 4) {
+		assert_eq!(bm.pool.write_dirty(2).unwrap(), 2);
+	}
+	// the fixed page is still dirty
+	assert_eq!(bm.pool.write_dirty(2).unwrap(), 0);
+	assert_eq!(bm.stats().background_writebacks, 8);
+
+	// stopping the writer waits for its last pass, which writes the rest
+	drop(fixed);
+	bm.start_writer(5, 2);
+	bm.stop_writer();
+	let stats = bm.stats();
+	assert_eq!(stats.background_writebacks, 9);
+	assert_eq!(stats.writebacks, 9);
+	// nothing left to do
+	bm.flush_all().unwrap();
+	assert_eq!(bm.stats().writebacks, 9);
+	assert_eq!(bm.stats().evictions, 0);
+}
+
+#[test]
+fn test_geometry() {
+	use storage::MemoryStorage;
+
+	assert!(Geometry::new(1000, 32).is_none());
+	assert!(Geometry::new(4096, 0).is_none());
+	let geometry = Geometry::new(16 * 1024, 20).unwrap();
+	assert_eq!(geometry.split_segment(geometry.join_segment(3, 7)), (3, 7));
+
+	let dir = match TempDir::new("buffermanager") {
+		Some(temp_dir) => temp_dir,
+		None => fail!("creation of temporary directory"),
+	};
+	{
+		let bm = BufferManager::create(4, ~FileStorage::new(dir.path().clone()) as ~Storage:Send,
+			replacement::LRU, geometry).unwrap();
+		let frame = bm.fix_page(bm.join_segment(1, 1)).unwrap();
+		let mut page = frame.write();
+		assert_eq!(page.get_data().len(), 16 * 1024);
+		page.get_mut_data()[16 * 1024 - 1] = 42;
+	}
+
+	// opening picks up the stored geometry
+	let bm = BufferManager::new(4, dir.path().clone(), replacement::LRU).unwrap();
+	assert_eq!(bm.geometry(), geometry);
+	assert_eq!(bm.page_size(), 16 * 1024);
+	let page_id = bm.join_segment(1, 1);
+	assert_eq!(bm.fix_page(page_id).unwrap().read().get_data()[16 * 1024 - 1], 42);
+	drop(bm);
+
+	match BufferManager::create(4, ~FileStorage::new(dir.path().clone()) as ~Storage:Send,
+			replacement::LRU, Geometry::default()) {
+		Err(WrongGeometry(stored)) => assert_eq!(stored, geometry),
+		_ => fail!("Opening with the wrong geometry did not fail"),
+	}
+
+	// fresh storages get the default
+	let bm = BufferManager::with_storage(4, ~MemoryStorage::new() as ~Storage:Send,
+		replacement::LRU).unwrap();
+	assert_eq!(bm.geometry(), Geometry::default());
+}
+
+#[test]
+fn test_segments() {
+	use storage::MemoryStorage;
+
+	let bm = BufferManager::with_storage(16, ~MemoryStorage::new() as ~Storage:Send,
+		replacement::LRU).unwrap();
+	let segment = bm.create_segment().unwrap();
+	assert_eq!(segment, 1);
+	assert!(bm.segments().unwrap().contains(&1));
+	// segments only the buffer knows about are taken as well
+	drop(bm.fix_page(bm.join_segment(5, 0)).unwrap());
+	assert_eq!(bm.create_segment().unwrap(), 6);
+
+	for i in range(0_u64, 4) {
+		let frame = bm.fix_page(bm.join_segment(segment, i)).unwrap();
+		frame.write().get_mut_data()[0] = 1;
+	}
+	bm.flush_segment(segment).unwrap();
+	// page 3 is still dirty when the segment gets cut
+	bm.fix_page(bm.join_segment(segment, 3)).unwrap().write().get_mut_data()[0] = 2;
+	bm.truncate_segment(segment, 2).unwrap();
+	assert_eq!(bm.fix_page(bm.join_segment(segment, 1)).unwrap().read().get_data()[0], 1);
+	assert_eq!(bm.fix_page(bm.join_segment(segment, 2)).unwrap().read().get_data()[0], 0);
+	assert_eq!(bm.fix_page(bm.join_segment(segment, 3)).unwrap().read().get_data()[0], 0);
+
+	let fixed = bm.fix_page(bm.join_segment(segment, 0)).unwrap();
+	match bm.drop_segment(segment) {
+		Err(PageFixed(page_id)) => assert_eq!(page_id, bm.join_segment(segment, 0)),
+		_ => fail!("Dropping a segment with a fixed page did not fail"),
+	}
+	// nothing was thrown away
+	assert_eq!(bm.fix_page(bm.join_segment(segment, 1)).unwrap().read().get_data()[0], 1);
+	drop(fixed);
+	bm.drop_segment(segment).unwrap();
+	assert!(!bm.segments().unwrap().contains(&segment));
+	assert_eq!(bm.fix_page(bm.join_segment(segment, 0)).unwrap().read().get_data()[0], 0);
+	// dropped ids are not handed out again
+	assert_eq!(bm.create_segment().unwrap(), 7);
+
+	// the writer might have picked up a dirty page just before the drop
+	let other = bm.create_segment().unwrap();
+	bm.fix_page(bm.join_segment(other, 0)).unwrap().write().get_mut_data()[0] = 3;
+	let frames = bm.pool.frames(|page_id| bm.split_segment(page_id) == (other, 0));
+	assert_eq!(frames.len(), 1);
+	bm.drop_segment(other).unwrap();
+	assert!(!bm.pool.write_back(frames.get(0)).unwrap());
+	assert!(!bm.segments().unwrap().contains(&other));
+}
+
+#[test]
+fn test_read_only() {
+	use std::task;
+
+	let dir = match TempDir::new("buffermanager") {
+		Some(temp_dir) => temp_dir,
+		None => fail!("creation of temporary directory"),
+	};
+	{
+		let bm = BufferManager::new(16, dir.path().clone(), replacement::LRU).unwrap();
+		for i in range(0_u64, 8) {
+			let frame = bm.fix_page(bm.join_segment(1, i)).unwrap();
+			frame.write().get_mut_data()[0] = i as u8 + 1;
+		}
+	}
+
+	let bm = Arc::new(BufferManager::read_only(4, dir.path().clone(), replacement::LRU).unwrap());
+	for i in range(0_u64, 8) {
+		assert_eq!(bm.fix_page(bm.join_segment(1, i)).unwrap().read().get_data()[0], i as u8 + 1);
+	}
+	// behind the end of the file and in segments without a file
+	assert_eq!(bm.fix_page(bm.join_segment(1, 100)).unwrap().read().get_data()[0], 0);
+	assert_eq!(bm.fix_page(bm.join_segment(2, 0)).unwrap().read().get_data()[0], 0);
+	// nothing went through the storage
+	assert_eq!(bm.stats().bytes_read, 0);
+	assert!(!dir.path().join("2").exists());
+
+	let shared = bm.clone();
+	let result = task::try(proc() {
+		let page_id = shared.join_segment(1, 0);
+		shared.fix_page(page_id).unwrap().write().get_mut_data()[0] = 42;
+	});
+	assert!(result.is_err());
+	assert_eq!(bm.fix_page(bm.join_segment(1, 0)).unwrap().read().get_data()[0], 1);
+	match bm.create_segment() {
+		Err(ReadOnly) => (),
+		_ => fail!("Creating a segment in read only mode did not fail"),
+	}
+}
+
+#[test]
+fn test_wal_rule() {
+	let faults = faulty::FaultInjector::new();
+	let log_faults = faulty::FaultInjector::new();
+	let open = || {
+		BufferManager::logged(4, ~faults.storage() as ~Storage:Send,
+			~log_faults.storage() as ~Storage:Send, replacement::LRU).unwrap()
+	};
+	let bm = open();
+	bm.begin();
+	bm.fix_page(0).unwrap().write().get_mut_data()[1] = 42;
+	// the change is not logged yet, so it must not be written
+	bm.flush_all().unwrap();
+	assert_eq!(faults.writes(), 0);
+	// nested transactions commit with the outermost one
+	bm.begin();
+	bm.commit().unwrap();
+	assert_eq!(log_faults.writes(), 0);
+	bm.commit().unwrap();
+
+	let lsn = bm.fix_page(0).unwrap().read().lsn();
+	assert!(lsn != 0 && lsn < bm.log().unwrap().flushed_lsn());
+	match bm.log().unwrap().records().unwrap().as_slice() {
+		[(_, wal::Begin(txn)), (image_lsn, wal::PageImage(_, _, 0, ref image)),
+				(update_lsn, wal::Update(_, image_prev, ref update)), (_, wal::Commit(_, prev_lsn))] => {
+			assert_eq!(update_lsn, lsn);
+			assert_eq!(prev_lsn, lsn);
+			assert!(txn != 0);
+			// the page was never written, so it is logged whole first
+			assert_eq!(image_prev, image_lsn);
+			assert_eq!(*image, Vec::from_elem(bm.page_size(), 0_u8));
+			assert_eq!(*update, wal::PageUpdate {page_id: 0, offset: 1,
+				before: vec!(0_u8), after: vec!(42_u8)});
+		},
+		other => fail!("Unexpected log {}", other),
+	}
+	bm.flush_all().unwrap();
+	assert_eq!(faults.writes(), 1);
+	drop(bm);
+
+	// the LSN is stored with the page
+	let bm = open();
+	assert_eq!(bm.fix_page(0).unwrap().read().lsn(), lsn);
+}
+
+#[test]
+fn test_abort() {
+	let faults = faulty::FaultInjector::new();
+	let log_faults = faulty::FaultInjector::new();
+	let bm = Arc::new(BufferManager::logged(4, ~faults.storage() as ~Storage:Send,
+		~log_faults.storage() as ~Storage:Send, replacement::LRU).unwrap());
+	bm.fix_page(0).unwrap().write().get_mut_data()[0] = 1;
+	bm.begin();
+	bm.fix_page(0).unwrap().write().get_mut_data()[0] = 2;
+	bm.abort().unwrap();
+	assert_eq!(bm.fix_page(0).unwrap().read().get_data()[0], 1);
+	match bm.log().unwrap().records().unwrap().as_slice() {
+		[(_, wal::Begin(txn)), (_, wal::Abort(aborted, _))] => assert_eq!(txn, aborted),
+		other => fail!("Unexpected log {}", other),
+	}
+
+	// a nested abort makes the outer commit fail
+	bm.begin();
+	bm.begin();
+	bm.fix_page(0).unwrap().write().get_mut_data()[0] = 3;
+	bm.abort().unwrap();
+	match bm.commit() {
+		Err(Aborted) => (),
+		_ => fail!("Committing after a nested abort did not fail"),
+	}
+	assert_eq!(bm.fix_page(0).unwrap().read().get_data()[0], 1);
+
+	// a task that fails in the middle of a transaction is rolled back
+	let shared = bm.clone();
+	let result = task::try(proc() {
+		shared.begin();
+		shared.fix_page(0).unwrap().write().get_mut_data()[0] = 4;
+		fail!("failing inside of a transaction");
+	});
+	assert!(result.is_err());
+	assert_eq!(bm.fix_page(0).unwrap().read().get_data()[0], 1);
+
+	// changed pages stay in the buffer, four of them fill it
+	bm.begin();
+	for i in range(1_u64, 5) {
+		bm.fix_page(i).unwrap().write().get_mut_data()[0] = 5;
+	}
+	match bm.fix_page(5) {
+		Err(BufferFull) => (),
+		_ => fail!("Changing more pages than there are frames did not fail"),
+	}
+	bm.abort().unwrap();
+	// now they can go
+	for i in range(5_u64, 9) {
+		drop(bm.fix_page(i).unwrap());
+	}
+	bm.flush_all().unwrap();
+	assert_eq!(bm.fix_page(1).unwrap().read().get_data()[0], 0);
+}
+
+#[test]
+fn test_page_locks() {
+	let faults = faulty::FaultInjector::new();
+	let log_faults = faulty::FaultInjector::new();
+	let bm = Arc::new(BufferManager::logged(4, ~faults.storage() as ~Storage:Send,
+		~log_faults.storage() as ~Storage:Send, replacement::LRU).unwrap());
+	bm.begin();
+	bm.fix_page(0).unwrap().write().get_mut_data()[0] = 1;
+
+	// the other transaction waits until this one is rolled back
+	let (tx, rx) = channel();
+	let shared = bm.clone();
+	let mut other = Future::spawn(proc() {
+		shared.begin();
+		tx.send(());
+		shared.fix_page(0).unwrap().write().get_mut_data()[1] = 2;
+		shared.commit().unwrap();
+	});
+	rx.recv();
+	bm.abort().unwrap();
+	other.get();
+	let data = Vec::from_slice(bm.fix_page(0).unwrap().read().get_data().slice_to(2));
+	assert_eq!(data, vec!(0_u8, 2));
+	// and only its own change was logged
+	let records = bm.log().unwrap().records().unwrap();
+	let updates: Vec<wal::PageUpdate> = records.move_iter().filter_map(|(_, record)| match record {
+		wal::Update(_, _, update) => Some(update),
+		_ => None,
+	}).collect();
+	assert_eq!(updates, vec!(wal::PageUpdate {page_id: 0, offset: 1,
+		before: vec!(0_u8), after: vec!(2_u8)}));
+
+	// only reading through write neither logs nor locks the page
+	bm.begin();
+	assert_eq!(bm.fix_page(1).unwrap().write().get_data()[0], 0);
+	let shared = bm.clone();
+	Future::spawn(proc() {
+		shared.begin();
+		shared.fix_page(1).unwrap().write().get_mut_data()[0] = 3;
+		shared.commit().unwrap();
+	}).get();
+	bm.commit().unwrap();
+	assert_eq!(bm.fix_page(1).unwrap().read().get_data()[0], 3);
+
+	// pages of open transactions are not thrown away
+	let segment = bm.create_segment().unwrap();
+	bm.begin();
+	bm.fix_page(bm.join_segment(segment, 0)).unwrap().write().get_mut_data()[0] = 4;
+	match bm.drop_segment(segment) {
+		Err(PageFixed(page_id)) => assert_eq!(page_id, bm.join_segment(segment, 0)),
+		_ => fail!("Dropping a segment with changes of an open transaction did not fail"),
+	}
+	bm.commit().unwrap();
+	bm.drop_segment(segment).unwrap();
+
+	match (bm.commit(), bm.abort()) {
+		(Err(NoTransaction), Err(NoTransaction)) => (),
+		_ => fail!("Ending a transaction that was never begun did not fail"),
+	}
+}
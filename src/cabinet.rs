@@ -1,11 +1,21 @@
-#![feature(phase)]
+#![feature(phase, unsafe_destructor)]
 #[phase(syntax, link)] extern crate log;
 extern crate collections;
 extern crate sync;
 extern crate rand;
 extern crate serialize;
+extern crate time;
+#[cfg(test)] extern crate test;
 
+mod replacement;
+mod storage;
+mod compress;
+mod encrypt;
+mod faulty;
 mod buffer;
+mod wal;
+mod recovery;
+mod freespace;
 mod schema;
 mod btree;
 mod operators;
@@ -0,0 +1,598 @@
+use collections::{HashMap, HashSet};
+use std::cmp::{min, max};
+use std::io::{IoResult, IoError, InvalidInput, BufReader, BufWriter, TempDir};
+use storage::Storage;
+use storage;
+use buffer;
+use replacement;
+
+/*
+ * A small LZ4 style codec. The output is a list of sequences, each one is a
+ * token, some literal bytes and a match pointing back into what was already
+ * decoded. The high nibble of the token is the number of literals, the low
+ * one the match length minus MIN_MATCH, 15 means more length bytes follow.
+ * The last sequence has no match. Not compatible with real LZ4, it only has
+ * to read what it wrote itself.
+ */
+static MIN_MATCH: uint = 4;
+static MAX_OFFSET: uint = 0xFFFF;
+static HASH_BITS: uint = 12;
+
+pub fn compress(input: &[u8]) -> Vec<u8> {
+	let mut out = Vec::with_capacity(input.len() / 2 + 16);
+	// last position + 1 of every hashed 4 byte sequence, 0 is empty
+	let mut table = Vec::from_elem(1 << HASH_BITS, 0_u);
+	let mut anchor = 0;
+	let mut pos = 0;
+	while pos + MIN_MATCH <= input.len() {
+		let h = hash(input.slice(pos, pos + MIN_MATCH));
+		let candidate = *table.get(h);
+		*table.get_mut(h) = pos + 1;
+		if candidate > 0 {
+			let candidate = candidate - 1;
+			if pos - candidate <= MAX_OFFSET &&
+					input.slice(candidate, candidate + MIN_MATCH) == input.slice(pos, pos + MIN_MATCH) {
+				let mut len = MIN_MATCH;
+				while pos + len < input.len() && input[candidate + len] == input[pos + len] {
+					len += 1;
+				}
+				emit(&mut out, input.slice(anchor, pos), Some((pos - candidate, len)));
+				pos += len;
+				anchor = pos;
+				continue;
+			}
+		}
+		pos += 1;
+	}
+	emit(&mut out, input.slice_from(anchor), None);
+	out
+}
+
+/*
+ * decodes into output, returns how many bytes were written or None if the
+ * input is damaged or doesn't fit
+ */
+pub fn decompress(input: &[u8], output: &mut [u8]) -> Option<uint> {
+	let mut i = 0;
+	let mut o = 0;
+	while i < input.len() {
+		let token = input[i] as uint;
+		i += 1;
+		let literals = match read_length(input, &mut i, token >> 4) {
+			Some(n) => n,
+			None => return None,
+		};
+		if i + literals > input.len() || o + literals > output.len() {
+			return None;
+		}
+		output.mut_slice(o, o + literals).copy_from(input.slice(i, i + literals));
+		i += literals;
+		o += literals;
+		if i == input.len() {
+			break;
+		}
+
+		if i + 2 > input.len() {
+			return None;
+		}
+		let offset = input[i] as uint | (input[i + 1] as uint << 8);
+		i += 2;
+		let len = match read_length(input, &mut i, token & 0xF) {
+			Some(n) => n + MIN_MATCH,
+			None => return None,
+		};
+		if offset == 0 || offset > o || o + len > output.len() {
+			return None;
+		}
+		// byte by byte, the match may overlap what it produces
+		for k in range(o, o + len) {
+			output[k] = output[k - offset];
+		}
+		o += len;
+	}
+	Some(o)
+}
+
+fn hash(bytes: &[u8]) -> uint {
+	let n = bytes[0] as u32 | (bytes[1] as u32 << 8) | (bytes[2] as u32 << 16) |
+		(bytes[3] as u32 << 24);
+	(n * 2654435761) as uint >> (32 - HASH_BITS)
+}
+
+fn emit(out: &mut Vec<u8>, literals: &[u8], matched: Option<(uint, uint)>) {
+	let match_len = match matched {
+		Some((_, len)) => len - MIN_MATCH,
+		None => 0,
+	};
+	out.push(((min(literals.len(), 15) << 4) | min(match_len, 15)) as u8);
+	if literals.len() >= 15 {
+		push_length(out, literals.len() - 15);
+	}
+	out.push_all(literals);
+	match matched {
+		Some((offset, _)) => {
+			out.push(offset as u8);
+			out.push((offset >> 8) as u8);
+			if match_len >= 15 {
+				push_length(out, match_len - 15);
+			}
+		},
+		None => (),
+	}
+}
+
+fn push_length(out: &mut Vec<u8>, n: uint) {
+	let mut n = n;
+	while n >= 255 {
+		out.push(255);
+		n -= 255;
+	}
+	out.push(n as u8);
+}
+
+fn read_length(input: &[u8], i: &mut uint, nibble: uint) -> Option<uint> {
+	let mut n = nibble;
+	if nibble == 15 {
+		loop {
+			if *i >= input.len() {
+				return None;
+			}
+			let b = input[*i] as uint;
+			*i += 1;
+			n += b;
+			if b != 255 {
+				break;
+			}
+		}
+	}
+	Some(n)
+}
+
+/* compressed pages take up whole blocks in the data segment */
+pub static BLOCK_SIZE: uint = 256;
+static ENTRY_SIZE: uint = 20;
+/* "NCMP", at the start of every map segment */
+static MAP_MAGIC: u32 = 0x4E434D50;
+/* "NCCZ", in front of the meta, so plain storages refuse to open this */
+static META_MAGIC: u32 = 0x4E43435A;
+static FLAG_COMPRESSED: u32 = 1;
+
+/* where a page lives in the data segment */
+#[deriving(Clone)]
+struct Entry {
+	block: u64,
+	/*
+	 * how many blocks are reserved for the page, a 16 MiB page that doesn't
+	 * compress needs more than 2^16
+	 */
+	blocks: u32,
+	/* how many bytes it actually takes */
+	length: u32,
+	compressed: bool,
+}
+
+struct SegmentMap {
+	/* 0 as long as nothing was written to the segment */
+	page_size: uint,
+	entries: Vec<Option<Entry>>,
+	/* the data segment ends here */
+	next_block: u64,
+	/* pages whose entries changed, they are written on the next sync */
+	pending: HashSet<u64>,
+}
+
+/*
+ * Compresses pages on their way to another storage. Every segment turns
+ * into two segments of the inner storage: the compressed pages (2 * segment)
+ * and a map from page number to where the page is (2 * segment + 1).
+ *
+ * A page that still fits into the blocks it had is rewritten in place,
+ * otherwise it gets appended to the end of the data segment. The blocks it
+ * left behind are not reused, space is only given back when the segment is
+ * truncated to nothing or dropped.
+ *
+ * Changed map entries are only written by sync, after the data segment was
+ * synced, so after a crash the map only points to blocks that made it to
+ * disk. A page that moved since the last sync comes back as it was before.
+ * One that was rewritten in place may come back damaged, like a torn write.
+ * If it was compressed, reading it most likely fails with InvalidInput
+ * because it doesn't decompress, otherwise the buffer's checksum reports it.
+ */
+pub struct CompressedStorage<S> {
+	inner: S,
+	/* loaded on first use */
+	maps: HashMap<u64, SegmentMap>,
+}
+
+fn data_segment(segment: u64) -> u64 {
+	segment * 2
+}
+
+fn map_segment(segment: u64) -> u64 {
+	segment * 2 + 1
+}
+
+fn damaged(desc: &'static str) -> IoError {
+	IoError {kind: InvalidInput, desc: desc, detail: None}
+}
+
+impl<S: Storage> CompressedStorage<S> {
+	pub fn new(inner: S) -> CompressedStorage<S> {
+		CompressedStorage {inner: inner, maps: HashMap::new()}
+	}
+
+	fn map<'a>(&'a mut self, segment: u64) -> IoResult<&'a mut SegmentMap> {
+		if !self.maps.contains_key(&segment) {
+			let map = try!(self.read_map(segment));
+			self.maps.insert(segment, map);
+		}
+		Ok(self.maps.get_mut(&segment))
+	}
+
+	fn read_map(&mut self, segment: u64) -> IoResult<SegmentMap> {
+		let mut map = SegmentMap {page_size: 0, entries: Vec::new(), next_block: 0,
+			pending: HashSet::new()};
+		let mut header = [0_u8, ..ENTRY_SIZE];
+		if try!(self.inner.read_page(map_segment(segment), 0, header)) < ENTRY_SIZE {
+			return Ok(map);
+		}
+		let mut reader = BufReader::new(header);
+		if reader.read_le_u32().unwrap() != MAP_MAGIC {
+			return Err(damaged("compressed segment has no valid map"));
+		}
+		map.page_size = reader.read_le_u64().unwrap() as uint;
+
+		// the entries come right after the header, read them in chunks
+		let mut buf = Vec::from_elem(256 * ENTRY_SIZE, 0_u8);
+		loop {
+			let first = map.entries.len() as u64 + 1;
+			let n = try!(self.inner.read_pages(map_segment(segment), first, ENTRY_SIZE,
+				buf.as_mut_slice()));
+			for raw in buf.slice_to(n).chunks(ENTRY_SIZE) {
+				if raw.len() < ENTRY_SIZE {
+					break;
+				}
+				let entry = decode_entry(raw);
+				match entry {
+					Some(ref e) => map.next_block = max(map.next_block, e.block + e.blocks as u64),
+					None => (),
+				}
+				map.entries.push(entry);
+			}
+			if n < buf.len() {
+				break;
+			}
+		}
+		debug!("Loaded map of compressed segment {}, {} pages", segment, map.entries.len());
+		Ok(map)
+	}
+
+	/* writes the map entries that changed since the last sync */
+	fn write_entries(&mut self, segment: u64) -> IoResult<()> {
+		let changed: Vec<(u64, [u8, ..ENTRY_SIZE])> = match self.maps.find(&segment) {
+			Some(map) => {
+				let mut pages: Vec<u64> = map.pending.iter().map(|p| *p).collect();
+				pages.sort();
+				pages.iter().map(|p| {
+					(*p, encode_entry(map.entries.get(*p as uint).get_ref()))
+				}).collect()
+			},
+			None => return Ok(()),
+		};
+		for &(page_no, ref raw) in changed.iter() {
+			try!(self.inner.write_page(map_segment(segment), page_no + 1, raw.as_slice()));
+		}
+		self.maps.get_mut(&segment).pending.clear();
+		Ok(())
+	}
+}
+
+fn encode_entry(entry: &Entry) -> [u8, ..ENTRY_SIZE] {
+	let mut raw = [0_u8, ..ENTRY_SIZE];
+	{
+		let mut writer = BufWriter::new(raw);
+		writer.write_le_u64(entry.block).unwrap();
+		writer.write_le_u32(entry.length).unwrap();
+		writer.write_le_u32(entry.blocks).unwrap();
+		writer.write_le_u32(if entry.compressed {FLAG_COMPRESSED} else {0}).unwrap();
+	}
+	raw
+}
+
+/* entries of pages that were never written are all zero */
+fn decode_entry(raw: &[u8]) -> Option<Entry> {
+	let mut reader = BufReader::new(raw);
+	let block = reader.read_le_u64().unwrap();
+	let length = reader.read_le_u32().unwrap();
+	let blocks = reader.read_le_u32().unwrap();
+	let flags = reader.read_le_u32().unwrap();
+	if length == 0 {
+		return None;
+	}
+	Some(Entry {block: block, blocks: blocks, length: length,
+		compressed: flags & FLAG_COMPRESSED != 0})
+}
+
+impl<S: Storage> Storage for CompressedStorage<S> {
+	fn read_page(&mut self, segment: u64, page_no: u64, buf: &mut [u8]) -> IoResult<uint> {
+		for b in buf.mut_iter() {
+			*b = 0;
+		}
+		let entry = {
+			let map = try!(self.map(segment));
+			if page_no >= map.entries.len() as u64 {
+				return Ok(0);
+			}
+			match *map.entries.get(page_no as uint) {
+				Some(ref entry) => entry.clone(),
+				None => return Ok(0),
+			}
+		};
+		let mut data = Vec::from_elem(entry.blocks as uint * BLOCK_SIZE, 0_u8);
+		try!(self.inner.read_pages(data_segment(segment), entry.block, BLOCK_SIZE,
+			data.as_mut_slice()));
+		let data = data.slice_to(entry.length as uint);
+		if entry.compressed {
+			let decoded = decompress(data, buf);
+			if decoded != Some(buf.len()) {
+				return Err(damaged("compressed page is damaged"));
+			}
+		} else {
+			if data.len() != buf.len() {
+				return Err(damaged("stored page has the wrong size"));
+			}
+			buf.copy_from(data);
+		}
+		Ok(buf.len())
+	}
+
+	fn write_page(&mut self, segment: u64, page_no: u64, buf: &[u8]) -> IoResult<()> {
+		let compressed = compress(buf);
+		// pages that don't get smaller are stored as they are
+		let (data, is_compressed) = if compressed.len() < buf.len() {
+			(compressed.as_slice(), true)
+		} else {
+			(buf, false)
+		};
+		let blocks = (data.len() + BLOCK_SIZE - 1) / BLOCK_SIZE;
+
+		let (entry, fresh) = {
+			let map = try!(self.map(segment));
+			let fresh = map.page_size == 0;
+			if fresh {
+				map.page_size = buf.len();
+			} else if map.page_size != buf.len() {
+				return Err(damaged("page size differs from the one the segment was written with"));
+			}
+			let old = if page_no < map.entries.len() as u64 {
+				map.entries.get(page_no as uint).clone()
+			} else {
+				None
+			};
+			let entry = match old {
+				Some(ref old) if old.blocks as uint >= blocks => Entry {block: old.block,
+					blocks: old.blocks, length: data.len() as u32, compressed: is_compressed},
+				_ => {
+					let block = map.next_block;
+					map.next_block += blocks as u64;
+					Entry {block: block, blocks: blocks as u32, length: data.len() as u32,
+						compressed: is_compressed}
+				},
+			};
+			(entry, fresh)
+		};
+
+		for (i, chunk) in data.chunks(BLOCK_SIZE).enumerate() {
+			let mut block = [0_u8, ..BLOCK_SIZE];
+			block.copy_from(chunk);
+			try!(self.inner.write_page(data_segment(segment), entry.block + i as u64, block));
+		}
+		if fresh {
+			let mut header = [0_u8, ..ENTRY_SIZE];
+			{
+				let mut writer = BufWriter::new(header);
+				writer.write_le_u32(MAP_MAGIC).unwrap();
+				writer.write_le_u64(buf.len() as u64).unwrap();
+			}
+			try!(self.inner.write_page(map_segment(segment), 0, header));
+		}
+		debug!("Compressed page {} of segment {} to {} bytes", page_no, segment, data.len());
+
+		// the page only moves on disk once the map gets written by sync
+		let map = self.maps.get_mut(&segment);
+		if page_no >= map.entries.len() as u64 {
+			let missing = page_no as uint + 1 - map.entries.len();
+			map.entries.grow(missing, &None);
+		}
+		*map.entries.get_mut(page_no as uint) = Some(entry);
+		map.pending.insert(page_no);
+		Ok(())
+	}
+
+	fn sync(&mut self, segment: u64) -> IoResult<()> {
+		// the data first, the map must not point to anything that's not there
+		try!(self.inner.sync(data_segment(segment)));
+		try!(self.write_entries(segment));
+		self.inner.sync(map_segment(segment))
+	}
+
+	fn delete_segment(&mut self, segment: u64) -> IoResult<()> {
+		self.maps.remove(&segment);
+		try!(self.inner.delete_segment(data_segment(segment)));
+		self.inner.delete_segment(map_segment(segment))
+	}
+
+	/* the blocks of the pages that were cut off are not reused */
+	fn truncate(&mut self, segment: u64, pages: u64, _: uint) -> IoResult<()> {
+		if pages == 0 {
+			self.maps.remove(&segment);
+			try!(self.inner.truncate(data_segment(segment), 0, BLOCK_SIZE));
+			return self.inner.truncate(map_segment(segment), 0, ENTRY_SIZE);
+		}
+		let written = {
+			let map = try!(self.map(segment));
+			if pages < map.entries.len() as u64 {
+				map.entries.truncate(pages as uint);
+			}
+			map.pending = map.pending.iter().filter(|p| **p < pages).map(|p| *p).collect();
+			map.page_size != 0
+		};
+		// the header takes up the first entry
+		let entries = if written {pages + 1} else {0};
+		self.inner.truncate(map_segment(segment), entries, ENTRY_SIZE)
+	}
+
+	fn segments(&mut self) -> IoResult<Vec<u64>> {
+		let inner = try!(self.inner.segments());
+		Ok(inner.iter().filter(|s| **s % 2 == 1).map(|s| *s / 2).collect())
+	}
+
+	fn read_meta(&mut self) -> IoResult<Option<Vec<u8>>> {
+		match try!(self.inner.read_meta()) {
+			Some(meta) => {
+				if meta.len() < 4 || BufReader::new(meta.slice_to(4)).read_le_u32().unwrap() != META_MAGIC {
+					return Err(damaged("storage was not written compressed"));
+				}
+				Ok(Some(Vec::from_slice(meta.slice_from(4))))
+			},
+			None => Ok(None),
+		}
+	}
+
+	fn write_meta(&mut self, meta: &[u8]) -> IoResult<()> {
+		let mut tagged = Vec::from_elem(4, 0_u8);
+		BufWriter::new(tagged.as_mut_slice()).write_le_u32(META_MAGIC).unwrap();
+		tagged.push_all(meta);
+		self.inner.write_meta(tagged.as_slice())
+	}
+
+	fn opens_saved(&self) -> u64 {
+		self.inner.opens_saved()
+	}
+}
+
+#[test]
+fn codec_roundtrip() {
+	use rand::random;
+
+	let text = bytes!("a short varchar, a short varchar, and another short varchar");
+	let noise = Vec::from_fn(4096, |_| random::<u8>());
+	let mut page = Vec::from_elem(4096, 0_u8);
+	page.mut_slice(100, 100 + text.len()).copy_from(text);
+	let inputs = [Vec::new(), Vec::from_elem(4096, 0_u8), Vec::from_slice(text), noise, page];
+	for input in inputs.iter() {
+		let compressed = compress(input.as_slice());
+		let mut output = Vec::from_elem(input.len(), 1_u8);
+		assert_eq!(decompress(compressed.as_slice(), output.as_mut_slice()), Some(input.len()));
+		assert_eq!(&output, input);
+	}
+	// zeros are what most pages are made of
+	assert!(compress(Vec::from_elem(4096, 0_u8).as_slice()).len() < 64);
+
+	// too small an output and broken offsets are caught
+	let compressed = compress(inputs[1].as_slice());
+	assert_eq!(decompress(compressed.as_slice(), [0_u8, ..100]), None);
+	assert_eq!(decompress([0x04, 1, 0xFF, 0xFF], [0_u8, ..100]), None);
+}
+
+#[test]
+fn compressed_segments() {
+	let dir = match TempDir::new("compress") {
+		Some(temp_dir) => temp_dir,
+		None => fail!("creation of temporary directory"),
+	};
+	let open = || {
+		let storage = CompressedStorage::new(storage::FileStorage::new(dir.path().clone()));
+		buffer::BufferManager::with_storage(16, ~storage as ~Storage:Send,
+			replacement::LRU).unwrap()
+	};
+	{
+		let bm = open();
+		for i in range(0_u64, 64) {
+			let frame = bm.fix_page(bm.join_segment(1, i)).unwrap();
+			let mut page = frame.write();
+			page.get_mut_data()[0] = i as u8;
+			page.get_mut_data()[4000] = 42;
+		}
+		// rewritten a few times, bigger every time
+		for n in range(1_u, 4) {
+			let frame = bm.fix_page(bm.join_segment(1, 3)).unwrap();
+			let mut page = frame.write();
+			for (i, b) in page.get_mut_data().mut_slice_to(n * 500).mut_iter().enumerate() {
+				*b = (i * 7 + n) as u8;
+			}
+			drop(page);
+			drop(frame);
+			bm.flush_page(bm.join_segment(1, 3)).unwrap();
+		}
+	}
+	// way smaller than 64 plain pages
+	let size = dir.path().join(data_segment(1).to_str()).stat().unwrap().size;
+	assert!(size < 64 * 4096 / 8);
+
+	let bm = open();
+	for i in range(0_u64, 64) {
+		let frame = bm.fix_page(bm.join_segment(1, i)).unwrap();
+		let page = frame.read();
+		if i == 3 {
+			assert_eq!(page.get_data()[1499], (1499 * 7 + 3) as u8);
+		} else {
+			assert_eq!(page.get_data()[0], i as u8);
+		}
+		assert_eq!(page.get_data()[4000], 42);
+	}
+	assert!(bm.segments().unwrap().contains(&1));
+	bm.truncate_segment(1, 10).unwrap();
+	assert_eq!(bm.fix_page(bm.join_segment(1, 20)).unwrap().read().get_data()[4000], 0);
+	drop(bm);
+
+	// a plain storage can't make sense of it
+	match buffer::BufferManager::new(16, dir.path().clone(), replacement::LRU) {
+		Err(buffer::BadMeta) => (),
+		_ => fail!("Opening a compressed storage as a plain one did not fail"),
+	}
+}
+
+/* a page that moved is only found at its new place once that was synced */
+#[test]
+fn map_written_after_data() {
+	use rand::random;
+
+	let mut compressed = CompressedStorage::new(storage::MemoryStorage::new());
+	let small = Vec::from_elem(1024, 0_u8);
+	let big = Vec::from_fn(1024, |_| random::<u8>());
+	compressed.write_page(1, 0, small.as_slice()).unwrap();
+	compressed.sync(1).unwrap();
+	let mut raw = [0_u8, ..ENTRY_SIZE];
+	compressed.inner.read_page(map_segment(1), 1, raw).unwrap();
+	let before = decode_entry(raw).unwrap();
+
+	// doesn't fit into its block anymore
+	compressed.write_page(1, 0, big.as_slice()).unwrap();
+	let mut page = Vec::from_elem(1024, 0_u8);
+	compressed.read_page(1, 0, page.as_mut_slice()).unwrap();
+	assert_eq!(page, big);
+	compressed.inner.read_page(map_segment(1), 1, raw).unwrap();
+	assert_eq!(decode_entry(raw).unwrap().block, before.block);
+
+	compressed.sync(1).unwrap();
+	compressed.inner.read_page(map_segment(1), 1, raw).unwrap();
+	assert!(decode_entry(raw).unwrap().block != before.block);
+
+	// a fresh storage on the same pages sees what was synced
+	let mut reopened = CompressedStorage::new(compressed.inner);
+	reopened.read_page(1, 0, page.as_mut_slice()).unwrap();
+	assert_eq!(page, big);
+}
+
+/* a 16 MiB page that doesn't compress, plus its header */
+#[test]
+fn entries_of_big_pages() {
+	let entry = Entry {block: 3, blocks: (16 << 20) / BLOCK_SIZE as u32 + 1,
+		length: (16 << 20) + 8, compressed: false};
+	let raw = encode_entry(&entry);
+	let decoded = decode_entry(raw).unwrap();
+	assert_eq!(decoded.blocks, 65537);
+	assert_eq!(decoded.length, entry.length);
+	assert!(!decoded.compressed);
+}
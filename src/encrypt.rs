@@ -0,0 +1,362 @@
+use std::io::{IoResult, IoError, InvalidInput, BufReader, BufWriter, File, TempDir};
+use rand::random;
+use storage::Storage;
+use storage;
+use buffer;
+use replacement;
+use schema;
+
+/* ChaCha20 as in RFC 7539: 256 bit key, 96 bit nonce, 32 bit block counter */
+pub static KEY_SIZE: uint = 32;
+static BLOCK_SIZE: uint = 64;
+
+fn rotate(x: u32, n: uint) -> u32 {
+	(x << n) | (x >> (32 - n))
+}
+
+fn quarter_round(x: &mut [u32, ..16], a: uint, b: uint, c: uint, d: uint) {
+	x[a] += x[b];
+	x[d] = rotate(x[d] ^ x[a], 16);
+	x[c] += x[d];
+	x[b] = rotate(x[b] ^ x[c], 12);
+	x[a] += x[b];
+	x[d] = rotate(x[d] ^ x[a], 8);
+	x[c] += x[d];
+	x[b] = rotate(x[b] ^ x[c], 7);
+}
+
+/* the constants, the key and four words of input */
+fn initial_state(key: &[u32, ..8], input: &[u32, ..4]) -> [u32, ..16] {
+	let mut state = [0x61707865_u32, 0x3320646e, 0x79622d32, 0x6b206574,
+		0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+	for i in range(0, 8) {
+		state[4 + i] = key[i];
+	}
+	for i in range(0, 4) {
+		state[12 + i] = input[i];
+	}
+	state
+}
+
+/* 20 rounds, every iteration does a column and a diagonal round */
+fn rounds(x: &mut [u32, ..16]) {
+	for _ in range(0, 10) {
+		quarter_round(x, 0, 4, 8, 12);
+		quarter_round(x, 1, 5, 9, 13);
+		quarter_round(x, 2, 6, 10, 14);
+		quarter_round(x, 3, 7, 11, 15);
+		quarter_round(x, 0, 5, 10, 15);
+		quarter_round(x, 1, 6, 11, 12);
+		quarter_round(x, 2, 7, 8, 13);
+		quarter_round(x, 3, 4, 9, 14);
+	}
+}
+
+/* one 64 byte block of key stream */
+fn chacha20_block(key: &[u32, ..8], counter: u32, nonce: &[u32, ..3], out: &mut [u8]) {
+	let state = initial_state(key, &[counter, nonce[0], nonce[1], nonce[2]]);
+	let mut x = state;
+	rounds(&mut x);
+	let mut writer = BufWriter::new(out);
+	for i in range(0, 16) {
+		writer.write_le_u32(x[i] + state[i]).unwrap();
+	}
+}
+
+/* encrypts or decrypts, it's the same thing for a stream cipher */
+pub fn chacha20_xor(key: &[u32, ..8], nonce: &[u32, ..3], counter: u32, data: &mut [u8]) {
+	let mut stream = [0_u8, ..BLOCK_SIZE];
+	for (i, chunk) in data.mut_chunks(BLOCK_SIZE).enumerate() {
+		chacha20_block(key, counter + i as u32, nonce, stream);
+		for (b, s) in chunk.mut_iter().zip(stream.iter()) {
+			*b ^= *s;
+		}
+	}
+}
+
+/*
+ * HChaCha20 as in XChaCha20: derives a new key from a key and 128 bits of
+ * input, without the final addition of the block function
+ */
+fn hchacha20(key: &[u32, ..8], input: &[u32, ..4]) -> [u32, ..8] {
+	let mut x = initial_state(key, input);
+	rounds(&mut x);
+	[x[0], x[1], x[2], x[3], x[12], x[13], x[14], x[15]]
+}
+
+fn key_words(key: &[u8]) -> [u32, ..8] {
+	let mut words = [0_u32, ..8];
+	let mut reader = BufReader::new(key);
+	for i in range(0, 8) {
+		words[i] = reader.read_le_u32().unwrap();
+	}
+	words
+}
+
+/* random bytes in front of every page, the nonce for its key stream */
+static SALT_SIZE: uint = 12;
+/* "NCEN", in front of the meta, so plain storages refuse to open this */
+static META_MAGIC: u32 = 0x4E43454E;
+/* the meta carries this many bytes of key stream to tell a wrong key */
+static CHECK_SIZE: uint = 16;
+
+/*
+ * Encrypts pages on their way to another storage. Every page has a key of
+ * its own, derived from the database key, the segment and the page number
+ * with HChaCha20. The nonce is a 96 bit salt that is drawn anew for every
+ * write and stored in front of the page, so a page that gets rewritten
+ * doesn't reuse the key stream of its old version. Pages in the inner
+ * storage are therefore SALT_SIZE bytes bigger. A page that ends up at the
+ * wrong place decrypts to garbage, which the buffer manager's checksum
+ * catches.
+ *
+ * This only keeps the data secret, there is no integrity protection. The
+ * checksum is a CRC and not a MAC, and flipping a bit of the cipher text
+ * flips the same bit of the page, so whoever can write the files can change
+ * pages in ways that go unnoticed.
+ *
+ * The key belongs to the database: the meta remembers a bit of key stream,
+ * so opening it with another key fails instead of reading garbage.
+ */
+pub struct EncryptedStorage<S> {
+	inner: S,
+	key: [u32, ..8],
+}
+
+fn wrong_key(desc: &'static str) -> IoError {
+	IoError {kind: InvalidInput, desc: desc, detail: None}
+}
+
+fn new_salt() -> [u32, ..3] {
+	[random::<u32>(), random::<u32>(), random::<u32>()]
+}
+
+fn read_salt(raw: &[u8]) -> [u32, ..3] {
+	let mut reader = BufReader::new(raw);
+	let mut salt = [0_u32, ..3];
+	for i in range(0, 3) {
+		salt[i] = reader.read_le_u32().unwrap();
+	}
+	salt
+}
+
+fn write_salt(salt: &[u32, ..3], raw: &mut [u8]) {
+	let mut writer = BufWriter::new(raw);
+	for word in salt.iter() {
+		writer.write_le_u32(*word).unwrap();
+	}
+}
+
+impl<S: Storage> EncryptedStorage<S> {
+	pub fn new(inner: S, key: [u8, ..KEY_SIZE]) -> EncryptedStorage<S> {
+		EncryptedStorage {inner: inner, key: key_words(key)}
+	}
+
+	/* the key of one page, all 64 bits of segment and page number go into it */
+	fn page_key(&self, segment: u64, page_no: u64) -> [u32, ..8] {
+		hchacha20(&self.key, &[segment as u32, (segment >> 32) as u32,
+			page_no as u32, (page_no >> 32) as u32])
+	}
+
+	/*
+	 * key stream for the meta check, the meta has a salt of its own. Pages
+	 * never use the database key itself, so this doesn't collide with them.
+	 */
+	fn check(&self, salt: &[u32, ..3]) -> [u8, ..CHECK_SIZE] {
+		let mut check = [0_u8, ..CHECK_SIZE];
+		chacha20_xor(&self.key, salt, 0, check);
+		check
+	}
+}
+
+impl<S: Storage> Storage for EncryptedStorage<S> {
+	fn read_page(&mut self, segment: u64, page_no: u64, buf: &mut [u8]) -> IoResult<uint> {
+		let mut raw = Vec::from_elem(buf.len() + SALT_SIZE, 0_u8);
+		let n = try!(self.inner.read_page(segment, page_no, raw.as_mut_slice()));
+		let read = if n > SALT_SIZE {n - SALT_SIZE} else {0};
+		// never written, e.g. a hole before a later page or after truncate,
+		// every written page has a random salt
+		if raw.iter().all(|b| *b == 0) {
+			for b in buf.mut_iter() {
+				*b = 0;
+			}
+			return Ok(read);
+		}
+		let salt = read_salt(raw.slice_to(SALT_SIZE));
+		buf.copy_from(raw.slice_from(SALT_SIZE));
+		// whatever is missing decrypts to garbage, the checksum reports it
+		chacha20_xor(&self.page_key(segment, page_no), &salt, 0, buf);
+		Ok(read)
+	}
+
+	fn write_page(&mut self, segment: u64, page_no: u64, buf: &[u8]) -> IoResult<()> {
+		let salt = new_salt();
+		let mut raw = Vec::from_elem(SALT_SIZE, 0_u8);
+		write_salt(&salt, raw.as_mut_slice());
+		raw.push_all(buf);
+		chacha20_xor(&self.page_key(segment, page_no), &salt, 0,
+			raw.mut_slice_from(SALT_SIZE));
+		self.inner.write_page(segment, page_no, raw.as_slice())
+	}
+
+	fn sync(&mut self, segment: u64) -> IoResult<()> {
+		self.inner.sync(segment)
+	}
+
+	fn delete_segment(&mut self, segment: u64) -> IoResult<()> {
+		self.inner.delete_segment(segment)
+	}
+
+	fn truncate(&mut self, segment: u64, pages: u64, page_size: uint) -> IoResult<()> {
+		self.inner.truncate(segment, pages, page_size + SALT_SIZE)
+	}
+
+	fn segments(&mut self) -> IoResult<Vec<u64>> {
+		self.inner.segments()
+	}
+
+	fn read_meta(&mut self) -> IoResult<Option<Vec<u8>>> {
+		let meta = match try!(self.inner.read_meta()) {
+			Some(meta) => meta,
+			None => return Ok(None),
+		};
+		let header = 4 + SALT_SIZE + CHECK_SIZE;
+		if meta.len() < header {
+			return Err(wrong_key("storage was not written encrypted"));
+		}
+		if BufReader::new(meta.slice_to(4)).read_le_u32().unwrap() != META_MAGIC {
+			return Err(wrong_key("storage was not written encrypted"));
+		}
+		let salt = read_salt(meta.slice(4, 4 + SALT_SIZE));
+		if meta.slice(4 + SALT_SIZE, header) != self.check(&salt).as_slice() {
+			return Err(wrong_key("storage was written with another key"));
+		}
+		Ok(Some(Vec::from_slice(meta.slice_from(header))))
+	}
+
+	fn write_meta(&mut self, meta: &[u8]) -> IoResult<()> {
+		let salt = new_salt();
+		let mut tagged = Vec::from_elem(4 + SALT_SIZE, 0_u8);
+		BufWriter::new(tagged.mut_slice_to(4)).write_le_u32(META_MAGIC).unwrap();
+		write_salt(&salt, tagged.mut_slice_from(4));
+		tagged.push_all(self.check(&salt));
+		tagged.push_all(meta);
+		self.inner.write_meta(tagged.as_slice())
+	}
+
+	fn opens_saved(&self) -> u64 {
+		self.inner.opens_saved()
+	}
+}
+
+#[test]
+fn chacha20_test_vector() {
+	// RFC 7539, section 2.4.2
+	let key: Vec<u8> = range(0_u8, 32).collect();
+	let nonce = [0_u32, 0x4a000000, 0];
+	let mut data = Vec::from_slice(bytes!("Ladies and Gentlemen of the class of '99: If I could offer you only one tip for the future, sunscreen would be it."));
+	chacha20_xor(&key_words(key.as_slice()), &nonce, 1, data.as_mut_slice());
+	let expected = [0x6e_u8, 0x2e, 0x35, 0x9a, 0x25, 0x68, 0xf9, 0x80,
+		0x41, 0xba, 0x07, 0x28, 0xdd, 0x0d, 0x69, 0x81];
+	assert_eq!(data.slice_to(16), expected.as_slice());
+	// and back
+	chacha20_xor(&key_words(key.as_slice()), &nonce, 1, data.as_mut_slice());
+	assert!(data.as_slice().starts_with(bytes!("Ladies and Gentlemen")));
+}
+
+#[test]
+fn hchacha20_test_vector() {
+	// draft-irtf-cfrg-xchacha, section 2.2.1
+	let key: Vec<u8> = range(0_u8, 32).collect();
+	let input = [0x09000000_u32, 0x4a000000, 0, 0x27594131];
+	let subkey = hchacha20(&key_words(key.as_slice()), &input);
+	let mut out = [0_u8, ..32];
+	{
+		let mut writer = BufWriter::new(out);
+		for word in subkey.iter() {
+			writer.write_le_u32(*word).unwrap();
+		}
+	}
+	let expected = [0x82_u8, 0x41, 0x3b, 0x42, 0x27, 0xb2, 0x7b, 0xfe,
+		0xd3, 0x0e, 0x42, 0x50, 0x8a, 0x87, 0x7d, 0x73,
+		0xa0, 0xf9, 0xe4, 0xd5, 0x8a, 0x74, 0xa8, 0x53,
+		0xc1, 0x2e, 0xc4, 0x13, 0x26, 0xd3, 0xec, 0xdc];
+	assert_eq!(out.as_slice(), expected.as_slice());
+}
+
+/* the same page number in another segment or 2^32 pages further gets another key */
+#[test]
+fn page_keys_differ() {
+	let encrypted = EncryptedStorage::new(storage::MemoryStorage::new(), [7_u8, ..KEY_SIZE]);
+	let key = encrypted.page_key(1, 5);
+	assert!(encrypted.page_key(2, 5).as_slice() != key.as_slice());
+	assert!(encrypted.page_key(1, 5 + (1 << 32)).as_slice() != key.as_slice());
+	assert!(encrypted.page_key(1 << 32 | 1, 5).as_slice() != key.as_slice());
+	assert_eq!(encrypted.page_key(1, 5).as_slice(), key.as_slice());
+}
+
+/* pages that were never written read as blank, even inside the file */
+#[test]
+fn holes_are_blank() {
+	let dir = match TempDir::new("encrypt") {
+		Some(temp_dir) => temp_dir,
+		None => fail!("creation of temporary directory"),
+	};
+	let open = || {
+		let storage = EncryptedStorage::new(storage::FileStorage::new(dir.path().clone()), [7_u8, ..KEY_SIZE]);
+		buffer::BufferManager::with_storage(16, ~storage as ~Storage:Send, replacement::LRU).unwrap()
+	};
+	{
+		let manager = open();
+		manager.fix_page(manager.join_segment(1, 5)).unwrap().write().get_mut_data()[0] = 42;
+		manager.flush_all().unwrap();
+	}
+	let manager = open();
+	let guard = manager.fix_page(manager.join_segment(1, 3)).unwrap();
+	assert!(guard.read().get_data().iter().all(|b| *b == 0));
+	assert_eq!(manager.fix_page(manager.join_segment(1, 5)).unwrap().read().get_data()[0], 42);
+}
+
+#[cfg(test)]
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+	haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+#[test]
+fn no_plaintext_on_disk() {
+	use sync::Arc;
+
+	let dir = match TempDir::new("encrypt") {
+		Some(temp_dir) => temp_dir,
+		None => fail!("creation of temporary directory"),
+	};
+	let open = |key: [u8, ..KEY_SIZE]| {
+		let storage = EncryptedStorage::new(storage::FileStorage::new(dir.path().clone()), key);
+		buffer::BufferManager::with_storage(16, ~storage as ~Storage:Send, replacement::LRU)
+	};
+	let secret = bytes!("Person: Jane Doe, born 1970");
+	let tid = {
+		let mut seg = schema::SPSegment::new(1, Arc::new(open([7_u8, ..KEY_SIZE]).unwrap()));
+		let tid = seg.insert(&schema::Record::new(Vec::from_slice(secret))).unwrap();
+		seg.flush().unwrap();
+		tid
+	};
+
+	let raw = File::open(&dir.path().join("1")).unwrap().read_to_end().unwrap();
+	assert!(raw.len() > secret.len());
+	assert!(!contains(raw.as_slice(), secret));
+	assert!(!contains(raw.as_slice(), bytes!("Jane")));
+
+	let seg = schema::SPSegment::new(1, Arc::new(open([7_u8, ..KEY_SIZE]).unwrap()));
+	assert_eq!(seg.lookup(tid), schema::Record::new(Vec::from_slice(secret)));
+	drop(seg);
+
+	match open([8_u8, ..KEY_SIZE]) {
+		Err(buffer::IoFailed(_)) => (),
+		_ => fail!("Opening with the wrong key did not fail"),
+	}
+	match buffer::BufferManager::new(16, dir.path().clone(), replacement::LRU) {
+		Err(buffer::BadMeta) => (),
+		_ => fail!("Opening an encrypted storage as a plain one did not fail"),
+	}
+}
@@ -0,0 +1,338 @@
+use collections::HashMap;
+use std::io::{IoResult, IoError, OtherIoError};
+use sync::{Arc, Mutex};
+use storage;
+use storage::Storage;
+use buffer;
+use replacement;
+
+/*
+ * A storage for tests that misbehaves on request. It keeps two copies of
+ * every segment: what the "OS" sees (everything written) and what made it to
+ * the "disk" (everything that was synced). A crash throws away everything
+ * that was not synced.
+ *
+ * The faults are set up via a FaultInjector, which can hand out any number of
+ * storages working on the same data, so after a crash a new buffer manager
+ * can be started on what survived.
+ */
+struct FaultState {
+	/* the segments as of the last sync */
+	durable: HashMap<u64, Vec<u8>>,
+	/* the segments including unsynced writes */
+	volatile: HashMap<u64, Vec<u8>>,
+	reads: uint,
+	writes: uint,
+	/* the numbers of the reads and writes that should fail */
+	failing_reads: Vec<uint>,
+	failing_writes: Vec<uint>,
+	/* write number and how many bytes of it make it */
+	torn_writes: Vec<(uint, uint)>,
+	/* the numbers of the writes that crash instead of writing */
+	crashing_writes: Vec<uint>,
+	/* bumped on every crash, storages from before stop working */
+	epoch: uint,
+	/* written atomically and durably, like FileStorage does it */
+	meta: Option<Vec<u8>>,
+}
+
+#[deriving(Clone)]
+pub struct FaultInjector {
+	state: Arc<Mutex<FaultState>>,
+}
+
+pub struct FaultyStorage {
+	state: Arc<Mutex<FaultState>>,
+	epoch: uint,
+}
+
+fn injected(desc: &'static str) -> IoError {
+	IoError {kind: OtherIoError, desc: desc, detail: None}
+}
+
+impl FaultInjector {
+	pub fn new() -> FaultInjector {
+		let state = FaultState {
+			durable: HashMap::new(),
+			volatile: HashMap::new(),
+			reads: 0,
+			writes: 0,
+			failing_reads: Vec::new(),
+			failing_writes: Vec::new(),
+			torn_writes: Vec::new(),
+			crashing_writes: Vec::new(),
+			epoch: 0,
+			meta: None,
+		};
+		FaultInjector {state: Arc::new(Mutex::new(state))}
+	}
+
+	/* a storage on the current data, stops working on the next crash */
+	pub fn storage(&self) -> FaultyStorage {
+		FaultyStorage {state: self.state.clone(), epoch: self.state.lock().epoch}
+	}
+
+	/* the n-th read from now on fails, 1 is the next one */
+	pub fn fail_read(&self, n: uint) {
+		let mut state = self.state.lock();
+		let at = state.reads + n;
+		state.failing_reads.push(at);
+	}
+
+	/* the n-th write from now on fails without writing anything */
+	pub fn fail_write(&self, n: uint) {
+		let mut state = self.state.lock();
+		let at = state.writes + n;
+		state.failing_writes.push(at);
+	}
+
+	/*
+	 * the n-th write from now on only writes its first `bytes` bytes but
+	 * reports success, like a power cut in the middle of a page write
+	 */
+	pub fn tear_write(&self, n: uint, bytes: uint) {
+		let mut state = self.state.lock();
+		let at = state.writes + n;
+		state.torn_writes.push((at, bytes));
+	}
+
+	/*
+	 * the n-th write from now on doesn't happen, the storage crashes
+	 * instead, like a kill in the middle of whatever wanted to write. If
+	 * that write is torn too, the part it writes reaches the disk before
+	 * the crash, like a power cut in the middle of the page.
+	 */
+	pub fn crash_at_write(&self, n: uint) {
+		let mut state = self.state.lock();
+		let at = state.writes + n;
+		state.crashing_writes.push(at);
+	}
+
+	/*
+	 * pulls the plug: everything that was not synced is lost and all
+	 * storages handed out so far fail from now on
+	 */
+	pub fn crash(&self) {
+		crash(&mut *self.state.lock());
+	}
+
+	pub fn reads(&self) -> uint {
+		self.state.lock().reads
+	}
+
+	pub fn writes(&self) -> uint {
+		self.state.lock().writes
+	}
+}
+
+fn crash(state: &mut FaultState) {
+	let survived = state.durable.clone();
+	state.volatile = survived;
+	// crashes that were planned for later are moot now
+	state.crashing_writes.clear();
+	state.epoch += 1;
+	info!("Crashed storage, now in epoch {}", state.epoch);
+}
+
+/* writes `bytes` into the segment `data` at `start`, growing it if needed */
+fn put(data: &mut Vec<u8>, start: uint, bytes: &[u8]) {
+	if data.len() < start + bytes.len() {
+		data.grow(start + bytes.len() - data.len(), &0_u8);
+	}
+	data.mut_slice(start, start + bytes.len()).copy_from(bytes);
+}
+
+impl FaultyStorage {
+	fn check_epoch(&self, state: &FaultState) -> IoResult<()> {
+		if state.epoch != self.epoch {
+			return Err(injected("storage crashed"));
+		}
+		Ok(())
+	}
+}
+
+impl Storage for FaultyStorage {
+	fn read_page(&mut self, segment: u64, page_no: u64, buf: &mut [u8]) -> IoResult<uint> {
+		let mut state = self.state.lock();
+		try!(self.check_epoch(&*state));
+		state.reads += 1;
+		let n = state.reads;
+		if state.failing_reads.contains(&n) {
+			return Err(injected("injected read failure"));
+		}
+		for b in buf.mut_iter() {
+			*b = 0;
+		}
+		let data = match state.volatile.find(&segment) {
+			Some(data) => data,
+			None => return Ok(0),
+		};
+		let start = (page_no * buf.len() as u64) as uint;
+		if start >= data.len() {
+			return Ok(0);
+		}
+		Ok(buf.copy_from(data.slice_from(start)))
+	}
+
+	fn write_page(&mut self, segment: u64, page_no: u64, buf: &[u8]) -> IoResult<()> {
+		let mut state = self.state.lock();
+		try!(self.check_epoch(&*state));
+		state.writes += 1;
+		let n = state.writes;
+		if state.failing_writes.contains(&n) {
+			return Err(injected("injected write failure"));
+		}
+		let mut length = buf.len();
+		for &(at, bytes) in state.torn_writes.iter() {
+			if at == n && bytes < length {
+				info!("Tearing write {} after {} bytes", n, bytes);
+				length = bytes;
+			}
+		}
+		let start = (page_no * buf.len() as u64) as uint;
+		if state.crashing_writes.contains(&n) {
+			if length < buf.len() {
+				put(state.durable.find_or_insert_with(segment, |_| Vec::new()), start,
+					buf.slice_to(length));
+			}
+			crash(&mut *state);
+			return Err(injected("storage crashed"));
+		}
+		put(state.volatile.find_or_insert_with(segment, |_| Vec::new()), start, buf.slice_to(length));
+		Ok(())
+	}
+
+	fn sync(&mut self, segment: u64) -> IoResult<()> {
+		let mut state = self.state.lock();
+		try!(self.check_epoch(&*state));
+		let data = match state.volatile.find(&segment) {
+			Some(data) => data.clone(),
+			None => return Ok(()),
+		};
+		state.durable.insert(segment, data);
+		Ok(())
+	}
+
+	fn delete_segment(&mut self, segment: u64) -> IoResult<()> {
+		let mut state = self.state.lock();
+		try!(self.check_epoch(&*state));
+		state.volatile.remove(&segment);
+		state.durable.remove(&segment);
+		Ok(())
+	}
+
+	/* like writes, this only gets durable with the next sync */
+	fn truncate(&mut self, segment: u64, pages: u64, page_size: uint) -> IoResult<()> {
+		let mut state = self.state.lock();
+		try!(self.check_epoch(&*state));
+		let data = state.volatile.find_or_insert_with(segment, |_| Vec::new());
+		storage::resize(data, pages as uint * page_size);
+		Ok(())
+	}
+
+	fn segments(&mut self) -> IoResult<Vec<u64>> {
+		let state = self.state.lock();
+		try!(self.check_epoch(&*state));
+		Ok(state.volatile.keys().map(|s| *s).collect())
+	}
+
+	fn read_meta(&mut self) -> IoResult<Option<Vec<u8>>> {
+		let state = self.state.lock();
+		try!(self.check_epoch(&*state));
+		Ok(state.meta.clone())
+	}
+
+	fn write_meta(&mut self, meta: &[u8]) -> IoResult<()> {
+		let mut state = self.state.lock();
+		try!(self.check_epoch(&*state));
+		state.meta = Some(Vec::from_slice(meta));
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+fn manager(faults: &FaultInjector, size: uint) -> buffer::BufferManager {
+	buffer::BufferManager::with_storage(size, ~faults.storage() as ~Storage:Send,
+		replacement::LRU).unwrap()
+}
+
+#[test]
+fn failed_read_is_reported() {
+	let faults = FaultInjector::new();
+	let bm = manager(&faults, 4);
+	faults.fail_read(1);
+	match bm.fix_page(0) {
+		Err(buffer::IoFailed(_)) => (),
+		_ => fail!("Injected read failure was not reported"),
+	}
+	// only that one read fails
+	assert!(bm.fix_page(0).is_ok());
+}
+
+#[test]
+fn failed_write_keeps_page() {
+	let faults = FaultInjector::new();
+	let bm = manager(&faults, 1);
+	{
+		let frame = bm.fix_page(0).unwrap();
+		frame.write().get_mut_data()[0] = 42;
+	}
+	// evicting page 0 has to write it, which fails
+	faults.fail_write(1);
+	match bm.fix_page(1) {
+		Err(buffer::IoFailed(_)) => (),
+		_ => fail!("Injected write failure was not reported"),
+	}
+	// the change is still in the buffer and gets written on the next try
+	assert_eq!(bm.fix_page(0).unwrap().read().get_data()[0], 42);
+	drop(bm.fix_page(1).unwrap());
+	assert_eq!(bm.fix_page(0).unwrap().read().get_data()[0], 42);
+}
+
+#[test]
+fn crash_loses_unsynced_writes() {
+	let faults = FaultInjector::new();
+	let bm = manager(&faults, 4);
+	for &(page_id, value) in [(0_u64, 1_u8), (1, 2)].iter() {
+		let frame = bm.fix_page(page_id).unwrap();
+		frame.write().get_mut_data()[0] = value;
+	}
+	bm.flush_page(0).unwrap();
+	{
+		let frame = bm.fix_page(0).unwrap();
+		frame.write().get_mut_data()[0] = 3;
+	}
+	faults.crash();
+	// the old manager can't write anything anymore, not even on drop
+	assert!(bm.flush_all().is_err());
+	drop(bm);
+
+	let bm = manager(&faults, 4);
+	assert_eq!(bm.fix_page(0).unwrap().read().get_data()[0], 1);
+	assert_eq!(bm.fix_page(1).unwrap().read().get_data()[0], 0);
+}
+
+#[test]
+fn torn_write_is_detected() {
+	let faults = FaultInjector::new();
+	let bm = manager(&faults, 4);
+	{
+		let frame = bm.fix_page(0).unwrap();
+		frame.write().get_mut_data()[0] = 1;
+	}
+	bm.flush_all().unwrap();
+	{
+		let frame = bm.fix_page(0).unwrap();
+		frame.write().get_mut_data()[1000] = 2;
+	}
+	// the header with the new checksum makes it, the data doesn't
+	faults.tear_write(1, 512);
+	bm.flush_all().unwrap();
+	drop(bm);
+
+	let bm = manager(&faults, 4);
+	match bm.fix_page(0) {
+		Err(buffer::Corrupted(0)) => (),
+		_ => fail!("Torn write was not detected"),
+	}
+}
@@ -0,0 +1,311 @@
+use std::cmp::min;
+use std::io::{BufReader, BufWriter};
+use sync::Arc;
+use buffer;
+use buffer::{BufferResult, SegmentFull, NotAllocated, BadFormat};
+use replacement;
+use storage;
+use faulty;
+
+/* "NCFS", starts every map page that is in use */
+static MAP_MAGIC: u32 = 0x4E434653;
+static MAP_VERSION: u32 = 1;
+/* magic and version */
+static MAP_HEADER_SIZE: uint = 8;
+
+/*
+ * Keeps track of which pages of a segment are in use. The segment is split
+ * into intervals, the first page of every interval is a map page with a
+ * header and one bit per page of the interval after it. Bit 0 is the map
+ * page itself, it gets set when the map page is first used and stays set,
+ * so a blank map page means nothing behind it was ever allocated. A map page
+ * that is neither blank nor has our header means the segment is something
+ * else, that's reported as BadFormat.
+ *
+ * The map pages live in the buffer like every other page, so they get
+ * written and synced together with the rest of the segment.
+ */
+pub struct FreeSpaceMap {
+	segment: u64,
+	manager: Arc<buffer::BufferManager>,
+}
+
+impl FreeSpaceMap {
+	pub fn new(segment: u64, manager: Arc<buffer::BufferManager>) -> FreeSpaceMap {
+		FreeSpaceMap {segment: segment, manager: manager}
+	}
+
+	/* how many pages one map page covers */
+	fn interval(&self) -> u64 {
+		(self.manager.page_size() - MAP_HEADER_SIZE) as u64 * 8
+	}
+
+	fn fix_map(&self, map: u64) -> BufferResult<buffer::PageGuard> {
+		let page_id = self.manager.join_segment(self.segment, map);
+		let guard = try!(self.manager.fix_page(page_id));
+		let valid = {
+			let frame = guard.read();
+			let data = frame.get_data();
+			let mut reader = BufReader::new(data);
+			match (reader.read_le_u32(), reader.read_le_u32()) {
+				(Ok(MAP_MAGIC), Ok(MAP_VERSION)) => true,
+				_ => data.iter().all(|b| *b == 0),
+			}
+		};
+		if !valid {
+			error!("Page {} of segment {} is no free space map page", map, self.segment);
+			return Err(BadFormat(page_id));
+		}
+		Ok(guard)
+	}
+
+	/*
+	 * fails with BadFormat if the segment has pages, but no free space map
+	 * at their start
+	 */
+	pub fn check(&self) -> BufferResult<()> {
+		self.fix_map(0).map(|_| ())
+	}
+
+	/*
+	 * marks the lowest free page as used and returns its number. Fails with
+	 * SegmentFull if there is none left.
+	 */
+	pub fn allocate(&self) -> BufferResult<u64> {
+		let interval = self.interval();
+		let pages = self.manager.geometry().pages_per_segment();
+		let mut map = 0;
+		while map < pages {
+			let guard = try!(self.fix_map(map));
+			let mut frame = guard.write();
+			let limit = min(interval, pages - map) as uint;
+			let fresh = !is_set(bits(frame.get_data()), 0);
+			if fresh {
+				{
+					let mut writer = BufWriter::new(frame.get_mut_data());
+					writer.write_le_u32(MAP_MAGIC).unwrap();
+					writer.write_le_u32(MAP_VERSION).unwrap();
+				}
+				set(mut_bits(frame.get_mut_data()), 0);
+			}
+			let free = find_clear(bits(frame.get_data()), limit);
+			match free {
+				Some(bit) => {
+					set(mut_bits(frame.get_mut_data()), bit);
+					debug!("Allocated page {} in segment {}", map + bit as u64, self.segment);
+					return Ok(map + bit as u64);
+				},
+				None => (),
+			}
+			map += interval;
+		}
+		Err(SegmentFull(self.segment))
+	}
+
+	/*
+	 * gives the page back, it may be handed out again by the next allocate.
+	 * Fails with NotAllocated for map pages and pages that are not in use.
+	 */
+	pub fn free(&self, page: u64) -> BufferResult<()> {
+		let interval = self.interval();
+		let bit = (page % interval) as uint;
+		let page_id = self.manager.join_segment(self.segment, page);
+		if bit == 0 {
+			error!("Page {} of segment {} is a free space map page", page, self.segment);
+			return Err(NotAllocated(page_id));
+		}
+		let guard = try!(self.fix_map(page - bit as u64));
+		let allocated = is_set(bits(guard.read().get_data()), bit);
+		if !allocated {
+			error!("Page {} of segment {} was not allocated", page, self.segment);
+			return Err(NotAllocated(page_id));
+		}
+		let mut frame = guard.write();
+		clear(mut_bits(frame.get_mut_data()), bit);
+		debug!("Freed page {} in segment {}", page, self.segment);
+		Ok(())
+	}
+
+	/* map pages count as allocated once they are in use */
+	pub fn is_allocated(&self, page: u64) -> BufferResult<bool> {
+		let bit = (page % self.interval()) as uint;
+		let guard = try!(self.fix_map(page - bit as u64));
+		let frame = guard.read();
+		Ok(is_set(bits(frame.get_data()), bit))
+	}
+
+	/*
+	 * the first allocated page at or after `from` that is not a map page,
+	 * None if there is no such page
+	 */
+	pub fn next_allocated(&self, from: u64) -> BufferResult<Option<u64>> {
+		let interval = self.interval();
+		let pages = self.manager.geometry().pages_per_segment();
+		let mut page = from;
+		while page < pages {
+			let map = page - page % interval;
+			let guard = try!(self.fix_map(map));
+			let frame = guard.read();
+			let used = bits(frame.get_data());
+			// intervals get used in order, so the map ends here
+			if !is_set(used, 0) {
+				return Ok(None);
+			}
+			let end = min(map + interval, pages);
+			while page < end {
+				let bit = (page - map) as uint;
+				if bit != 0 && is_set(used, bit) {
+					return Ok(Some(page));
+				}
+				page += 1;
+			}
+		}
+		Ok(None)
+	}
+}
+
+/* the bitmap behind the header of a map page */
+fn bits<'a>(data: &'a [u8]) -> &'a [u8] {
+	data.slice_from(MAP_HEADER_SIZE)
+}
+
+fn mut_bits<'a>(data: &'a mut [u8]) -> &'a mut [u8] {
+	data.mut_slice_from(MAP_HEADER_SIZE)
+}
+
+fn is_set(bits: &[u8], bit: uint) -> bool {
+	bits[bit / 8] & (1 << (bit % 8)) != 0
+}
+
+fn set(bits: &mut [u8], bit: uint) {
+	bits[bit / 8] |= 1 << (bit % 8);
+}
+
+fn clear(bits: &mut [u8], bit: uint) {
+	bits[bit / 8] &= !(1 << (bit % 8));
+}
+
+/* the first clear bit below `limit`, skipping full bytes */
+fn find_clear(bits: &[u8], limit: uint) -> Option<uint> {
+	for (i, byte) in bits.iter().enumerate() {
+		if *byte == 0xFF {
+			continue;
+		}
+		for j in range(0_u, 8) {
+			let bit = i * 8 + j;
+			if bit >= limit {
+				return None;
+			}
+			if *byte & (1 << j) == 0 {
+				return Some(bit);
+			}
+		}
+	}
+	None
+}
+
+#[cfg(test)]
+fn small_manager(storage: ~storage::Storage:Send, page_bits: uint) -> Arc<buffer::BufferManager> {
+	let geometry = buffer::Geometry::new(512, page_bits).unwrap();
+	Arc::new(buffer::BufferManager::create(64, storage, replacement::LRU, geometry).unwrap())
+}
+
+#[test]
+fn allocate_and_free() {
+	let manager = small_manager(~storage::MemoryStorage::new() as ~storage::Storage:Send,
+		buffer::DEFAULT_PAGE_BITS);
+	let map = FreeSpaceMap::new(1, manager);
+	assert_eq!(map.next_allocated(0).unwrap(), None);
+	for i in range(1_u64, 4) {
+		assert_eq!(map.allocate().unwrap(), i);
+	}
+	assert!(map.is_allocated(0).unwrap());
+	map.free(2).unwrap();
+	assert!(!map.is_allocated(2).unwrap());
+	assert_eq!(map.next_allocated(2).unwrap(), Some(3));
+	// freed pages get reused first
+	assert_eq!(map.allocate().unwrap(), 2);
+
+	// 512 byte pages cover 4032 pages, the next map page is skipped
+	for i in range(4_u64, 4032) {
+		assert_eq!(map.allocate().unwrap(), i);
+	}
+	assert_eq!(map.allocate().unwrap(), 4033);
+	assert!(map.is_allocated(4032).unwrap());
+	assert_eq!(map.next_allocated(4031).unwrap(), Some(4031));
+	assert_eq!(map.next_allocated(4032).unwrap(), Some(4033));
+	assert_eq!(map.next_allocated(4034).unwrap(), None);
+}
+
+#[test]
+fn bad_frees_and_foreign_segments() {
+	let manager = small_manager(~storage::MemoryStorage::new() as ~storage::Storage:Send,
+		buffer::DEFAULT_PAGE_BITS);
+	let map = FreeSpaceMap::new(1, manager.clone());
+	assert_eq!(map.allocate().unwrap(), 1);
+	match map.free(0) {
+		Err(NotAllocated(page_id)) => assert_eq!(page_id, manager.join_segment(1, 0)),
+		_ => fail!("Freeing a map page did not fail"),
+	}
+	match map.free(2) {
+		Err(NotAllocated(page_id)) => assert_eq!(page_id, manager.join_segment(1, 2)),
+		_ => fail!("Freeing a page that is not allocated did not fail"),
+	}
+	map.free(1).unwrap();
+	match map.free(1) {
+		Err(NotAllocated(_)) => (),
+		_ => fail!("Freeing a page twice did not fail"),
+	}
+
+	// segment 2 holds something else
+	manager.fix_page(manager.join_segment(2, 0)).unwrap().write().get_mut_data()[0] = 42;
+	let foreign = FreeSpaceMap::new(2, manager.clone());
+	match foreign.check() {
+		Err(BadFormat(page_id)) => assert_eq!(page_id, manager.join_segment(2, 0)),
+		_ => fail!("Opening a segment without a map did not fail"),
+	}
+	match foreign.allocate() {
+		Err(BadFormat(_)) => (),
+		_ => fail!("Allocating in a segment without a map did not fail"),
+	}
+	// an empty segment is fine
+	FreeSpaceMap::new(3, manager).check().unwrap();
+}
+
+#[test]
+fn segment_full() {
+	// eight pages, one of them is the map
+	let manager = small_manager(~storage::MemoryStorage::new() as ~storage::Storage:Send, 3);
+	let map = FreeSpaceMap::new(1, manager);
+	for i in range(1_u64, 8) {
+		assert_eq!(map.allocate().unwrap(), i);
+	}
+	match map.allocate() {
+		Err(SegmentFull(1)) => (),
+		_ => fail!("Allocating from a full segment did not fail"),
+	}
+	map.free(5).unwrap();
+	assert_eq!(map.allocate().unwrap(), 5);
+}
+
+#[test]
+fn map_survives_crash() {
+	let faults = faulty::FaultInjector::new();
+	{
+		let manager = small_manager(~faults.storage() as ~storage::Storage:Send,
+			buffer::DEFAULT_PAGE_BITS);
+		let map = FreeSpaceMap::new(1, manager.clone());
+		map.allocate().unwrap();
+		map.allocate().unwrap();
+		manager.flush_segment(1).unwrap();
+		// this one gets lost
+		map.allocate().unwrap();
+		faults.crash();
+	}
+
+	let manager = buffer::BufferManager::with_storage(64,
+		~faults.storage() as ~storage::Storage:Send, replacement::LRU).unwrap();
+	let map = FreeSpaceMap::new(1, Arc::new(manager));
+	assert!(map.is_allocated(2).unwrap());
+	assert_eq!(map.allocate().unwrap(), 3);
+}
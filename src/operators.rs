@@ -1,9 +1,10 @@
 use std::io::{TempDir, MemWriter};
 use std::str::from_utf8;
-use sync::{Arc, Mutex, RWLock};
+use sync::{Arc, Mutex};
 use collections::hashmap::HashMap;
 use schema;
 use buffer;
+use replacement;
 
 #[deriving(Show, Eq, TotalEq, Hash, Clone)]
 struct Register {
@@ -281,8 +282,8 @@ impl<T: Operatorish<Vec<Register>>> Operatorish<Vec<Register>> for HashJoin<T> {
 fn construct_relation(p: Path) -> (schema::Relation, Arc<Mutex<schema::SPSegment>>) {
 	//let p = Path::new(".");
 
-	let manager = buffer::BufferManager::new(1024, p);
-	let mut seg = schema::SPSegment::new(1, Arc::new(RWLock::new(manager)));
+	let manager = buffer::BufferManager::new(1024, p, replacement::LRU).unwrap();
+	let mut seg = schema::SPSegment::new(1, Arc::new(manager));
 
 	let name = schema::Column::new(~"name", schema::Varchar(128), vec!(schema::NotNull));
 	let age = schema::Column::new(~"age", schema::Integer, vec!(schema::NotNull));
@@ -413,8 +414,8 @@ fn simple_hashjoin() {
 	let p = dir.path();
 	//let p = Path::new(".");
 
-	let manager = buffer::BufferManager::new(1024, p.clone());
-	let mut seg = schema::SPSegment::new(1, Arc::new(RWLock::new(manager)));
+	let manager = buffer::BufferManager::new(1024, p.clone(), replacement::LRU).unwrap();
+	let mut seg = schema::SPSegment::new(1, Arc::new(manager));
 
 	/* first relation */
 	let mut people = schema::Relation::new(~"Person");
@@ -0,0 +1,529 @@
+use collections::HashMap;
+use sync::Arc;
+use buffer;
+use buffer::{BufferManager, BufferResult, BadLog, Corrupted};
+use wal;
+use wal::{Begin, Update, Commit, Compensation, Abort, PageImage, PageUpdate};
+use storage::{Storage, MemoryStorage};
+use replacement;
+use schema;
+use faulty;
+
+/*
+ * ARIES style recovery, runs when a buffer with a log is opened:
+ *
+ * analysis finds the transactions that neither committed nor aborted, the
+ * losers. Redo repeats history: every logged change the page doesn't have
+ * yet, judging by its LSN, is applied again, the losers' included. Undo then
+ * rolls the losers back, the newest change first. Every undone change gets
+ * a compensation record pointing at the next change to undo, so a crash
+ * during recovery neither undoes anything twice nor forgets anything. An
+ * Abort record finishes every loser.
+ *
+ * A page torn by the crash fails its checksum. Redo skips the changes it
+ * can't apply to it, until the log has an image of the whole page, which
+ * it starts over from. Every page that was changed since it was last
+ * written has one, so only torn pages that were never logged stay broken.
+ *
+ * There are no checkpoints yet, analysis and redo go through the whole log.
+ */
+pub fn recover(manager: &BufferManager) -> BufferResult<()> {
+	let log = match manager.log() {
+		Some(log) => log,
+		None => return Ok(()),
+	};
+	let records = try!(log.records());
+
+	// analysis: the last record of every unfinished transaction
+	let mut losers = HashMap::new();
+	for &(lsn, ref record) in records.iter() {
+		match *record {
+			Commit(txn, _) | Abort(txn, _) => {
+				losers.remove(&txn);
+			},
+			_ => {
+				losers.insert(record.txn(), lsn);
+			},
+		}
+	}
+	info!("Recovering from {} log records, {} transactions to undo",
+		records.len(), losers.len());
+
+	// redo
+	for &(lsn, ref record) in records.iter() {
+		match *record {
+			Update(_, _, ref update) | Compensation(_, _, ref update, _) => {
+				try!(redo(manager, lsn, update));
+			},
+			PageImage(_, _, page_id, ref data) => {
+				let guard = try!(manager.fix_torn_page(page_id));
+				let mut frame = guard.write();
+				if frame.lsn() < lsn {
+					frame.apply_logged(0, data.as_slice(), lsn);
+				}
+			},
+			_ => (),
+		}
+	}
+
+	// undo, the latest record of all losers first
+	let by_lsn: HashMap<u64, &wal::Record> = records.iter()
+		.map(|&(lsn, ref record)| (lsn, record)).collect();
+	// the last record each loser has in the log and the next one to undo
+	let mut last = losers.clone();
+	let mut next = losers;
+	let mut last_lsn = 0;
+	loop {
+		let (txn, lsn) = match next.iter().max_by(|&(_, lsn)| *lsn) {
+			Some((txn, lsn)) => (*txn, *lsn),
+			None => break,
+		};
+		let record = match by_lsn.find(&lsn) {
+			Some(record) => *record,
+			None => {
+				error!("Transaction {} refers to {}, which is not in the log", txn, lsn);
+				return Err(BadLog);
+			},
+		};
+		let prev = *last.get(&txn);
+		match *record {
+			Update(_, prev_lsn, ref update) => {
+				let undo = PageUpdate {page_id: update.page_id, offset: update.offset,
+					before: update.after.clone(), after: update.before.clone()};
+				let clr = log.append(&Compensation(txn, prev, undo.clone(), prev_lsn));
+				try!(apply(manager, clr, &undo));
+				last.insert(txn, clr);
+				next.insert(txn, prev_lsn);
+			},
+			Compensation(_, _, _, undo_next) => {
+				next.insert(txn, undo_next);
+			},
+			// the update that follows it has what is needed to undo
+			PageImage(_, prev_lsn, _, _) => {
+				next.insert(txn, prev_lsn);
+			},
+			Begin(_) => {
+				last_lsn = log.append(&Abort(txn, prev));
+				next.remove(&txn);
+			},
+			Commit(..) | Abort(..) => {
+				error!("Finished transaction {} is to be undone", txn);
+				return Err(BadLog);
+			},
+		}
+	}
+	try!(log.flush(last_lsn));
+	// the recovered pages go to disk right away
+	manager.flush_all()
+}
+
+/*
+ * applies the change again unless the page already has it. A torn page is
+ * left for an image further on.
+ */
+fn redo(manager: &BufferManager, lsn: u64, update: &PageUpdate) -> BufferResult<()> {
+	let guard = match manager.fix_page(update.page_id) {
+		Ok(guard) => guard,
+		Err(Corrupted(page_id)) => {
+			debug!("Skipping {} for torn page {}", lsn, page_id);
+			return Ok(());
+		},
+		Err(e) => return Err(e),
+	};
+	let mut frame = guard.write();
+	if frame.lsn() < lsn {
+		frame.apply_logged(update.offset, update.after.as_slice(), lsn);
+	}
+	Ok(())
+}
+
+fn apply(manager: &BufferManager, lsn: u64, update: &PageUpdate) -> BufferResult<()> {
+	let guard = try!(manager.fix_page(update.page_id));
+	guard.write().apply_logged(update.offset, update.after.as_slice(), lsn);
+	Ok(())
+}
+
+/*
+ * the tests keep data and log on the same storage, so one crash hits both.
+ * The log gets a segment none of the data uses.
+ */
+#[cfg(test)]
+static TEST_LOG_SEGMENT: u64 = 1000;
+
+#[cfg(test)]
+fn open_logged(data: faulty::FaultyStorage, log: faulty::FaultyStorage, size: uint) -> BufferManager {
+	let log = wal::Log::open_segment(~log as ~Storage:Send, TEST_LOG_SEGMENT).unwrap();
+	BufferManager::with_log(size, ~data as ~Storage:Send, log, replacement::LRU).unwrap()
+}
+
+#[cfg(test)]
+fn logged_manager(faults: &faulty::FaultInjector, size: uint) -> Arc<BufferManager> {
+	Arc::new(open_logged(faults.storage(), faults.storage(), size))
+}
+
+/* checksum over the data of the first `pages` pages of the segment */
+#[cfg(test)]
+pub fn segment_checksum(manager: &BufferManager, segment: u64, pages: u64) -> u32 {
+	let mut crc = 0xFFFFFFFF;
+	for page in range(0, pages) {
+		let guard = manager.fix_page(manager.join_segment(segment, page)).unwrap();
+		let frame = guard.read();
+		crc = buffer::crc32_update(crc, frame.get_data());
+	}
+	crc
+}
+
+/*
+ * the checksums of the segment before `work` and after every step of it,
+ * on a buffer without log that never crashes
+ */
+#[cfg(test)]
+pub fn expected_checksums(work: fn(Arc<BufferManager>, ||), segment: u64, pages: u64) -> Vec<u32> {
+	let manager = Arc::new(BufferManager::with_storage(256,
+		~MemoryStorage::new() as ~Storage:Send, replacement::LRU).unwrap());
+	let mut checksums = vec!(segment_checksum(&*manager, segment, pages));
+	let observer = manager.clone();
+	work(manager, || checksums.push(segment_checksum(&*observer, segment, pages)));
+	checksums
+}
+
+/* how many writes `work` did after each of its steps, without crashing */
+#[cfg(test)]
+pub fn writes_per_step(size: uint, work: fn(Arc<BufferManager>, ||)) -> Vec<uint> {
+	let faults = faulty::FaultInjector::new();
+	let mut writes = Vec::new();
+	work(logged_manager(&faults, size), || writes.push(faults.writes()));
+	writes
+}
+
+/*
+ * runs `work` on a logged buffer of `size` frames, the storage crashes at
+ * write number `at` or after the work is done. Returns how many steps were
+ * done before that.
+ */
+#[cfg(test)]
+pub fn steps_before_crash(faults: &faulty::FaultInjector, at: uint, size: uint,
+		work: fn(Arc<BufferManager>, ||)) -> uint {
+	use std::task;
+
+	faults.crash_at_write(at);
+	let (data, log) = (faults.storage(), faults.storage());
+	let (tx, rx) = channel();
+	let result = task::try(proc() {
+		work(Arc::new(open_logged(data, log, size)), || tx.send(()));
+	});
+	debug!("Work was cut short: {}", result.is_err());
+	faults.crash();
+	rx.iter().count()
+}
+
+/*
+ * `count` writes to crash at, `pick` turns the number of the crash point
+ * and a random number into one. They are logged, and setting CRASH_AT to a
+ * comma separated list of writes crashes there instead, to repeat a run.
+ */
+#[cfg(test)]
+pub fn crash_points(count: uint, pick: |uint, uint| -> uint) -> Vec<uint> {
+	use std::os;
+	use rand::random;
+
+	let points: Vec<uint> = match os::getenv("CRASH_AT") {
+		Some(v) => v.split(',').map(|at| {
+			from_str(at.trim()).expect("CRASH_AT expects write numbers")
+		}).collect(),
+		None => range(0, count).map(|i| pick(i, random::<uint>())).collect(),
+	};
+	info!("Crashing at writes {}", points);
+	points
+}
+
+/*
+ * how much of a torn write makes it to the disk, past the header of data
+ * and log pages but not all of either
+ */
+#[cfg(test)]
+static TORN_BYTES: uint = 300;
+
+/*
+ * crashes `work` at every one of the crash points, once dropping the write
+ * there and once tearing it, and checks that recovery brings the segment
+ * back to what it was after the last step that was done
+ */
+#[cfg(test)]
+pub fn check_recovery(work: fn(Arc<BufferManager>, ||), segment: u64, pages: u64,
+		crash_points: &[uint]) -> Vec<(uint, Arc<BufferManager>)> {
+	let expected = expected_checksums(work, segment, pages);
+	let mut recovered = Vec::new();
+	for &at in crash_points.iter() {
+		for &torn in [false, true].iter() {
+			let faults = faulty::FaultInjector::new();
+			if torn {
+				faults.tear_write(at, TORN_BYTES);
+			}
+			let mut steps = steps_before_crash(&faults, at, 64, work);
+			let manager = logged_manager(&faults, 64);
+			let checksum = segment_checksum(&*manager, segment, pages);
+			// a torn log write can still get the commit it was writing to the disk
+			if torn && steps + 1 < expected.len() && checksum == *expected.get(steps + 1) {
+				steps += 1;
+			}
+			if checksum != *expected.get(steps) {
+				fail!("Recovery after crashing at write {} ({} steps done, torn: {}) went wrong, \
+					run again with CRASH_AT={}", at, steps, torn, at);
+			}
+			recovered.push((steps, manager));
+		}
+	}
+	recovered
+}
+
+#[cfg(test)]
+fn segment_work(manager: Arc<BufferManager>, step: ||) {
+	let mut seg = schema::SPSegment::new(1, manager.clone());
+	step();
+	let mut tids = Vec::new();
+	for i in range(1_u, 60) {
+		if i % 3 == 0 {
+			let tid = tids.remove(0).unwrap();
+			seg.remove(tid);
+		} else {
+			let len = (i * 389) % 1000 + 1;
+			tids.push(seg.insert(&schema::Record::new(Vec::from_elem(len, i as u8))).unwrap());
+		}
+		// so the pages on disk are at all sorts of LSNs
+		if i % 10 == 0 {
+			manager.flush_all().unwrap();
+		}
+		step();
+	}
+}
+
+#[test]
+fn segment_survives_random_crashes() {
+	let writes = *writes_per_step(64, segment_work).last().unwrap();
+	let points = crash_points(12, |_, random| random % writes + 1);
+	check_recovery(segment_work, 1, 16, points.as_slice());
+}
+
+/* how many tasks count their transactions in counting_work and how many each runs */
+#[cfg(test)]
+static COUNTING_TASKS: u64 = 4;
+#[cfg(test)]
+static COUNTING_TXNS: uint = 40;
+
+/* a count in the first bytes of a page */
+#[cfg(test)]
+fn read_count(data: &[u8]) -> u64 {
+	use std::io::BufReader;
+	BufReader::new(data.slice_to(8)).read_le_u64().unwrap()
+}
+
+#[cfg(test)]
+fn write_count(data: &mut [u8], count: u64) {
+	use std::io::BufWriter;
+	BufWriter::new(data.mut_slice_to(8)).write_le_u64(count).unwrap();
+}
+
+/*
+ * what the `count`-th transaction of a task does to its page: the count goes
+ * in front and a byte of its own somewhere behind
+ */
+#[cfg(test)]
+fn count_page(data: &mut [u8], count: u64) {
+	write_count(data, count);
+	let spread = data.len() - 8;
+	data[8 + (count as uint * 997) % spread] = count as u8;
+}
+
+/*
+ * every task counts its transactions on a page of its own in segment 1 and
+ * all of them in the page behind those, where they wait for each other.
+ * Every seventh transaction is aborted instead. Each commit is sent with
+ * the task and its count. This returns right away, the tasks end on their
+ * own.
+ */
+#[cfg(test)]
+fn counting_work(manager: Arc<BufferManager>, commits: Sender<(u64, u64)>) {
+	use std::task;
+
+	for worker in range(0, COUNTING_TASKS) {
+		let (manager, commits) = (manager.clone(), commits.clone());
+		task::spawn(proc() {
+			let own = manager.join_segment(1, worker);
+			let total = manager.join_segment(1, COUNTING_TASKS);
+			let mut count = 0;
+			for i in range(1_u, COUNTING_TXNS + 1) {
+				manager.begin();
+				count_page(manager.fix_page(own).unwrap().write().get_mut_data(), count + 1);
+				{
+					let guard = manager.fix_page(total).unwrap();
+					let mut frame = guard.write();
+					let sum = read_count(frame.get_data()) + 1;
+					write_count(frame.get_mut_data(), sum);
+				}
+				if i % 7 == 0 {
+					manager.abort().unwrap();
+					continue;
+				}
+				manager.commit().unwrap();
+				count += 1;
+				commits.send((worker, count));
+				if i % 10 == 0 {
+					manager.flush_all().unwrap();
+				}
+			}
+		});
+	}
+}
+
+/*
+ * runs counting_work until the storage crashes at write number `at`, if it
+ * gets that far. Returns the last count every task sent.
+ */
+#[cfg(test)]
+fn counts_before_crash(faults: &faulty::FaultInjector, at: uint) -> Vec<u64> {
+	faults.crash_at_write(at);
+	let (tx, rx) = channel();
+	counting_work(logged_manager(faults, 64), tx);
+	let mut counts = Vec::from_elem(COUNTING_TASKS as uint, 0_u64);
+	// ends once every task is done, crashed ones included
+	for (worker, count) in rx.iter() {
+		*counts.get_mut(worker as uint) = count;
+	}
+	faults.crash();
+	counts
+}
+
+#[test]
+fn concurrent_transactions_survive_crashes() {
+	let writes = {
+		let faults = faulty::FaultInjector::new();
+		// there is no write 0, so nothing crashes
+		counts_before_crash(&faults, 0);
+		faults.writes()
+	};
+	let points = crash_points(6, |_, random| random % writes + 1);
+	for &at in points.iter() {
+		for &torn in [false, true].iter() {
+			let faults = faulty::FaultInjector::new();
+			if torn {
+				faults.tear_write(at, TORN_BYTES);
+			}
+			let sent = counts_before_crash(&faults, at);
+			let manager = logged_manager(&faults, 64);
+			let mut sum = 0;
+			for worker in range(0, COUNTING_TASKS) {
+				let guard = manager.fix_page(manager.join_segment(1, worker)).unwrap();
+				let frame = guard.read();
+				let count = read_count(frame.get_data());
+				let mut expected = Vec::from_elem(manager.page_size(), 0_u8);
+				for i in range(1, count + 1) {
+					count_page(expected.as_mut_slice(), i);
+				}
+				// a commit that was durable before the crash might not have been sent yet
+				let last_sent = *sent.get(worker as uint);
+				if (count != last_sent && count != last_sent + 1) || frame.get_data() != expected.as_slice() {
+					fail!("Page of task {} went wrong, {} commits were sent and {} recovered after crashing \
+						at write {} (torn: {}), run again with CRASH_AT={}", worker, last_sent, count,
+						at, torn, at);
+				}
+				sum += count;
+			}
+			let guard = manager.fix_page(manager.join_segment(1, COUNTING_TASKS)).unwrap();
+			assert_eq!(read_count(guard.read().get_data()), sum);
+		}
+	}
+}
+
+#[test]
+fn undoes_unfinished_transactions() {
+	let faults = faulty::FaultInjector::new();
+	let page_id = buffer::Geometry::default().join_segment(1, 0);
+	let change = |offset: uint, value: u8| {
+		PageUpdate {page_id: page_id, offset: offset, before: vec!(0_u8), after: vec!(value)}
+	};
+	{
+		let log = wal::Log::open_segment(~faults.storage() as ~Storage:Send, TEST_LOG_SEGMENT).unwrap();
+		let begin = log.append(&Begin(1));
+		let update = log.append(&Update(1, begin, change(0, 7)));
+		log.append(&Commit(1, update));
+		// never committed
+		let begin = log.append(&Begin(2));
+		let update = log.append(&Update(2, begin, change(1, 9)));
+		log.flush(update).unwrap();
+	}
+
+	// the second time around it is already aborted and stays that way
+	for _ in range(0, 2) {
+		let manager = logged_manager(&faults, 16);
+		let guard = manager.fix_page(page_id).unwrap();
+		assert_eq!(guard.read().get_data().slice_to(2), [7_u8, 0].as_slice());
+		let records = manager.log().unwrap().records().unwrap();
+		let aborts = records.iter().filter(|&&(_, ref record)| match *record {
+			Abort(2, _) => true,
+			_ => false,
+		}).count();
+		assert_eq!(aborts, 1);
+	}
+}
+
+#[test]
+fn rebuilds_torn_pages() {
+	let faults = faulty::FaultInjector::new();
+	let page_id = buffer::Geometry::default().join_segment(1, 0);
+	{
+		let manager = logged_manager(&faults, 16);
+		for &(offset, value) in [(0_u, 1_u8), (2000, 2)].iter() {
+			manager.begin();
+			manager.fix_page(page_id).unwrap().write().get_mut_data()[offset] = value;
+			manager.commit().unwrap();
+			if offset == 0 {
+				manager.flush_all().unwrap();
+			}
+		}
+		// the next write is the page, only its start reaches the disk
+		faults.tear_write(1, TORN_BYTES);
+		faults.crash_at_write(1);
+		assert!(manager.flush_all().is_err());
+	}
+
+	let manager = BufferManager::with_storage(16, ~faults.storage() as ~Storage:Send,
+		replacement::LRU).unwrap();
+	match manager.fix_page(page_id) {
+		Err(Corrupted(torn)) => assert_eq!(torn, page_id),
+		_ => fail!("The page was not torn"),
+	}
+	drop(manager);
+	let manager = logged_manager(&faults, 16);
+	let guard = manager.fix_page(page_id).unwrap();
+	let frame = guard.read();
+	assert_eq!((frame.get_data()[0], frame.get_data()[2000]), (1_u8, 2_u8));
+}
+
+#[test]
+fn recovers_directory() {
+	use std::cast;
+	use std::io::TempDir;
+
+	let dir = match TempDir::new("recovery") {
+		Some(temp_dir) => temp_dir,
+		None => fail!("creation of temporary directory"),
+	};
+	let record = schema::Record::new(vec!(1, 2, 3));
+	let tid = {
+		let manager = Arc::new(BufferManager::new_logged(16, dir.path().clone(),
+			replacement::LRU).unwrap());
+		let mut seg = schema::SPSegment::new(1, manager.clone());
+		let tid = seg.insert(&record).unwrap();
+		drop(seg);
+		// killed before anything but the log reached the disk
+		unsafe {
+			cast::forget(manager);
+		}
+		tid
+	};
+
+	let manager = BufferManager::new_logged(16, dir.path().clone(), replacement::LRU).unwrap();
+	let seg = schema::SPSegment::new(1, Arc::new(manager));
+	assert_eq!(seg.lookup(tid), record);
+}
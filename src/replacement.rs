@@ -0,0 +1,426 @@
+use collections::{HashMap, HashSet};
+use std::ascii::StrAsciiExt;
+use std::from_str::FromStr;
+use std::num::Zero;
+use rand::task_rng;
+use rand::distributions::{IndependentSample, Range};
+use rand::distributions::range::SampleRange;
+
+/*
+ * The page replacement strategies the buffer manager knows about. Pick one
+ * when creating the BufferManager.
+ */
+#[deriving(Eq, Clone, Show)]
+pub enum Policy {
+	/* evict any unfixed page, the old behaviour */
+	Random,
+	/* evict the page that was not used for the longest time */
+	LRU,
+	/* second chance approximation of LRU */
+	Clock,
+	/* simplified 2Q, keeps pages that were only touched once away from hot pages */
+	TwoQ,
+}
+
+impl Policy {
+	/* creates a fresh policy for a buffer with `capacity` frames */
+	pub fn instantiate(&self, capacity: uint) -> ~ReplacementPolicy:Send {
+		match *self {
+			Random => ~RandomPolicy::new() as ~ReplacementPolicy:Send,
+			LRU => ~LRUPolicy::new() as ~ReplacementPolicy:Send,
+			Clock => ~ClockPolicy::new() as ~ReplacementPolicy:Send,
+			TwoQ => ~TwoQPolicy::new(capacity) as ~ReplacementPolicy:Send,
+		}
+	}
+}
+
+/* so the policy can be picked via environment variables in the tests */
+impl FromStr for Policy {
+	fn from_str(s: &str) -> Option<Policy> {
+		match s.to_ascii_lower().as_slice() {
+			"random" => Some(Random),
+			"lru" => Some(LRU),
+			"clock" => Some(Clock),
+			"2q" | "twoq" => Some(TwoQ),
+			_ => None,
+		}
+	}
+}
+
+/*
+ * The buffer manager tells the policy about every page that enters, gets
+ * used or leaves the buffer and asks it for a victim when it runs full.
+ */
+pub trait ReplacementPolicy {
+	/* a page was loaded into a frame */
+	fn admitted(&mut self, page_id: u64);
+	/* a page that was already in the buffer got fixed again */
+	fn accessed(&mut self, page_id: u64);
+	/* a page left the buffer */
+	fn removed(&mut self, page_id: u64);
+	/*
+	 * picks the page that should be evicted next. `evictable` returns
+	 * whether a page may be evicted at all, fixed pages may not.
+	 */
+	fn victim(&mut self, evictable: |u64| -> bool) -> Option<u64>;
+}
+
+pub struct RandomPolicy {
+	pages: HashSet<u64>,
+}
+
+impl RandomPolicy {
+	pub fn new() -> RandomPolicy {
+		RandomPolicy {pages: HashSet::new()}
+	}
+}
+
+impl ReplacementPolicy for RandomPolicy {
+	fn admitted(&mut self, page_id: u64) {
+		self.pages.insert(page_id);
+	}
+
+	fn accessed(&mut self, _: u64) {
+	}
+
+	fn removed(&mut self, page_id: u64) {
+		self.pages.remove(&page_id);
+	}
+
+	fn victim(&mut self, evictable: |u64| -> bool) -> Option<u64> {
+		let mut candidates = self.pages.iter().map(|k| *k).filter(|k| evictable(*k));
+		sample(&mut candidates)
+	}
+}
+
+/*
+ * A doubly linked list of pages. The links live in a map from the page, so
+ * a page can be found, unlinked or moved to the back in O(1).
+ */
+struct PageList {
+	links: HashMap<u64, Link>,
+	head: Option<u64>,
+	tail: Option<u64>,
+}
+
+struct Link {
+	prev: Option<u64>,
+	next: Option<u64>,
+}
+
+impl PageList {
+	fn new() -> PageList {
+		PageList {links: HashMap::new(), head: None, tail: None}
+	}
+
+	fn len(&self) -> uint {
+		self.links.len()
+	}
+
+	fn front(&self) -> Option<u64> {
+		self.head
+	}
+
+	/* the page after this one, None at the back */
+	fn next(&self, page_id: u64) -> Option<u64> {
+		self.links.find(&page_id).and_then(|link| link.next)
+	}
+
+	fn push_back(&mut self, page_id: u64) {
+		self.links.insert(page_id, Link {prev: self.tail, next: None});
+		match self.tail {
+			Some(tail) => self.links.get_mut(&tail).next = Some(page_id),
+			None => self.head = Some(page_id),
+		}
+		self.tail = Some(page_id);
+	}
+
+	/* returns whether the page was in the list */
+	fn remove(&mut self, page_id: u64) -> bool {
+		let link = match self.links.pop(&page_id) {
+			Some(link) => link,
+			None => return false,
+		};
+		match link.prev {
+			Some(prev) => self.links.get_mut(&prev).next = link.next,
+			None => self.head = link.next,
+		}
+		match link.next {
+			Some(next) => self.links.get_mut(&next).prev = link.prev,
+			None => self.tail = link.prev,
+		}
+		true
+	}
+
+	fn pop_front(&mut self) -> Option<u64> {
+		match self.head {
+			Some(page_id) => {
+				self.remove(page_id);
+				Some(page_id)
+			},
+			None => None,
+		}
+	}
+
+	/* the first page from the front that may be evicted */
+	fn first_evictable(&self, evictable: |u64| -> bool) -> Option<u64> {
+		let mut page = self.head;
+		loop {
+			match page {
+				Some(page_id) if evictable(page_id) => return Some(page_id),
+				Some(page_id) => page = self.next(page_id),
+				None => return None,
+			}
+		}
+	}
+}
+
+pub struct LRUPolicy {
+	/* least recently used first */
+	pages: PageList,
+}
+
+impl LRUPolicy {
+	pub fn new() -> LRUPolicy {
+		LRUPolicy {pages: PageList::new()}
+	}
+
+	fn touch(&mut self, page_id: u64) {
+		self.pages.remove(page_id);
+		self.pages.push_back(page_id);
+	}
+}
+
+impl ReplacementPolicy for LRUPolicy {
+	fn admitted(&mut self, page_id: u64) {
+		self.touch(page_id);
+	}
+
+	fn accessed(&mut self, page_id: u64) {
+		self.touch(page_id);
+	}
+
+	fn removed(&mut self, page_id: u64) {
+		self.pages.remove(page_id);
+	}
+
+	fn victim(&mut self, evictable: |u64| -> bool) -> Option<u64> {
+		self.pages.first_evictable(evictable)
+	}
+}
+
+pub struct ClockPolicy {
+	/* the pages arranged in a circle, the back is followed by the front */
+	frames: PageList,
+	referenced: HashMap<u64, bool>,
+	/* the page the hand points at, None for the front */
+	hand: Option<u64>,
+}
+
+impl ClockPolicy {
+	pub fn new() -> ClockPolicy {
+		ClockPolicy {frames: PageList::new(), referenced: HashMap::new(), hand: None}
+	}
+}
+
+impl ReplacementPolicy for ClockPolicy {
+	fn admitted(&mut self, page_id: u64) {
+		self.frames.push_back(page_id);
+		self.referenced.insert(page_id, true);
+	}
+
+	fn accessed(&mut self, page_id: u64) {
+		self.referenced.insert(page_id, true);
+	}
+
+	fn removed(&mut self, page_id: u64) {
+		// keep the hand pointing at the same place in the circle
+		if self.hand == Some(page_id) {
+			self.hand = self.frames.next(page_id);
+		}
+		if self.frames.remove(page_id) {
+			self.referenced.remove(&page_id);
+		}
+	}
+
+	fn victim(&mut self, evictable: |u64| -> bool) -> Option<u64> {
+		let n = self.frames.len();
+		// two rounds: the first one might only clear reference bits
+		for _ in range(0, 2 * n) {
+			let page_id = match self.hand.or(self.frames.front()) {
+				Some(page_id) => page_id,
+				None => return None,
+			};
+			self.hand = self.frames.next(page_id);
+			if !evictable(page_id) {
+				continue;
+			}
+			let referenced = self.referenced.get_mut(&page_id);
+			if *referenced {
+				// give it a second chance
+				*referenced = false;
+			} else {
+				return Some(page_id);
+			}
+		}
+		None
+	}
+}
+
+/*
+ * Simplified 2Q (Johnson & Shasha): pages seen for the first time go into a
+ * FIFO queue, only pages that get requested again after they have been
+ * evicted from there make it into the LRU managed main queue. This keeps scans
+ * from flushing out hot pages like B-tree roots.
+ */
+pub struct TwoQPolicy {
+	/* FIFO of pages that were referenced once, oldest first */
+	a1in: PageList,
+	/* LRU of hot pages, most recently used last */
+	am: PageList,
+	/* ids of pages recently evicted from a1in, not buffered anymore */
+	a1out: PageList,
+	kin: uint,
+	kout: uint,
+}
+
+impl TwoQPolicy {
+	pub fn new(capacity: uint) -> TwoQPolicy {
+		TwoQPolicy {
+			a1in: PageList::new(),
+			am: PageList::new(),
+			a1out: PageList::new(),
+			// the sizes recommended in the paper
+			kin: if capacity / 4 > 0 {capacity / 4} else {1},
+			kout: if capacity / 2 > 0 {capacity / 2} else {1},
+		}
+	}
+}
+
+impl ReplacementPolicy for TwoQPolicy {
+	fn admitted(&mut self, page_id: u64) {
+		if self.a1out.remove(page_id) {
+			// seen before, so this one is hot
+			self.am.push_back(page_id);
+		} else {
+			self.a1in.push_back(page_id);
+		}
+	}
+
+	fn accessed(&mut self, page_id: u64) {
+		// accesses to pages in a1in are deliberately ignored
+		if self.am.remove(page_id) {
+			self.am.push_back(page_id);
+		}
+	}
+
+	fn removed(&mut self, page_id: u64) {
+		if self.a1in.remove(page_id) {
+			// remember it, so we can recognize it when it comes back
+			self.a1out.push_back(page_id);
+			if self.a1out.len() > self.kout {
+				self.a1out.pop_front();
+			}
+			return;
+		}
+		self.am.remove(page_id);
+	}
+
+	fn victim(&mut self, evictable: |u64| -> bool) -> Option<u64> {
+		let (first, second) = if self.a1in.len() > self.kin {
+			(&self.a1in, &self.am)
+		} else {
+			(&self.am, &self.a1in)
+		};
+		// fall back to the other queue if everything in the first one is fixed
+		match first.first_evictable(|p| evictable(p)) {
+			Some(page_id) => Some(page_id),
+			None => second.first_evictable(|p| evictable(p)),
+		}
+	}
+}
+
+pub fn sample<'a, T, I:Iterator<T>>(from: &'a mut I) -> Option<T> {
+	let from: ~[T] = from.collect();
+	let l = from.len();
+	if l == 0 {
+		return None;
+	}
+	let index = randrange(l);
+	Some(from[index])
+}
+
+pub fn randrange<X: SampleRange + Ord + Zero>(high: X) -> X {
+	let between: Range<X> = Range::new(Zero::zero(), high);
+	let mut rng = task_rng();
+	between.ind_sample(&mut rng)
+}
+
+/* feeds the same access pattern into a policy, returns the evicted pages */
+#[cfg(test)]
+fn evictions<P: ReplacementPolicy>(policy: &mut P, fixed: u64) -> Vec<u64> {
+	let mut evicted = Vec::new();
+	for page_id in range(0_u64, 4) {
+		policy.admitted(page_id);
+	}
+	// make 0 and 1 hot
+	policy.accessed(0);
+	policy.accessed(1);
+	for _ in range(0, 2) {
+		let victim = policy.victim(|p| p != fixed).unwrap();
+		policy.removed(victim);
+		evicted.push(victim);
+	}
+	evicted
+}
+
+#[test]
+fn lru_evicts_least_recently_used() {
+	let mut policy = LRUPolicy::new();
+	assert_eq!(evictions(&mut policy, 42), vec!(2, 3));
+	// fixed pages are skipped
+	let mut policy = LRUPolicy::new();
+	assert_eq!(evictions(&mut policy, 2), vec!(3, 0));
+}
+
+#[test]
+fn clock_gives_second_chance() {
+	let mut policy = ClockPolicy::new();
+	// all were referenced on admission, so one full sweep clears them
+	assert_eq!(evictions(&mut policy, 42), vec!(0, 1));
+	let mut policy = ClockPolicy::new();
+	assert_eq!(evictions(&mut policy, 0), vec!(1, 2));
+}
+
+#[test]
+fn twoq_protects_hot_pages() {
+	let mut policy = TwoQPolicy::new(4);
+	assert_eq!(evictions(&mut policy, 42), vec!(0, 1));
+	// 0 got evicted from a1in, so when it comes back it is hot
+	policy.admitted(0);
+	policy.admitted(5);
+	policy.admitted(6);
+	let victim = policy.victim(|_| true).unwrap();
+	assert!(victim != 0);
+}
+
+#[test]
+fn page_lists() {
+	let mut list = PageList::new();
+	for page_id in range(0_u64, 4) {
+		list.push_back(page_id);
+	}
+	assert!(list.remove(2));
+	assert!(!list.remove(2));
+	list.push_back(0);
+	assert_eq!(list.len(), 4);
+	assert_eq!(list.front(), Some(1));
+	assert_eq!(list.next(1), Some(3));
+	assert_eq!(list.next(0), None);
+	assert_eq!(list.first_evictable(|p| p != 1), Some(3));
+	assert_eq!(list.pop_front(), Some(1));
+	assert_eq!(list.pop_front(), Some(3));
+	assert_eq!(list.pop_front(), Some(0));
+	assert_eq!(list.pop_front(), None);
+	assert_eq!(list.first_evictable(|_| true), None);
+}
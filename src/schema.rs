@@ -3,10 +3,13 @@ use std::io::{IoResult, IoError, InvalidInput, SeekStyle, BufWriter, BufReader,
 use std::io::{SeekSet, SeekEnd, SeekCur};
 use std::mem::size_of;
 use std::fmt::{Formatter, Result, Show};
-use sync::{Arc, RWLock};
+use sync::Arc;
 use serialize::ebml::{reader,writer};
 use serialize::{Encodable, Decodable};
 use buffer;
+use freespace;
+use replacement;
+use faulty;
 
 #[deriving(Encodable, Decodable, Clone, Eq, TotalEq, Hash, Show)]
 pub enum SqlType {
@@ -73,6 +76,15 @@ impl Column {
 	pub fn get(&self, seg: &mut SPSegment, index: uint) -> Record {
 		seg.lookup(*self.tids.get(index))
 	}
+
+	pub fn datatype(&self) -> SqlType {
+		self.datatype
+	}
+
+	/* where the values of the column are, in the order they were inserted */
+	pub fn tids<'a>(&'a self) -> &'a [TID] {
+		self.tids.as_slice()
+	}
 }
 
 #[deriving(Encodable, Decodable, Clone)]
@@ -121,43 +133,45 @@ struct Schema {
 }
 
 struct SchemaWriter<'a> {
-	buffer_manager: &'a mut buffer::BufferManager,
+	buffer_manager: &'a buffer::BufferManager,
 	location: u64,
 	maximum: u64,
 }
 
 impl<'a> Writer for SchemaWriter<'a> {
 	fn write(&mut self, buf: &[u8]) -> IoResult<()> {
-		let pageno = self.location / buffer::PAGE_SIZE as u64 + 1;
-		let start_from = (self.location % buffer::PAGE_SIZE as u64) as uint;
+		let page_size = self.buffer_manager.page_size();
+		let pageno = self.location / page_size as u64 + 1;
+		let start_from = (self.location % page_size as u64) as uint;
 
-		let pagelock = self.buffer_manager.fix_page(pageno).unwrap_or_else(|| fail!("Fix page failed"));
 		let mut copied = 0;
 		{
+			let pagelock = match self.buffer_manager.fix_page(pageno) {
+				Ok(p) => p,
+				Err(e) => return Err(e.to_io_error()),
+			};
 			let mut page = pagelock.write();
 			let content = page.get_mut_data();
 
-			for i in range(0, min(buf.len(), buffer::PAGE_SIZE)) {
+			for i in range(0, min(buf.len(), page_size)) {
 				content[start_from+i] = buf[i];
 				copied += 1;
 				self.location += 1;
 			}
 		}
-		self.buffer_manager.unfix_page(pagelock, true);
 		if self.location > self.maximum {
 			self.maximum = self.location;
-			let pagelock = self.buffer_manager.fix_page(0).unwrap_or_else(
-				|| fail!("Fixing zero page failed"));
-			{
-				let mut page = pagelock.write();
-				let content = page.get_mut_data();
-				let mut writer = BufWriter::new(content);
-				match writer.write_le_u64(self.maximum) {
-					Ok(_) => (),
-					Err(e) => fail!("Failed writing length to page: {}", e)
-				};
-			}
-			self.buffer_manager.unfix_page(pagelock, true);
+			let pagelock = match self.buffer_manager.fix_page(0) {
+				Ok(p) => p,
+				Err(e) => return Err(e.to_io_error()),
+			};
+			let mut page = pagelock.write();
+			let content = page.get_mut_data();
+			let mut writer = BufWriter::new(content);
+			match writer.write_le_u64(self.maximum) {
+				Ok(_) => (),
+				Err(e) => fail!("Failed writing length to page: {}", e)
+			};
 		}
 		//TODO remaining bytes from buf
 		info!("copied {}/{}, location: {}", copied, buf.len(), self.location);
@@ -202,42 +216,37 @@ impl<'a> Seek for SchemaWriter<'a> {
 }
 
 impl<'a> SchemaWriter<'a> {
-	pub fn new<'b>(bufman: &'b mut buffer::BufferManager) -> SchemaWriter<'b> {
+	pub fn new<'b>(bufman: &'b buffer::BufferManager) -> SchemaWriter<'b> {
 		SchemaWriter {buffer_manager: bufman, location: 0,
 			maximum: 0}
 	}
 
 	pub fn get_data(&mut self) -> Vec<u8> {
-		let pagelock = self.buffer_manager.fix_page(0).unwrap_or_else(
-			|| fail!("Failed fixing 0 page for schema length"));
-		let mut size;
-		{
+		let size = {
+			let pagelock = self.buffer_manager.fix_page(0).unwrap_or_else(
+				|e| fail!("Failed fixing 0 page for schema length: {}", e));
 			let page = pagelock.read();
 			let mut reader = BufReader::new(page.get_data());
-			size = reader.read_le_u64().unwrap();
-		}
-		self.buffer_manager.unfix_page(pagelock, false);
+			reader.read_le_u64().unwrap()
+		};
 		debug!("Size: {}", size);
 
 		let mut data: Vec<u8> = Vec::with_capacity(size as uint);
 		let mut read = 0;
 
 		debug!("location: {}", self.location);
-		for i in range(1, self.location / buffer::PAGE_SIZE as u64 + 2) {
+		for i in range(1, self.location / self.buffer_manager.page_size() as u64 + 2) {
 			debug!("Reading page {}", i);
 			let pagelock = self.buffer_manager.fix_page(i).unwrap_or_else(
-				|| fail!("Failed fixing page {}", i));
-			{
-				let page = pagelock.read();
-				let content = page.get_data();
-				let mut j = 0;
-				while read < size {
-					data.push(content[j]);
-					j += 1;
-					read += 1;
-				}
+				|e| fail!("Failed fixing page {}: {}", i, e));
+			let page = pagelock.read();
+			let content = page.get_data();
+			let mut j = 0;
+			while read < size {
+				data.push(content[j]);
+				j += 1;
+				read += 1;
 			}
-			self.buffer_manager.unfix_page(pagelock, false);
 		}
 		data
 	}
@@ -248,7 +257,7 @@ impl Schema {
 		Schema {relations: Vec::new()}
 	}
 
-	pub fn new_from_disk(bufmanager: &mut buffer::BufferManager) -> Schema {
+	pub fn new_from_disk(bufmanager: &buffer::BufferManager) -> Schema {
 		let mut wr = SchemaWriter::new(bufmanager);
 		let data = wr.get_data();
 		let ebml_doc = reader::Doc(data.as_slice());
@@ -264,12 +273,23 @@ impl Schema {
 		self.relations.push(relation);
 	}
 
-	pub fn save_to_disk(&self, bufmanager: &mut buffer::BufferManager) {
-		let mut wr = SchemaWriter::new(bufmanager);
-		{
+	pub fn save_to_disk(&self, bufmanager: &buffer::BufferManager) -> buffer::BufferResult<()> {
+		bufmanager.begin();
+		let encoded = {
+			let mut wr = SchemaWriter::new(bufmanager);
 			let mut ebml_w = writer::Encoder(&mut wr);
-			let _ = self.encode(&mut ebml_w);
+			self.encode(&mut ebml_w)
+		};
+		match encoded {
+			Ok(()) => try!(bufmanager.commit()),
+			Err(e) => {
+				// whatever made it to the pages is incomplete
+				try!(bufmanager.abort());
+				return Err(buffer::IoFailed(e));
+			},
 		}
+		// the schema lives in segment 0, make sure it is durable
+		bufmanager.flush_segment(0)
 	}
 }
 
@@ -319,7 +339,13 @@ impl Record {
 
 pub struct SPSegment {
 	id: u64,
-	manager: Arc<RWLock<buffer::BufferManager>>,
+	manager: Arc<buffer::BufferManager>,
+	free_space: freespace::FreeSpaceMap,
+	/*
+	 * the page that had room last, inserts try it before allocating a new
+	 * one. None until the first insert looked through the pages there are.
+	 */
+	insert_hint: Option<u64>,
 }
 
 struct SlottedPageHeader {
@@ -393,13 +419,14 @@ impl Slot {
 
 struct SlottedPage {
 	header: SlottedPageHeader,
-	frame: Arc<RWLock<buffer::BufferFrame>>,
+	/* the page stays fixed as long as the slotted page exists */
+	frame: buffer::PageGuard,
 }
 
 impl SlottedPage {
-	pub fn new(frame: Arc<RWLock<buffer::BufferFrame>>) -> SlottedPage {
+	pub fn new(frame: buffer::PageGuard) -> SlottedPage {
 		let header = {
-			let mut frame = frame.write();
+			let frame = frame.read();
 			let mut br = BufReader::new(frame.get_data());
 			let slot_count = br.read_le_uint().unwrap();
 			let free_slot = br.read_le_uint().unwrap();
@@ -410,8 +437,8 @@ impl SlottedPage {
 				// blank frame, construct header
 				let slot_count = 0;
 				let free_slot = 0;
-				let data_start = buffer::PAGE_SIZE;
-				let free_space = buffer::PAGE_SIZE - size_of::<SlottedPageHeader>();
+				let data_start = frame.get_data().len();
+				let free_space = data_start - size_of::<SlottedPageHeader>();
 				SlottedPageHeader {slot_count: slot_count,
 					free_slot: free_slot, data_start: data_start,
 					free_space: free_space}
@@ -439,11 +466,12 @@ impl SlottedPage {
 		}
 	}
 
-	fn try_insert(&mut self, r: &Record) -> (bool, uint) {
+	/* returns the slot the record was stored in, if it fits */
+	fn try_insert(&mut self, r: &Record) -> Option<uint> {
 		info!("s.h.free_space {}", self.header.free_space);
 		let record_len = r.len();
 		if self.header.free_space < record_len + size_of::<Slot>() {
-			return (false, 0)
+			return None
 		}
 		// adjust the new start of data to be more to the frone
 		self.header.data_start -= record_len;
@@ -467,7 +495,7 @@ impl SlottedPage {
 					e),
 			}
 		}
-		let res = (true, self.header.free_slot);
+		let res = Some(self.header.free_slot);
 		self.header.free_slot += 1;
 		self.header.slot_count += 1;
 
@@ -475,14 +503,14 @@ impl SlottedPage {
 		res
 	}
 
-	fn lookup(&self, slot_id: uint) -> (bool, LookupResult) {
+	fn lookup(&self, slot_id: uint) -> LookupResult {
 		let slot = self.read_slot(slot_id);
 		let frame = self.frame.read();
 		let mut br = BufReader::new(frame.get_data());
 
 		if slot.is_tid() {
 			// the slot contains a TID, not an (offset, len)
-			return (false, Indirect(slot.as_tid()))
+			return Indirect(slot.as_tid())
 		}
 
 		// jump to that offset
@@ -499,10 +527,10 @@ impl SlottedPage {
 		};
 		// construct and return a record from that data
 		let v = Vec::from_slice(content);
-		(false, Direct(Record::new(v)))
+		Direct(Record::new(v))
 	}
 
-	fn update(&self, tid_to_update: TID, new_tid: TID) -> (bool, UpdateResult) {
+	fn update(&self, tid_to_update: TID, new_tid: TID) -> UpdateResult {
 		let slot_id = tid_to_update.slot_id();
 		let slot = self.read_slot(slot_id);
 		let new_slot = Slot::new_from_tid(new_tid);
@@ -512,10 +540,10 @@ impl SlottedPage {
 		if slot.is_tid() {
 			// the old slot contained a TID which is not referenced
 			// anymore, so we have to delete it.
-			(true, DeleteOld(slot.as_tid()))
+			DeleteOld(slot.as_tid())
 		} else {
 			// if it used to contain an (offset, len) we are done directly
-			(true, UpdateDone)
+			UpdateDone
 		}
 	}
 
@@ -552,7 +580,20 @@ impl SlottedPage {
 		}
 	}
 
-	fn remove(&mut self, slot_id: uint) -> (bool, DeleteResult) {
+	/* no slot is in use anymore */
+	fn is_empty(&self) -> bool {
+		self.header.slot_count == 0
+	}
+
+	/* blanks the page, so the next user gets a fresh header */
+	fn clear(&mut self) {
+		let mut frame = self.frame.write();
+		for b in frame.get_mut_data().mut_iter() {
+			*b = 0;
+		}
+	}
+
+	fn remove(&mut self, slot_id: uint) -> DeleteResult {
 		let slot = self.read_slot(slot_id);
 		info!("Removing slot_id {}, {:?}, is_tid? {}", slot_id, slot, slot.is_tid());
 		// zero out the slot
@@ -564,10 +605,10 @@ impl SlottedPage {
 		if slot.is_tid() {
 			// this entry linked to another TID, tell the called to remove
 			// it as well
-			(true, DeleteCascade(slot.as_tid()))
+			DeleteCascade(slot.as_tid())
 		} else {
 			// this was a leaf node, we're done deleting
-			(true, DeleteDone)
+			DeleteDone
 		}
 	}
 }
@@ -591,11 +632,16 @@ impl TID {
 		TID(res)
 	}
 
-	fn new_from_u64(num: u64) -> TID {
+	pub fn new_from_u64(num: u64) -> TID {
 		assert!(num < 1<<48);
 		TID(num)
 	}
 
+	pub fn as_u64(&self) -> u64 {
+		let &TID(n) = self;
+		n
+	}
+
 	fn page_id(&self) -> u64 {
 		let &TID(n) = self;
 		n >> 16
@@ -612,64 +658,155 @@ impl TID {
 	}
 }
 
-fn join_segment(segment: u64, page: u64) -> u64{
-	(segment << buffer::PAGE_BITS) | page
-}
-
 impl SPSegment {
-	pub fn new(id: u64, manager: Arc<RWLock<buffer::BufferManager>>) -> SPSegment {
+	pub fn new(id: u64, manager: Arc<buffer::BufferManager>) -> SPSegment {
+		let free_space = freespace::FreeSpaceMap::new(id, manager.clone());
+		match free_space.check() {
+			Ok(()) => (),
+			Err(e) => fail!("Segment {} is no slotted page segment: {}", id, e),
+		}
 		SPSegment {
 			id: id,
 			manager: manager,
+			free_space: free_space,
+			insert_hint: None,
 		}
 	}
 
+	/* every change to the segment is a transaction of its own */
 	pub fn insert(&mut self, r: &Record) -> Option<TID> {
-		for i in range(0, 1<<buffer::PAGE_BITS) {
-			info!("Testing page {} for insertion", i);
-			let mut manager = self.manager.write();
-			let pagelock = match manager.fix_page(join_segment(self.id, i as u64)) {
-				Some(p) => p,
-				None => fail!("Failed aquiring page {}", i),
+		self.manager.begin();
+		let tid = self.insert_record(r);
+		self.commit();
+		tid
+	}
+
+	fn insert_record(&mut self, r: &Record) -> Option<TID> {
+		let found = match self.insert_hint {
+			// it might have been freed since, or its transaction rolled back
+			Some(page) if self.is_allocated(page) => {
+				self.try_insert_into(page, r).map(|slot| (page, slot))
+			},
+			Some(_) => None,
+			None => self.find_room(r),
+		};
+		match found {
+			Some((page, slot)) => {
+				self.insert_hint = Some(page);
+				return Some(TID::new(page, slot));
+			},
+			None => (),
+		}
+		let page = match self.free_space.allocate() {
+			Ok(page) => page,
+			// the segment is full, whoa!
+			Err(buffer::SegmentFull(_)) => return None,
+			Err(e) => fail!("Allocating a page failed: {}", e),
+		};
+		match self.try_insert_into(page, r) {
+			Some(slot) => {
+				self.insert_hint = Some(page);
+				Some(TID::new(page, slot))
+			},
+			None => {
+				// doesn't even fit into an empty page
+				self.free_page(page);
+				None
+			},
+		}
+	}
+
+	/* the first of the pages we already have that takes the record */
+	fn find_room(&self, r: &Record) -> Option<(u64, uint)> {
+		let mut from = 0;
+		loop {
+			let page = match self.free_space.next_allocated(from) {
+				Ok(Some(page)) => page,
+				Ok(None) => return None,
+				Err(e) => fail!("Reading the free space map failed: {}", e),
 			};
-			let mut sp = SlottedPage::new(pagelock.clone());
-			let (inserted, slot) = sp.try_insert(r);
-			info!("try_insert: {}", inserted);
-			manager.unfix_page(pagelock, inserted);
-			if inserted {
-				return Some(TID::new(i as u64, slot));
+			match self.try_insert_into(page, r) {
+				Some(slot) => return Some((page, slot)),
+				None => from = page + 1,
 			}
 		}
-		// checked all the pages and didn't find any storage? whoa!
-		None
+	}
+
+	fn is_allocated(&self, page: u64) -> bool {
+		match self.free_space.is_allocated(page) {
+			Ok(allocated) => allocated,
+			Err(e) => fail!("Reading the free space map failed: {}", e),
+		}
+	}
+
+	fn try_insert_into(&self, page: u64, r: &Record) -> Option<uint> {
+		info!("Testing page {} for insertion", page);
+		let pagelock = match self.manager.fix_page(self.manager.join_segment(self.id, page)) {
+			Ok(p) => p,
+			Err(e) => fail!("Failed aquiring page {}: {}", page, e),
+		};
+		let mut sp = SlottedPage::new(pagelock);
+		let inserted = sp.try_insert(r);
+		info!("try_insert: {:?}", inserted);
+		inserted
 	}
 
 	pub fn remove(&mut self, tid: TID) -> bool {
+		self.manager.begin();
+		let removed = self.remove_record(tid);
+		self.commit();
+		removed
+	}
+
+	fn remove_record(&mut self, tid: TID) -> bool {
 		let slot_id = tid.slot_id();
-		match self.with_slotted_page(tid, |mut sp| sp.remove(slot_id)) {
+		let (result, emptied) = self.with_slotted_page(tid, |mut sp| {
+			let result = sp.remove(slot_id);
+			let emptied = sp.is_empty();
+			if emptied {
+				sp.clear();
+			}
+			(result, emptied)
+		});
+		// nothing lives there anymore, so the page can be used for anything
+		if emptied {
+			self.free_page(tid.page_id());
+		} else {
+			// now it has room for the next insert
+			self.insert_hint = Some(tid.page_id());
+		}
+		match result {
 			DeleteDone => true,
-			DeleteCascade(tid) => self.remove(tid),
+			DeleteCascade(tid) => self.remove_record(tid),
+		}
+	}
+
+	fn commit(&self) {
+		match self.manager.commit() {
+			Ok(()) => (),
+			Err(e) => fail!("Committing to the log failed: {}", e),
+		}
+	}
+
+	fn free_page(&self, page: u64) {
+		match self.free_space.free(page) {
+			Ok(()) => (),
+			Err(e) => fail!("Freeing page {} failed: {}", page, e),
 		}
 	}
 
 	/*
-	 * fix a page, create slotted page, call the closure with that slotted
-	 * page and unfix that page
+	 * fix a page, create slotted page and call the closure with that slotted
+	 * page. The page gets unfixed when the slotted page is dropped.
 	 */
-	fn with_slotted_page<T>(&self, tid: TID, f: |SlottedPage| -> (bool, T)) -> T {
+	fn with_slotted_page<T>(&self, tid: TID, f: |SlottedPage| -> T) -> T {
 		let page_id = tid.page_id();
-		let full_page_id = join_segment(self.id, page_id);
-		let mut manager = self.manager.write();
-		let pagelock = match manager.fix_page(full_page_id) {
-			Some(p) => p,
-			None => fail!("Failed looking up page {}", page_id),
-		};
-		let (wrote, result) = {
-			let sp = SlottedPage::new(pagelock.clone());
-			f(sp)
+		let full_page_id = self.manager.join_segment(self.id, page_id);
+		let pagelock = match self.manager.fix_page(full_page_id) {
+			Ok(p) => p,
+			Err(e) => fail!("Failed looking up page {}: {}", page_id, e),
 		};
-		manager.unfix_page(pagelock, wrote);
-		result
+		f(SlottedPage::new(pagelock))
 	}
 
 	pub fn lookup(&self, tid: TID) -> Record {
@@ -690,13 +827,21 @@ impl SPSegment {
 		}
 	}
 
+	/* makes all changes to this segment durable */
+	pub fn flush(&self) -> buffer::BufferResult<()> {
+		self.manager.flush_segment(self.id)
+	}
+
 	pub fn update(&mut self, tid: TID, r: &Record) -> bool {
+		self.manager.begin();
 		// TODO: prepend old tid to record
-		let new_tid = self.insert(r).unwrap();
-		match self.with_slotted_page(tid, |sp| sp.update(tid, new_tid)) {
+		let new_tid = self.insert_record(r).unwrap();
+		let updated = match self.with_slotted_page(tid, |sp| sp.update(tid, new_tid)) {
 			UpdateDone => true,
-			DeleteOld(obsolete_tid) => self.remove(obsolete_tid)
-		}
+			DeleteOld(obsolete_tid) => self.remove_record(obsolete_tid)
+		};
+		self.commit();
+		updated
 	}
 }
 
@@ -718,9 +863,9 @@ fn create_schema() {
 	let mut schema = Schema::new();
 	schema.add_relation(relation);
 
-	let mut manager = buffer::BufferManager::new(1024, p.clone());
-	schema.save_to_disk(&mut manager);
-	let new_schema = Schema::new_from_disk(&mut manager);
+	let manager = buffer::BufferManager::new(1024, p.clone(), replacement::LRU).unwrap();
+	schema.save_to_disk(&manager).unwrap();
+	let new_schema = Schema::new_from_disk(&manager);
 	println!("new_schema == {:?}", new_schema);
 }
 
@@ -734,8 +879,8 @@ fn slotted_page_create() {
 	let p = dir.path();
 	//let p = Path::new(".");
 
-	let mut manager = buffer::BufferManager::new(1024, p.clone());
-	let mut seg = SPSegment::new(1, Arc::new(RWLock::new(manager)));
+	let manager = buffer::BufferManager::new(1024, p.clone(), replacement::LRU).unwrap();
+	let mut seg = SPSegment::new(1, Arc::new(manager));
 
 	let rec = Record::new(vec!(42));
 	let tid = seg.insert(&rec).unwrap();
@@ -768,3 +913,124 @@ fn slotted_page_create() {
 
 	seg.remove(tid);
 }
+
+#[test]
+fn segment_reuses_pages() {
+	let faults = faulty::FaultInjector::new();
+	let mut seg = SPSegment::new(1, faulty_manager(&faults));
+	// page 0 holds the free space map
+	let first = seg.insert(&Record::new(vec!(1))).unwrap();
+	assert_eq!(first, TID::new(1, 0));
+	// too big to share a page with anything
+	let big = Vec::from_elem(3000, 7_u8);
+	let second = seg.insert(&Record::new(big.clone())).unwrap();
+	let third = seg.insert(&Record::new(big.clone())).unwrap();
+	assert_eq!(second, TID::new(1, 1));
+	assert_eq!(third, TID::new(2, 0));
+
+	seg.remove(first);
+	seg.remove(second);
+	// page 1 is empty and free again, so it is used from scratch
+	assert_eq!(seg.insert(&Record::new(big)).unwrap(), TID::new(1, 0));
+	assert_eq!(seg.lookup(third), Record::new(Vec::from_elem(3000, 7_u8)));
+	// records too big for any page are refused
+	assert_eq!(seg.insert(&Record::new(Vec::from_elem(8192, 0_u8))), None);
+}
+
+#[test]
+fn inserts_skip_full_pages() {
+	let faults = faulty::FaultInjector::new();
+	let manager = faulty_manager(&faults);
+	let mut seg = SPSegment::new(1, manager.clone());
+	let big = Vec::from_elem(3000, 7_u8);
+	for _ in range(0, 20) {
+		seg.insert(&Record::new(big.clone())).unwrap();
+	}
+	let stats = manager.stats();
+	let fixes = stats.hits + stats.misses;
+	assert_eq!(seg.insert(&Record::new(big)).unwrap(), TID::new(21, 0));
+	// the map, the last page and the new one, not the twenty full ones
+	let stats = manager.stats();
+	assert!(stats.hits + stats.misses - fixes < 10);
+}
+
+#[cfg(test)]
+fn faulty_manager(faults: &faulty::FaultInjector) -> Arc<buffer::BufferManager> {
+	use storage::Storage;
+
+	let manager = buffer::BufferManager::with_storage(16,
+		~faults.storage() as ~Storage:Send, replacement::LRU).unwrap();
+	Arc::new(manager)
+}
+
+#[test]
+fn segment_survives_crash() {
+	let faults = faulty::FaultInjector::new();
+	let (kept, lost) = {
+		let mut seg = SPSegment::new(1, faulty_manager(&faults));
+		let kept = seg.insert(&Record::new(vec!(1, 2, 3))).unwrap();
+		seg.flush().unwrap();
+		let lost = seg.insert(&Record::new(vec!(4, 5, 6))).unwrap();
+		faults.crash();
+		(kept, lost)
+	};
+
+	let mut seg = SPSegment::new(1, faulty_manager(&faults));
+	assert_eq!(seg.lookup(kept), Record::new(vec!(1, 2, 3)));
+	// the second record never made it, so its slot is free again
+	assert_eq!(seg.insert(&Record::new(vec!(7))).unwrap(), lost);
+}
+
+#[test]
+fn segment_read_failure() {
+	use std::task;
+
+	let faults = faulty::FaultInjector::new();
+	let tid = {
+		let mut seg = SPSegment::new(1, faulty_manager(&faults));
+		let tid = seg.insert(&Record::new(vec!(1, 2, 3))).unwrap();
+		seg.flush().unwrap();
+		tid
+	};
+
+	// a fresh buffer has to read the page
+	let seg = SPSegment::new(1, faulty_manager(&faults));
+	faults.fail_read(1);
+	let result = task::try(proc() {
+		seg.lookup(tid)
+	});
+	assert!(result.is_err());
+}
+
+#[test]
+fn segment_changes_are_logged() {
+	use storage::Storage;
+	use wal;
+
+	let faults = faulty::FaultInjector::new();
+	let log_faults = faulty::FaultInjector::new();
+	let manager = buffer::BufferManager::logged(16, ~faults.storage() as ~Storage:Send,
+		~log_faults.storage() as ~Storage:Send, replacement::LRU).unwrap();
+	let mut seg = SPSegment::new(1, Arc::new(manager));
+	let tid = seg.insert(&Record::new(vec!(1, 2, 3))).unwrap();
+	let page_id = seg.manager.join_segment(1, tid.page_id());
+	seg.remove(tid);
+
+	let records = seg.manager.log().unwrap().records().unwrap();
+	let commits = records.iter().filter(|&&(_, ref r)| match *r {
+		wal::Commit(..) => true,
+		_ => false,
+	}).count();
+	assert_eq!(commits, 2);
+	// the record was written to its page and the free space map changed
+	let inserted = records.iter().any(|&(_, ref r)| match *r {
+		wal::Update(_, _, ref update) => update.page_id == page_id &&
+			update.after.as_slice().windows(3).any(|w| w == [1_u8, 2, 3].as_slice()),
+		_ => false,
+	});
+	assert!(inserted);
+	assert!(records.iter().any(|&(_, ref r)| match *r {
+		wal::Update(_, _, ref update) => update.page_id == seg.manager.join_segment(1, 0),
+		_ => false,
+	}));
+}
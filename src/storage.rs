@@ -0,0 +1,451 @@
+use collections::HashMap;
+use collections::lru_cache::LruCache;
+use std::io::{Open, Read, ReadWrite, IoResult, IoError, TempDir, File};
+use std::io::fs;
+use std::libc;
+use std::ptr;
+use std::cast;
+use std::raw::Slice;
+use native::io::file;
+use native::io::file::FileDesc;
+use std::rt::rtio::RtioFileStream;
+
+/*
+ * Where the buffer manager gets its pages from and puts them back. Pages are
+ * addressed by segment and page number, the size of the buffer decides how
+ * big a page is, so page_no is at byte page_no * buf.len() of the segment.
+ */
+pub trait Storage {
+	/*
+	 * fills buf with the page, returns how many bytes of it existed. Pages
+	 * that were never written read as zeros.
+	 */
+	fn read_page(&mut self, segment: u64, page_no: u64, buf: &mut [u8]) -> IoResult<uint>;
+	fn write_page(&mut self, segment: u64, page_no: u64, buf: &[u8]) -> IoResult<()>;
+	/* makes sure everything written to the segment survives a crash */
+	fn sync(&mut self, segment: u64) -> IoResult<()>;
+	/* deleting a segment that doesn't exist is fine */
+	fn delete_segment(&mut self, segment: u64) -> IoResult<()>;
+	/*
+	 * keeps the first `pages` pages of `page_size` bytes, cutting off or
+	 * adding zeros as needed
+	 */
+	fn truncate(&mut self, segment: u64, pages: u64, page_size: uint) -> IoResult<()>;
+	/* the segments that exist, in no particular order */
+	fn segments(&mut self) -> IoResult<Vec<u64>>;
+
+	/*
+	 * a small blob the buffer manager keeps next to the segments, e.g. the
+	 * page size. None if it was never written.
+	 */
+	fn read_meta(&mut self) -> IoResult<Option<Vec<u8>>>;
+	/* replaces the blob, it has to be durable when this returns */
+	fn write_meta(&mut self, meta: &[u8]) -> IoResult<()>;
+
+	/*
+	 * reads consecutive pages of `page_size` bytes starting at page `first`
+	 * into buf, returns how many bytes existed. Backends that can do this in
+	 * one request should.
+	 */
+	fn read_pages(&mut self, segment: u64, first: u64, page_size: uint, buf: &mut [u8]) -> IoResult<uint> {
+		let mut read = 0;
+		for (i, page) in buf.mut_chunks(page_size).enumerate() {
+			read += try!(self.read_page(segment, first + i as u64, page));
+		}
+		Ok(read)
+	}
+
+	/* how often an already open segment could be used instead of opening it */
+	fn opens_saved(&self) -> u64 {
+		0
+	}
+}
+
+/* how many segment files are kept open if nothing else is said */
+pub static DEFAULT_OPEN_FILES: uint = 32;
+
+/*
+ * every segment is a file in a directory, named after the segment number.
+ * The most recently used files are kept open, the handles get closed when
+ * they drop out of the cache or the storage is dropped.
+ */
+pub struct FileStorage {
+	path: Path,
+	handles: LruCache<u64, FileDesc>,
+	opens_saved: u64,
+}
+
+impl FileStorage {
+	pub fn new(path: Path) -> FileStorage {
+		FileStorage::with_open_files(path, DEFAULT_OPEN_FILES)
+	}
+
+	/* keeps at most `open_files` segment files open at the same time */
+	pub fn with_open_files(path: Path, open_files: uint) -> FileStorage {
+		let open_files = if open_files == 0 {1} else {open_files};
+		FileStorage {path: path, handles: LruCache::new(open_files), opens_saved: 0}
+	}
+
+	/*
+	 * returns the handle for the segment file, creating the file if it
+	 * doesn't exist yet
+	 */
+	fn open<'a>(&'a mut self, segment: u64) -> IoResult<&'a FileDesc> {
+		if self.handles.get(&segment).is_some() {
+			self.opens_saved += 1;
+		} else {
+			let file_path = self.path.join(segment.to_str());
+			let handle = try!(file::open(&file_path.to_c_str(), Open, ReadWrite));
+			debug!("Opened segment {}", segment);
+			self.handles.put(segment, handle);
+		}
+		Ok(self.handles.get(&segment).unwrap())
+	}
+}
+
+impl Storage for FileStorage {
+	fn read_page(&mut self, segment: u64, page_no: u64, buf: &mut [u8]) -> IoResult<uint> {
+		let handle = try!(self.open(segment));
+		// everything behind the end of the file stays zero
+		for b in buf.mut_iter() {
+			*b = 0;
+		}
+		pread_all(handle, buf, page_no * buf.len() as u64)
+	}
+
+	fn read_pages(&mut self, segment: u64, first: u64, page_size: uint, buf: &mut [u8]) -> IoResult<uint> {
+		let handle = try!(self.open(segment));
+		for b in buf.mut_iter() {
+			*b = 0;
+		}
+		pread_all(handle, buf, first * page_size as u64)
+	}
+
+	fn write_page(&mut self, segment: u64, page_no: u64, buf: &[u8]) -> IoResult<()> {
+		let handle = try!(self.open(segment));
+		info!("Writing to segment {}, offset {}", segment, page_no);
+		pwrite_all(handle, buf, page_no * buf.len() as u64)
+	}
+
+	fn sync(&mut self, segment: u64) -> IoResult<()> {
+		try!(self.open(segment));
+		info!("Syncing segment {}", segment);
+		// fsync wants the handle mutable, the cache only lends it out immutably
+		let mut handle = self.handles.pop(&segment).unwrap();
+		let result = handle.fsync();
+		self.handles.put(segment, handle);
+		result
+	}
+
+	fn delete_segment(&mut self, segment: u64) -> IoResult<()> {
+		// closes the file if it was open
+		self.handles.pop(&segment);
+		let file_path = self.path.join(segment.to_str());
+		if !file_path.exists() {
+			return Ok(());
+		}
+		file::unlink(&file_path.to_c_str())
+	}
+
+	fn truncate(&mut self, segment: u64, pages: u64, page_size: uint) -> IoResult<()> {
+		try!(self.open(segment));
+		let mut handle = self.handles.pop(&segment).unwrap();
+		let result = handle.truncate((pages * page_size as u64) as i64);
+		self.handles.put(segment, handle);
+		result
+	}
+
+	fn segments(&mut self) -> IoResult<Vec<u64>> {
+		let mut segments = Vec::new();
+		for path in try!(fs::readdir(&self.path)).iter() {
+			// skips the meta file
+			match path.filename_str().and_then(|name| from_str::<u64>(name)) {
+				Some(segment) => segments.push(segment),
+				None => (),
+			}
+		}
+		Ok(segments)
+	}
+
+	/* the meta blob is the file `meta`, segments only have numbers as names */
+	fn read_meta(&mut self) -> IoResult<Option<Vec<u8>>> {
+		let meta_path = self.path.join("meta");
+		if !meta_path.exists() {
+			return Ok(None);
+		}
+		let mut meta = try!(File::open(&meta_path));
+		Ok(Some(Vec::from_slice(try!(meta.read_to_end()).as_slice())))
+	}
+
+	fn write_meta(&mut self, meta: &[u8]) -> IoResult<()> {
+		// write it next to the old one and swap them, so it is never half written
+		let temp_path = self.path.join("meta.new");
+		{
+			let mut file = try!(File::create(&temp_path));
+			try!(file.write(meta));
+			try!(file.fsync());
+		}
+		fs::rename(&temp_path, &self.path.join("meta"))
+	}
+
+	fn opens_saved(&self) -> u64 {
+		self.opens_saved
+	}
+}
+
+/*
+ * keeps all segments in memory, nothing survives the storage being dropped.
+ * Good for tests that don't care about the disk.
+ */
+pub struct MemoryStorage {
+	segments: HashMap<u64, Vec<u8>>,
+	meta: Option<Vec<u8>>,
+}
+
+impl MemoryStorage {
+	pub fn new() -> MemoryStorage {
+		MemoryStorage {segments: HashMap::new(), meta: None}
+	}
+}
+
+impl Storage for MemoryStorage {
+	fn read_page(&mut self, segment: u64, page_no: u64, buf: &mut [u8]) -> IoResult<uint> {
+		for b in buf.mut_iter() {
+			*b = 0;
+		}
+		let data = match self.segments.find(&segment) {
+			Some(data) => data,
+			None => return Ok(0),
+		};
+		let start = (page_no * buf.len() as u64) as uint;
+		if start >= data.len() {
+			return Ok(0);
+		}
+		Ok(buf.copy_from(data.slice_from(start)))
+	}
+
+	fn write_page(&mut self, segment: u64, page_no: u64, buf: &[u8]) -> IoResult<()> {
+		let data = self.segments.find_or_insert_with(segment, |_| Vec::new());
+		let start = (page_no * buf.len() as u64) as uint;
+		if data.len() < start + buf.len() {
+			data.grow(start + buf.len() - data.len(), &0_u8);
+		}
+		data.mut_slice(start, start + buf.len()).copy_from(buf);
+		Ok(())
+	}
+
+	fn sync(&mut self, _: u64) -> IoResult<()> {
+		Ok(())
+	}
+
+	fn delete_segment(&mut self, segment: u64) -> IoResult<()> {
+		self.segments.remove(&segment);
+		Ok(())
+	}
+
+	fn truncate(&mut self, segment: u64, pages: u64, page_size: uint) -> IoResult<()> {
+		let data = self.segments.find_or_insert_with(segment, |_| Vec::new());
+		resize(data, pages as uint * page_size);
+		Ok(())
+	}
+
+	fn segments(&mut self) -> IoResult<Vec<u64>> {
+		Ok(self.segments.keys().map(|s| *s).collect())
+	}
+
+	fn read_meta(&mut self) -> IoResult<Option<Vec<u8>>> {
+		Ok(self.meta.clone())
+	}
+
+	fn write_meta(&mut self, meta: &[u8]) -> IoResult<()> {
+		self.meta = Some(Vec::from_slice(meta));
+		Ok(())
+	}
+}
+
+/*
+ * a segment file mapped into memory read only, as it was when it got
+ * mapped. The mapping goes away when this is dropped.
+ */
+pub struct MappedSegment {
+	/* kept as a number, so the mapping can be shared between threads */
+	address: uint,
+	len: uint,
+}
+
+impl MappedSegment {
+	/* maps the segment's file in the directory, None if it is empty or missing */
+	pub fn map(path: &Path, segment: u64) -> IoResult<Option<MappedSegment>> {
+		let file_path = path.join(segment.to_str());
+		if !file_path.exists() {
+			return Ok(None);
+		}
+		let len = try!(fs::stat(&file_path)).size as uint;
+		if len == 0 {
+			return Ok(None);
+		}
+		// the mapping stays valid after the file is closed again
+		let handle = try!(file::open(&file_path.to_c_str(), Open, Read));
+		let address = unsafe {
+			libc::mmap(ptr::null(), len as libc::size_t, libc::PROT_READ,
+				libc::MAP_SHARED, handle.fd(), 0)
+		};
+		if address as uint == libc::MAP_FAILED as uint {
+			return Err(IoError::last_error());
+		}
+		debug!("Mapped segment {}, {} bytes", segment, len);
+		Ok(Some(MappedSegment {address: address as uint, len: len}))
+	}
+
+	pub fn len(&self) -> uint {
+		self.len
+	}
+
+	pub fn slice<'a>(&'a self, offset: uint, len: uint) -> &'a [u8] {
+		assert!(offset + len <= self.len);
+		unsafe {
+			cast::transmute(Slice {data: (self.address + offset) as *u8, len: len})
+		}
+	}
+}
+
+impl Drop for MappedSegment {
+	fn drop(&mut self) {
+		unsafe {
+			libc::munmap(self.address as *libc::c_void, self.len as libc::size_t);
+		}
+	}
+}
+
+/* shortens or zero extends a segment kept in memory */
+pub fn resize(data: &mut Vec<u8>, length: uint) {
+	if data.len() > length {
+		data.truncate(length);
+	} else {
+		data.grow(length - data.len(), &0_u8);
+	}
+}
+
+/*
+ * libnative's pwrite reports an error even if the write went through, so
+ * call into libc directly and keep writing until everything is out.
+ */
+fn pwrite_all(handle: &FileDesc, buf: &[u8], offset: u64) -> IoResult<()> {
+	let mut written = 0;
+	while written < buf.len() {
+		let n = unsafe {
+			libc::pwrite(handle.fd(),
+				buf.slice_from(written).as_ptr() as *libc::c_void,
+				(buf.len() - written) as libc::size_t,
+				(offset + written as u64) as libc::off_t)
+		};
+		if n < 0 {
+			return Err(IoError::last_error());
+		}
+		written += n as uint;
+	}
+	Ok(())
+}
+
+/*
+ * reads until the buffer is full or the end of the file is reached, returns
+ * how many bytes were read
+ */
+fn pread_all(handle: &FileDesc, buf: &mut [u8], offset: u64) -> IoResult<uint> {
+	let mut read = 0;
+	while read < buf.len() {
+		let n = unsafe {
+			libc::pread(handle.fd(),
+				buf.mut_slice_from(read).as_mut_ptr() as *mut libc::c_void,
+				(buf.len() - read) as libc::size_t,
+				(offset + read as u64) as libc::off_t)
+		};
+		if n < 0 {
+			return Err(IoError::last_error());
+		}
+		if n == 0 {
+			break;
+		}
+		read += n as uint;
+	}
+	Ok(read)
+}
+
+/* runs the same reads and writes against any backend */
+#[cfg(test)]
+fn roundtrip<S: Storage>(storage: &mut S) {
+	let mut buf = [0_u8, ..16];
+	// nothing there yet
+	assert_eq!(storage.read_page(1, 3, buf).unwrap(), 0);
+	assert!(buf.iter().all(|b| *b == 0));
+
+	storage.write_page(1, 3, [7_u8, ..16]).unwrap();
+	storage.sync(1).unwrap();
+	assert_eq!(storage.read_page(1, 3, buf).unwrap(), 16);
+	assert!(buf.iter().all(|b| *b == 7));
+	// the pages before it are holes
+	assert_eq!(storage.read_page(1, 1, buf).unwrap(), 16);
+	assert!(buf.iter().all(|b| *b == 0));
+	// other segments are separate
+	assert_eq!(storage.read_page(2, 3, buf).unwrap(), 0);
+
+	// pages 2 to 4, only two of them exist
+	let mut run = [1_u8, ..48];
+	assert_eq!(storage.read_pages(1, 2, 16, run).unwrap(), 32);
+	assert!(run.slice_to(16).iter().all(|b| *b == 0));
+	assert!(run.slice(16, 32).iter().all(|b| *b == 7));
+	assert!(run.slice_from(32).iter().all(|b| *b == 0));
+
+	// cut off page 3
+	storage.truncate(1, 3, 16).unwrap();
+	assert_eq!(storage.read_page(1, 3, buf).unwrap(), 0);
+	assert_eq!(storage.read_page(1, 2, buf).unwrap(), 16);
+	// files get created on first read, so there might be more
+	assert!(storage.segments().unwrap().contains(&1));
+
+	storage.delete_segment(1).unwrap();
+	assert!(!storage.segments().unwrap().contains(&1));
+	assert_eq!(storage.read_page(1, 3, buf).unwrap(), 0);
+	storage.delete_segment(1).unwrap();
+	storage.delete_segment(42).unwrap();
+
+	assert_eq!(storage.read_meta().unwrap(), None);
+	storage.write_meta([1, 2, 3]).unwrap();
+	storage.write_meta([4, 5]).unwrap();
+	assert_eq!(storage.read_meta().unwrap(), Some(vec!(4_u8, 5)));
+}
+
+#[test]
+fn file_roundtrip() {
+	let dir = match TempDir::new("storage") {
+		Some(temp_dir) => temp_dir,
+		None => fail!("creation of temporary directory"),
+	};
+	roundtrip(&mut FileStorage::new(dir.path().clone()));
+}
+
+#[test]
+fn file_handles_are_cached() {
+	let dir = match TempDir::new("storage") {
+		Some(temp_dir) => temp_dir,
+		None => fail!("creation of temporary directory"),
+	};
+	let mut storage = FileStorage::with_open_files(dir.path().clone(), 2);
+	let mut buf = [0_u8, ..16];
+	storage.read_page(1, 0, buf).unwrap();
+	storage.write_page(1, 0, buf).unwrap();
+	storage.read_page(2, 0, buf).unwrap();
+	storage.read_page(1, 0, buf).unwrap();
+	assert_eq!(storage.opens_saved(), 2);
+	// 2 is the least recently used one, so it has to be opened again
+	storage.read_page(3, 0, buf).unwrap();
+	storage.read_page(2, 0, buf).unwrap();
+	assert_eq!(storage.opens_saved(), 2);
+	storage.read_page(2, 0, buf).unwrap();
+	assert_eq!(storage.opens_saved(), 3);
+}
+
+#[test]
+fn memory_roundtrip() {
+	roundtrip(&mut MemoryStorage::new());
+}
@@ -0,0 +1,450 @@
+use std::io::{IoResult, BufReader, BufWriter, MemWriter, InvalidInput, standard_error};
+use sync::Mutex;
+use storage::Storage;
+use buffer;
+use buffer::{BufferResult, IoFailed, BadLog};
+use faulty;
+
+/*
+ * The log is written in pages of its own size, independent of the geometry
+ * of the database. 512 bytes is a disk sector, so the last page of a flush
+ * doesn't waste too much.
+ */
+pub static LOG_PAGE_SIZE: uint = 512;
+/* the log keeps all its pages in this segment of its storage, see open */
+static LOG_SEGMENT: u64 = 0;
+/* "NCLG", the first thing in the log */
+static LOG_MAGIC: u32 = 0x4E434C47;
+static LOG_VERSION: u32 = 1;
+/* the first record goes behind magic and version, so LSN 0 means "never logged" */
+static LOG_START: uint = 8;
+/* length and checksum in front of every record */
+static RECORD_HEADER: uint = 8;
+/* how many log pages are read at once */
+static READ_CHUNK: uint = 64;
+
+/* the bytes of a page that changed, as they were before and after */
+#[deriving(Clone, Eq, Show)]
+pub struct PageUpdate {
+	pub page_id: u64,
+	pub offset: uint,
+	pub before: Vec<u8>,
+	pub after: Vec<u8>,
+}
+
+/*
+ * What goes into the log. Every record but Begin carries the LSN of the
+ * previous record of its transaction, so the records of one transaction can
+ * be followed backwards.
+ */
+#[deriving(Clone, Eq, Show)]
+pub enum Record {
+	/* transaction */
+	Begin(u64),
+	/* transaction, previous LSN, the change */
+	Update(u64, u64, PageUpdate),
+	/* transaction, previous LSN */
+	Commit(u64, u64),
+	/*
+	 * undoes an update during recovery. Transaction, previous LSN, the
+	 * change that undoes it and the LSN of the record to undo next
+	 */
+	Compensation(u64, u64, PageUpdate, u64),
+	/* all changes of the transaction were undone, transaction, previous LSN */
+	Abort(u64, u64),
+	/*
+	 * the whole page before the first change since it was last written,
+	 * for redo to start from if that write got torn. Undo skips it.
+	 * Transaction, previous LSN, page, data
+	 */
+	PageImage(u64, u64, u64, Vec<u8>),
+}
+
+impl Record {
+	/* the transaction the record belongs to */
+	pub fn txn(&self) -> u64 {
+		match *self {
+			Begin(txn) | Update(txn, _, _) | Commit(txn, _) |
+				Compensation(txn, _, _, _) | Abort(txn, _) | PageImage(txn, _, _, _) => txn,
+		}
+	}
+
+	fn encode(&self) -> Vec<u8> {
+		let mut writer = MemWriter::new();
+		match write_record(&mut writer, self) {
+			Ok(()) => (),
+			Err(e) => fail!("Encoding log record failed: {}", e),
+		}
+		Vec::from_slice(writer.get_ref())
+	}
+
+	fn decode(data: &[u8]) -> Option<Record> {
+		read_record(&mut BufReader::new(data)).ok()
+	}
+}
+
+fn write_record(writer: &mut MemWriter, record: &Record) -> IoResult<()> {
+	match *record {
+		Begin(txn) => {
+			try!(writer.write_u8(1));
+			writer.write_le_u64(txn)
+		},
+		Update(txn, prev_lsn, ref update) => {
+			try!(writer.write_u8(2));
+			try!(writer.write_le_u64(txn));
+			try!(writer.write_le_u64(prev_lsn));
+			write_update(writer, update)
+		},
+		Commit(txn, prev_lsn) => {
+			try!(writer.write_u8(3));
+			try!(writer.write_le_u64(txn));
+			writer.write_le_u64(prev_lsn)
+		},
+		Compensation(txn, prev_lsn, ref update, undo_next) => {
+			try!(writer.write_u8(4));
+			try!(writer.write_le_u64(txn));
+			try!(writer.write_le_u64(prev_lsn));
+			try!(write_update(writer, update));
+			writer.write_le_u64(undo_next)
+		},
+		Abort(txn, prev_lsn) => {
+			try!(writer.write_u8(5));
+			try!(writer.write_le_u64(txn));
+			writer.write_le_u64(prev_lsn)
+		},
+		PageImage(txn, prev_lsn, page_id, ref data) => {
+			try!(writer.write_u8(6));
+			try!(writer.write_le_u64(txn));
+			try!(writer.write_le_u64(prev_lsn));
+			try!(writer.write_le_u64(page_id));
+			try!(writer.write_le_u32(data.len() as u32));
+			writer.write(data.as_slice())
+		},
+	}
+}
+
+fn write_update(writer: &mut MemWriter, update: &PageUpdate) -> IoResult<()> {
+	assert_eq!(update.before.len(), update.after.len());
+	try!(writer.write_le_u64(update.page_id));
+	try!(writer.write_le_u32(update.offset as u32));
+	try!(writer.write_le_u32(update.before.len() as u32));
+	try!(writer.write(update.before.as_slice()));
+	writer.write(update.after.as_slice())
+}
+
+fn read_record(reader: &mut BufReader) -> IoResult<Record> {
+	let kind = try!(reader.read_u8());
+	let txn = try!(reader.read_le_u64());
+	match kind {
+		1 => Ok(Begin(txn)),
+		2 => {
+			let prev_lsn = try!(reader.read_le_u64());
+			Ok(Update(txn, prev_lsn, try!(read_update(reader))))
+		},
+		3 => Ok(Commit(txn, try!(reader.read_le_u64()))),
+		4 => {
+			let prev_lsn = try!(reader.read_le_u64());
+			let update = try!(read_update(reader));
+			Ok(Compensation(txn, prev_lsn, update, try!(reader.read_le_u64())))
+		},
+		5 => Ok(Abort(txn, try!(reader.read_le_u64()))),
+		6 => {
+			let prev_lsn = try!(reader.read_le_u64());
+			let page_id = try!(reader.read_le_u64());
+			let len = try!(reader.read_le_u32()) as uint;
+			Ok(PageImage(txn, prev_lsn, page_id, Vec::from_slice(try!(reader.read_exact(len)))))
+		},
+		_ => Err(standard_error(InvalidInput)),
+	}
+}
+
+fn read_update(reader: &mut BufReader) -> IoResult<PageUpdate> {
+	let page_id = try!(reader.read_le_u64());
+	let offset = try!(reader.read_le_u32()) as uint;
+	let len = try!(reader.read_le_u32()) as uint;
+	let before = Vec::from_slice(try!(reader.read_exact(len)));
+	let after = Vec::from_slice(try!(reader.read_exact(len)));
+	Ok(PageUpdate {page_id: page_id, offset: offset, before: before, after: after})
+}
+
+/* the checksum covers where the record is, so an old record at another place doesn't count */
+fn record_crc(lsn: u64, payload: &[u8]) -> u32 {
+	let mut position = [0_u8, ..8];
+	BufWriter::new(position).write_le_u64(lsn).unwrap();
+	let crc = buffer::crc32_update(0xFFFFFFFF, position);
+	buffer::crc32_update(crc, payload) ^ 0xFFFFFFFF
+}
+
+/*
+ * An append only log of records, addressed by their LSN, which is the byte
+ * offset of the record in the log. Records are collected in memory and only
+ * reach the storage with a flush, which pads the last page with zeros. The
+ * next record then starts on a fresh page, so pages holding durable records
+ * are never written again and a torn write can only hit records that were
+ * not durable yet.
+ *
+ * A record length of 0 is padding, the log goes on on the next page. The log
+ * ends at a page that starts with padding or at the first record that fails
+ * its checksum.
+ */
+pub struct Log {
+	state: Mutex<LogState>,
+}
+
+struct LogState {
+	storage: ~Storage:Send,
+	segment: u64,
+	/* records that were not flushed yet, starting at a page boundary */
+	pending: Vec<u8>,
+	pending_start: uint,
+	/* every record below this is durable */
+	flushed: uint,
+	next_txn: u64,
+}
+
+impl LogState {
+	/* the LSN the next record gets */
+	fn end(&self) -> uint {
+		self.pending_start + self.pending.len()
+	}
+}
+
+impl Log {
+	/*
+	 * opens the log in the storage, an empty storage gets a new log.
+	 * Whatever follows the last intact record is cut off, so new records
+	 * don't end up behind garbage.
+	 */
+	pub fn open(storage: ~Storage:Send) -> BufferResult<Log> {
+		Log::open_segment(storage, LOG_SEGMENT)
+	}
+
+	/*
+	 * like open, but the log lives in `segment` of the storage. Then it can
+	 * share the storage with data that keeps out of that segment.
+	 */
+	pub fn open_segment(storage: ~Storage:Send, segment: u64) -> BufferResult<Log> {
+		let mut storage = storage;
+		let data = try!(read_all(&mut storage, segment));
+		let fresh = data.len() < LOG_START || data.slice_to(LOG_START).iter().all(|b| *b == 0);
+		let state = if fresh {
+			if data.len() != 0 {
+				try!(cut(&mut storage, segment, 0));
+			}
+			let mut header = MemWriter::new();
+			header.write_le_u32(LOG_MAGIC).unwrap();
+			header.write_le_u32(LOG_VERSION).unwrap();
+			LogState {storage: storage, segment: segment, pending: Vec::from_slice(header.get_ref()),
+				pending_start: 0, flushed: 0, next_txn: 1}
+		} else {
+			let mut reader = BufReader::new(data.slice_to(LOG_START));
+			if reader.read_le_u32().unwrap() != LOG_MAGIC || reader.read_le_u32().unwrap() != LOG_VERSION {
+				return Err(BadLog);
+			}
+			let (records, end) = scan(data.as_slice());
+			let next_txn = records.iter().fold(0, |acc, &(_, ref r)| if r.txn() > acc {r.txn()} else {acc}) + 1;
+			let pages = (end + LOG_PAGE_SIZE - 1) / LOG_PAGE_SIZE;
+			// the page the log ends in may have a torn record behind the end
+			let page_start = end - end % LOG_PAGE_SIZE;
+			if end != page_start && data.len() > end {
+				let mut page = Vec::from_elem(LOG_PAGE_SIZE, 0_u8);
+				page.mut_slice_to(end - page_start).copy_from(data.slice(page_start, end));
+				try!(storage.write_page(segment, (page_start / LOG_PAGE_SIZE) as u64,
+					page.as_slice()).map_err(|e| IoFailed(e)));
+			}
+			try!(cut(&mut storage, segment, pages));
+			info!("Opened log with {} records, ending at {}", records.len(), end);
+			LogState {storage: storage, segment: segment, pending: Vec::new(),
+				pending_start: pages * LOG_PAGE_SIZE,
+				flushed: pages * LOG_PAGE_SIZE, next_txn: next_txn}
+		};
+		Ok(Log {state: Mutex::new(state)})
+	}
+
+	/* adds the record and returns its LSN, it is only durable after a flush */
+	pub fn append(&self, record: &Record) -> u64 {
+		let payload = record.encode();
+		let mut state = self.state.lock();
+		// headers don't cross pages, that way padding is always recognized
+		let room = LOG_PAGE_SIZE - state.end() % LOG_PAGE_SIZE;
+		if room < RECORD_HEADER {
+			state.pending.grow(room, &0_u8);
+		}
+		let lsn = state.end();
+		let mut header = MemWriter::new();
+		header.write_le_u32(payload.len() as u32).unwrap();
+		header.write_le_u32(record_crc(lsn as u64, payload.as_slice())).unwrap();
+		state.pending.push_all(header.get_ref());
+		state.pending.push_all(payload.as_slice());
+		debug!("Logged {} at {}", record, lsn);
+		lsn as u64
+	}
+
+	/* makes every record up to and including the one at `lsn` durable */
+	pub fn flush(&self, lsn: u64) -> BufferResult<()> {
+		let mut state = self.state.lock();
+		if lsn == 0 || (lsn as uint) < state.flushed || state.pending.len() == 0 {
+			return Ok(());
+		}
+		let padding = (LOG_PAGE_SIZE - state.pending.len() % LOG_PAGE_SIZE) % LOG_PAGE_SIZE;
+		state.pending.grow(padding, &0_u8);
+		let first = (state.pending_start / LOG_PAGE_SIZE) as u64;
+		{
+			let state = state.deref_mut();
+			for (i, page) in state.pending.as_slice().chunks(LOG_PAGE_SIZE).enumerate() {
+				try!(state.storage.write_page(state.segment, first + i as u64, page).map_err(|e| IoFailed(e)));
+			}
+			try!(state.storage.sync(state.segment).map_err(|e| IoFailed(e)));
+		}
+		state.pending_start += state.pending.len();
+		state.pending.clear();
+		state.flushed = state.pending_start;
+		debug!("Flushed log up to {}", state.flushed);
+		Ok(())
+	}
+
+	/* every record below this LSN is durable */
+	pub fn flushed_lsn(&self) -> u64 {
+		self.state.lock().flushed as u64
+	}
+
+	/* a transaction id that was never used in this log */
+	pub fn next_txn(&self) -> u64 {
+		let mut state = self.state.lock();
+		let txn = state.next_txn;
+		state.next_txn += 1;
+		txn
+	}
+
+	/* every record with its LSN, the ones that are not durable yet included */
+	pub fn records(&self) -> BufferResult<Vec<(u64, Record)>> {
+		let mut state = self.state.lock();
+		let segment = state.segment;
+		let mut data = try!(read_all(&mut state.storage, segment));
+		data.truncate(state.pending_start);
+		data.push_all(state.pending.as_slice());
+		let (records, _) = scan(data.as_slice());
+		Ok(records)
+	}
+}
+
+/* the whole log segment */
+fn read_all(storage: &mut ~Storage:Send, segment: u64) -> BufferResult<Vec<u8>> {
+	let mut data = Vec::new();
+	let mut buf = Vec::from_elem(READ_CHUNK * LOG_PAGE_SIZE, 0_u8);
+	loop {
+		let first = (data.len() / LOG_PAGE_SIZE) as u64;
+		let n = try!(storage.read_pages(segment, first, LOG_PAGE_SIZE,
+			buf.as_mut_slice()).map_err(|e| IoFailed(e)));
+		data.push_all(buf.slice_to(n));
+		if n < buf.len() {
+			return Ok(data);
+		}
+	}
+}
+
+/* keeps the first `pages` log pages and makes that durable */
+fn cut(storage: &mut ~Storage:Send, segment: u64, pages: uint) -> BufferResult<()> {
+	try!(storage.truncate(segment, pages as u64, LOG_PAGE_SIZE).map_err(|e| IoFailed(e)));
+	storage.sync(segment).map_err(|e| IoFailed(e))
+}
+
+/* the intact records of the log in `data` and where they end */
+fn scan(data: &[u8]) -> (Vec<(u64, Record)>, uint) {
+	let mut records = Vec::new();
+	let mut pos = LOG_START;
+	loop {
+		let room = LOG_PAGE_SIZE - pos % LOG_PAGE_SIZE;
+		if room < RECORD_HEADER {
+			pos += room;
+			continue;
+		}
+		if pos + RECORD_HEADER > data.len() {
+			break;
+		}
+		let mut reader = BufReader::new(data.slice(pos, pos + RECORD_HEADER));
+		let len = reader.read_le_u32().unwrap() as uint;
+		let crc = reader.read_le_u32().unwrap();
+		if len == 0 {
+			// a page without records is where the log ends
+			if pos % LOG_PAGE_SIZE == 0 {
+				break;
+			}
+			pos += room;
+			continue;
+		}
+		let end = pos + RECORD_HEADER + len;
+		if end > data.len() {
+			break;
+		}
+		let payload = data.slice(pos + RECORD_HEADER, end);
+		if record_crc(pos as u64, payload) != crc {
+			info!("Log ends with a broken record at {}", pos);
+			break;
+		}
+		match Record::decode(payload) {
+			Some(record) => records.push((pos as u64, record)),
+			None => break,
+		}
+		pos = end;
+	}
+	(records, pos)
+}
+
+#[cfg(test)]
+fn update(txn: u64, prev_lsn: u64, page_id: u64, value: u8) -> Record {
+	Update(txn, prev_lsn, PageUpdate {page_id: page_id, offset: 16,
+		before: vec!(0_u8, 0), after: vec!(value, value)})
+}
+
+#[test]
+fn records_survive_reopen() {
+	let faults = faulty::FaultInjector::new();
+	let log = Log::open(~faults.storage() as ~Storage:Send).unwrap();
+	let txn = log.next_txn();
+	let begin = log.append(&Begin(txn));
+	let first = log.append(&update(txn, begin, 3, 1));
+	// long enough to run over into the next page
+	let big = Update(txn, first, PageUpdate {page_id: 4, offset: 0,
+		before: Vec::from_elem(400, 1_u8), after: Vec::from_elem(400, 2_u8)});
+	let second = log.append(&big);
+	let commit = log.append(&Commit(txn, second));
+	log.flush(commit).unwrap();
+	assert!(log.flushed_lsn() > commit);
+	// never flushed
+	log.append(&Begin(log.next_txn()));
+	assert_eq!(log.records().unwrap().len(), 5);
+	faults.crash();
+
+	let log = Log::open(~faults.storage() as ~Storage:Send).unwrap();
+	let expected = vec!((begin, Begin(txn)), (first, update(txn, begin, 3, 1)),
+		(second, big), (commit, Commit(txn, second)));
+	assert_eq!(log.records().unwrap(), expected);
+	assert_eq!(log.next_txn(), txn + 1);
+	// new records go behind the old ones
+	let lsn = log.append(&Begin(txn + 1));
+	assert!(lsn > commit);
+	log.flush(lsn).unwrap();
+	assert_eq!(log.records().unwrap().len(), 5);
+}
+
+#[test]
+fn torn_flush_ends_log() {
+	let faults = faulty::FaultInjector::new();
+	let log = Log::open(~faults.storage() as ~Storage:Send).unwrap();
+	let kept = log.append(&Begin(1));
+	log.flush(kept).unwrap();
+	let torn = log.append(&update(1, kept, 1, 7));
+	// the record header makes it, the rest doesn't
+	faults.tear_write(1, 12);
+	log.flush(torn).unwrap();
+	drop(log);
+
+	let log = Log::open(~faults.storage() as ~Storage:Send).unwrap();
+	assert_eq!(log.records().unwrap(), vec!((kept, Begin(1))));
+	let lsn = log.append(&Commit(1, kept));
+	log.flush(lsn).unwrap();
+	drop(log);
+	let log = Log::open(~faults.storage() as ~Storage:Send).unwrap();
+	assert_eq!(log.records().unwrap(), vec!((kept, Begin(1)), (lsn, Commit(1, kept))));
+}